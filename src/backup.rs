@@ -0,0 +1,179 @@
+/*
+ * Backup activity against the plants, of the kind a real backup tool would
+ * perform while the plants are in use.
+ */
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use super::common::*;
+use super::config::BackupPolicy;
+use super::control::Control;
+use super::golden::GoldenModel;
+use super::integrity::Finding;
+use super::latency::Op;
+use super::plant::fatal_finding;
+use super::stats::Stats;
+use super::zfs::{ZfsBackend, ZfsError};
+
+/**
+ * Use the calling thread to perform periodic "backup" activity.  For each
+ * plant, we want to:
+ *      - take a new snapshot
+ *      - delete the oldest snapshot until there are only N snapshots left
+ *      - if there are at least two snapshots, do an incremental zfs send of
+ *        the current snapshot using the second most recent snapshot as the
+ *        comparison base
+ *
+ * If we have the golden model for a plant (i.e., its I/O threads are running
+ * in this process), we also check that the new snapshot contains every write
+ * that had been acknowledged when we began to take it.
+ */
+pub fn backup(log: &Logger, zfs: &Arc<dyn ZfsBackend>, pool: &str,
+    policy: &BackupPolicy, ctl: &Arc<Control>, deadline: Option<Instant>,
+    models: HashMap<String, Arc<GoldenModel>>)
+    -> Result<()>
+{
+    let models = Arc::new(models);
+    let plantroot = format!("{}/plant", pool);
+    let maxsnaps = policy.max_snaps;
+
+    loop {
+        let snapnum = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let datasets = Arc::new(Mutex::new(
+            zfs.dataset_children(log, &plantroot)?));
+
+        let mut threads = Vec::<thread::JoinHandle<Result<()>>>::new();
+        for _ in 0..policy.threads {
+            let log = log.clone();
+            let zfs = Arc::clone(zfs);
+            let ctl = Arc::clone(ctl);
+            let datasets = Arc::clone(&datasets);
+            let models = Arc::clone(&models);
+            let snapname = format!("backup-{}", snapnum);
+
+            threads.push(thread::spawn(move || {
+                'next: loop {
+                    if ctl.stopping() {
+                        return Ok(());
+                    }
+
+                    let ds = {
+                        let mut datasets = datasets.lock().unwrap();
+                        if let Some(x) = datasets.pop() {
+                            x
+                        } else {
+                            return Ok(());
+                        }
+                    };
+                    let lat = ctl.latency.plant(&ds);
+
+                    /*
+                     * Age out old snapshots.
+                     */
+                    let snaps = loop {
+                        let snaps = match lat.time(Op::List,
+                            || zfs.snapshot_list(&log, &ds))
+                        {
+                            Ok(snaps) => snaps,
+                            Err(ZfsError::NotFound) => {
+                                /*
+                                 * The plant was destroyed since we listed it.
+                                 */
+                                warn!(log, #"backup", "{} has gone away", ds);
+                                continue 'next;
+                            }
+                            Err(e) => return Err(e.into()),
+                        };
+
+                        if snaps.len() < maxsnaps {
+                            break snaps;
+                        }
+
+                        match lat.time(Op::Destroy,
+                            || zfs.destroy_snapshot(&log, &ds, &snaps[0]))
+                        {
+                            Ok(()) => {
+                                Stats::add(&ctl.stats.snapshots_destroyed, 1);
+                            }
+                            Err(ZfsError::Busy) => {
+                                /*
+                                 * Something else holds this snapshot.  Try
+                                 * again on the next cycle.
+                                 */
+                                warn!(log, #"backup",
+                                    "{}@{} is busy; skipping", ds, snaps[0]);
+                                continue 'next;
+                            }
+                            Err(e) => return Err(e.into()),
+                        }
+                    };
+
+                    /*
+                     * Take snapshot.
+                     */
+                    let expect = models.get(&ds)
+                        .filter(|_| zfs.consistent_snapshots())
+                        .map(|m| m.expect());
+                    lat.time(Op::Snapshot,
+                        || zfs.snapshot(&log, &ds, &snapname, false))?;
+                    Stats::add(&ctl.stats.snapshots, 1);
+
+                    if let Some(expect) = expect {
+                        let dir = zfs.snapshot_dir(&log, &ds, &snapname)?;
+                        match expect.verify(&log, &dir) {
+                            Ok(n) => {
+                                info!(log, #"backup", "verified {} \
+                                    acknowledged blocks in {}@{}", n, ds,
+                                    snapname);
+                                Stats::add(&ctl.stats.verified_blocks, n);
+                            }
+                            Err(e) => {
+                                if let Some(f) = e.downcast_ref::<Finding>() {
+                                    fatal_finding(&log, &ctl, f);
+                                    return Ok(());
+                                }
+                                return Err(e.context(format!("verifying \
+                                    {}@{}", ds, snapname)));
+                            }
+                        }
+                    }
+
+                    if snaps.len() < 2 {
+                        continue;
+                    }
+
+                    let sold = snaps[snaps.len() - 2].to_string();
+                    let snew = snaps[snaps.len() - 1].to_string();
+
+                    /*
+                     * Hold the base snapshot while we send, as a real backup
+                     * tool would.
+                     */
+                    lat.time(Op::Hold,
+                        || zfs.hold(&log, &ds, &sold, "stress-send"))?;
+                    let res = lat.time(Op::Send,
+                        || zfs.send_to_null(&log, &ds, &sold, &snew));
+                    lat.time(Op::Release,
+                        || zfs.release(&log, &ds, &sold, "stress-send"))?;
+                    res?;
+                    Stats::add(&ctl.stats.sends, 1);
+                }
+            }));
+        }
+
+        while let Some(t) = threads.pop() {
+            t.join().unwrap()?;
+        }
+        Stats::add(&ctl.stats.backup_cycles, 1);
+
+        if !ctl.sleep_until(deadline, policy.interval.as_millis() as u64) {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,637 @@
+/*
+ * A backend built from btrfs subvolumes, so that the same seeds, plants and
+ * backup cycle can stress a second copy-on-write filesystem.
+ *
+ * The datasets are laid out as for the directory backend ("dir.rs"), but
+ * each is a subvolume, so that it can be snapshotted on its own:
+ *
+ *      - a snapshot is a read-only snapshot of the subvolume, kept at
+ *        ".zfs/snapshot/NAME" within it
+ *      - a clone is a writable snapshot of one of those
+ *      - an incremental send is "btrfs send -p"
+ *
+ * The order in which snapshots were taken is kept in ".zfs/snapshots", as for
+ * the directory backend.  Holds exist only for the lifetime of the backend.
+ *
+ * The subvolumes belong to whoever ran "btrfs", so every change, including to
+ * our own metadata within them, is made through the privilege wrapper.
+ */
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::Mutex;
+use super::common::*;
+use super::dir::{failure, is_dataset, read_meta, unsupported, Holds, Layout,
+    META};
+use super::tools::Tools;
+use super::zfs::*;
+
+pub struct BtrfsBackend {
+    tools: Tools,
+    layout: Layout,
+    guard: Guard,
+    holds: Holds,
+    failures: Failures,
+    /*
+     * Serialises updates to the list of snapshots of each subvolume.
+     */
+    lock: Mutex<()>,
+}
+
+/**
+ * Determine the class of failure from the output of a failed command.
+ */
+fn classify(res: &Output) -> ZfsError {
+    let stderr = String::from_utf8_lossy(&res.stderr);
+
+    if stderr.contains("No such file or directory") {
+        ZfsError::NotFound
+    } else if stderr.contains("already exists")
+        || stderr.contains("File exists")
+    {
+        ZfsError::AlreadyExists
+    } else if stderr.contains("Operation not permitted")
+        || stderr.contains("Permission denied")
+    {
+        ZfsError::PermissionDenied
+    } else if stderr.contains("No space left on device") {
+        ZfsError::OutOfSpace
+    } else {
+        ZfsError::Other {
+            code: res.status.code(),
+            stderr: res.info(),
+        }
+    }
+}
+
+fn text(p: &Path) -> String {
+    p.to_string_lossy().to_string()
+}
+
+impl BtrfsBackend {
+    /**
+     * Use "root", which must be a directory on a btrfs filesystem, as the
+     * pool with the given name.
+     */
+    pub fn new(log: &Logger, tools: Tools, root: &Path, pool: &str,
+        guard: Guard)
+        -> Result<BtrfsBackend>
+    {
+        if !root.is_dir() {
+            bail!("root {:?} is not a directory", root);
+        }
+        info!(log, "using btrfs subvolumes in {:?}", root);
+
+        let b = BtrfsBackend {
+            tools,
+            layout: Layout {
+                root: root.to_path_buf(),
+                pool: pool.to_string(),
+            },
+            guard,
+            holds: Default::default(),
+            failures: Default::default(),
+            lock: Default::default(),
+        };
+        if !is_dataset(root) {
+            b.script(log, r#"mkdir -p "$2/.zfs""#, &[&text(root)])?;
+        }
+        Ok(b)
+    }
+
+    fn btrfs(&self) -> Command {
+        self.tools.command(&self.tools.btrfs)
+    }
+
+    /**
+     * Run a short shell script with privileges, passing it the path of the
+     * "btrfs" program as "$1" and then the given arguments.
+     */
+    fn script(&self, log: &Logger, script: &str, args: &[&str])
+        -> ZfsResult<Output>
+    {
+        let mut cmd = self.tools.command(&self.tools.bash);
+        cmd.arg("-c");
+        cmd.arg(format!("set -o errexit; set -o pipefail; {}", script));
+        cmd.arg("festival");
+        cmd.arg(&self.tools.btrfs);
+        cmd.args(args);
+
+        self.exec(log, &mut cmd)
+    }
+
+    /**
+     * Run a command, returning its output if it succeeds and the class of
+     * failure if it does not.
+     */
+    fn exec(&self, log: &Logger, cmd: &mut Command) -> ZfsResult<Output> {
+        self.exec_expecting(log, cmd, |_| false)
+    }
+
+    /**
+     * Run a command, as for "exec()", where the caller will handle any
+     * failure for which "expected" returns true.  Only other failures are
+     * logged as errors and recorded for the run report.
+     */
+    fn exec_expecting<F>(&self, log: &Logger, cmd: &mut Command, expected: F)
+        -> ZfsResult<Output>
+        where F: Fn(&ZfsError) -> bool
+    {
+        debug!(log, #"zfs", "exec: {:?}", cmd.get_args());
+
+        let res = cmd.output()?;
+        if !res.status.success() {
+            let e = classify(&res);
+            if expected(&e) {
+                debug!(log, #"zfs", "{:?} failed: {}", cmd.get_args(),
+                    res.info());
+            } else {
+                error!(log, #"zfs", "{:?} failed: {}", cmd.get_args(),
+                    res.info());
+                self.failures.record(cmd, e.kind(), res.info());
+            }
+            return Err(e);
+        }
+
+        Ok(res)
+    }
+
+    fn snapshot_path(&self, dataset: &str, snapname: &str)
+        -> ZfsResult<PathBuf>
+    {
+        validate_snapshot_name(snapname)?;
+        Ok(self.layout.existing(dataset)?.join(META).join("snapshot")
+            .join(snapname))
+    }
+
+    /**
+     * The snapshots of the subvolume in "dir", oldest first.  Any snapshot
+     * missing from the list, because we were interrupted before we could
+     * record it, is put after those in the list.
+     */
+    fn snapshots(&self, dir: &Path) -> ZfsResult<Vec<String>> {
+        let sd = dir.join(META).join("snapshot");
+        if !sd.exists() {
+            return Ok(Vec::new());
+        }
+        let mut found = Vec::new();
+        for ent in fs::read_dir(&sd).map_err(|e| failure("listing", &sd, e))? {
+            let ent = ent.map_err(|e| failure("listing", &sd, e))?;
+            found.push(ent.file_name().to_string_lossy().to_string());
+        }
+        found.sort();
+
+        let mut out = read_meta(&dir.join(META).join("snapshots"))?
+            .map(|s| s.lines()
+                .filter(|n| found.iter().any(|f| f == n))
+                .map(str::to_string)
+                .collect::<Vec<_>>())
+            .unwrap_or_default();
+        for n in found {
+            if !out.contains(&n) {
+                out.push(n);
+            }
+        }
+        Ok(out)
+    }
+
+    /**
+     * Record the current order of the snapshots of the subvolume in "dir".
+     */
+    fn save_snapshots(&self, log: &Logger, dir: &Path) -> ZfsResult<()> {
+        let _g = self.lock.lock().unwrap();
+        let snaps = self.snapshots(dir)?;
+
+        let mut args = vec![text(dir)];
+        args.extend(snaps);
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+        self.script(log, r#"
+            printf '%s\n' "${@:3}" > "$2/.zfs/snapshots"
+            "#, &args)?;
+        Ok(())
+    }
+
+    /**
+     * Delete the subvolume in "dir", after its snapshots and any subvolumes
+     * beneath it.
+     */
+    fn remove(&self, log: &Logger, dir: &Path) -> ZfsResult<()> {
+        for child in self.layout.children(dir)? {
+            self.remove(log, &dir.join(child))?;
+        }
+        let sd = dir.join(META).join("snapshot");
+        for snap in self.snapshots(dir)? {
+            let mut cmd = self.btrfs();
+            cmd.arg("subvolume");
+            cmd.arg("delete");
+            cmd.arg(sd.join(snap));
+            self.exec(log, &mut cmd)?;
+        }
+
+        let mut cmd = self.btrfs();
+        cmd.arg("subvolume");
+        cmd.arg("delete");
+        cmd.arg(dir);
+        self.exec(log, &mut cmd)?;
+        Ok(())
+    }
+
+    /**
+     * Check that a new dataset can be created, returning its path.
+     */
+    fn prepare(&self, dataset: &str) -> ZfsResult<PathBuf> {
+        let p = self.layout.path(dataset)?;
+        if !is_dataset(p.parent().unwrap()) {
+            return Err(ZfsError::NotFound);
+        }
+        if p.exists() {
+            return Err(ZfsError::AlreadyExists);
+        }
+        Ok(p)
+    }
+}
+
+impl ZfsBackend for BtrfsBackend {
+    fn destroy_snapshot(&self, log: &Logger, dataset: &str, snapname: &str)
+        -> ZfsResult<()>
+    {
+        let sp = match self.snapshot_path(dataset, snapname) {
+            Ok(sp) if sp.exists() => sp,
+            Ok(_) | Err(ZfsError::NotFound) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if self.holds.held(&format!("{}@{}", dataset, snapname)) {
+            return Err(ZfsError::Busy);
+        }
+
+        let mut cmd = self.btrfs();
+        cmd.arg("subvolume");
+        cmd.arg("delete");
+        cmd.arg(sp);
+
+        match self.exec_expecting(log, &mut cmd,
+            |e| matches!(e, ZfsError::NotFound))
+        {
+            Ok(_) | Err(ZfsError::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+        self.save_snapshots(log, &self.layout.existing(dataset)?)
+    }
+
+    fn destroy(&self, log: &Logger, dataset: &str, recursive: bool)
+        -> ZfsResult<()>
+    {
+        let p = match self.layout.existing(dataset) {
+            Ok(p) => p,
+            Err(ZfsError::NotFound) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if dataset == self.layout.pool {
+            return Err(unsupported("btrfs", "destroying the root"));
+        }
+
+        if recursive {
+            if !self.guard.destroy_unmarked
+                && read_meta(&p.join(META).join("owner"))?.is_none()
+            {
+                return Err(ZfsError::Unmarked(dataset.to_string()));
+            }
+            if self.holds.any_held(dataset) {
+                return Err(ZfsError::Busy);
+            }
+        } else if !self.layout.children(&p)?.is_empty()
+            || !self.snapshots(&p)?.is_empty()
+        {
+            return Err(ZfsError::Other {
+                code: None,
+                stderr: format!("cannot destroy '{}': filesystem has \
+                    children", dataset),
+            });
+        }
+
+        self.remove(log, &p)?;
+        self.holds.forget(dataset);
+        Ok(())
+    }
+
+    fn create(&self, log: &Logger, dataset: &str, exists_ok: bool)
+        -> ZfsResult<()>
+    {
+        let p = match self.prepare(dataset) {
+            Err(ZfsError::AlreadyExists) if exists_ok => return Ok(()),
+            res => res?,
+        };
+
+        self.script(log, r#"
+            "$1" subvolume create "$2" >/dev/null
+            mkdir -p "$2/.zfs/snapshot"
+            printf '%s\n' "$3" > "$2/.zfs/owner"
+            "#, &[&text(&p), &self.guard.run_id])?;
+        Ok(())
+    }
+
+    fn snapshot(&self, log: &Logger, dataset: &str, name: &str,
+        recursive: bool)
+        -> ZfsResult<()>
+    {
+        let sp = self.snapshot_path(dataset, name)?;
+
+        let mut cmd = self.btrfs();
+        cmd.arg("subvolume");
+        cmd.arg("snapshot");
+        cmd.arg("-r");
+        cmd.arg(self.layout.existing(dataset)?);
+        cmd.arg(&sp);
+        self.exec(log, &mut cmd)?;
+        self.save_snapshots(log, &self.layout.existing(dataset)?)?;
+
+        if recursive {
+            for child in self.layout.children(&self.layout.path(dataset)?)? {
+                self.snapshot(log, &format!("{}/{}", dataset, child), name,
+                    true)?;
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Make a writable snapshot of the snapshot, and replace the metadata it
+     * inherited from the original subvolume with our own.
+     */
+    fn clone_snapshot(&self, log: &Logger, dataset: &str, snapname: &str,
+        target: &str)
+        -> ZfsResult<()>
+    {
+        let sp = self.snapshot_path(dataset, snapname)?;
+        if !sp.exists() {
+            return Err(ZfsError::NotFound);
+        }
+        let p = self.prepare(target)?;
+
+        self.script(log, r#"
+            "$1" subvolume snapshot "$2" "$3" >/dev/null
+            rm -rf "$3/.zfs"
+            mkdir -p "$3/.zfs/snapshot"
+            printf '%s\n' "$4" > "$3/.zfs/owner"
+            printf '%s\n' "$5" > "$3/.zfs/origin"
+            "#, &[&text(&sp), &text(&p), &self.guard.run_id,
+                &format!("{}@{}", dataset, snapname)])?;
+        Ok(())
+    }
+
+    fn get(&self, _log: &Logger, dataset: &str, prop: &str)
+        -> ZfsResult<String>
+    {
+        let p = self.layout.existing(dataset)?;
+
+        let meta = |name: &str| -> ZfsResult<String> {
+            Ok(read_meta(&p.join(META).join(name))?
+                .unwrap_or_else(|| "-".to_string()))
+        };
+
+        match prop {
+            "mountpoint" => Ok(text(&p)),
+            "origin" => meta("origin"),
+            OWNER_PROP => meta("owner"),
+            p if p.contains(':') => meta(p),
+            _ => Err(unsupported("btrfs", &format!("property {:?}", prop))),
+        }
+    }
+
+    fn set(&self, log: &Logger, dataset: &str, prop: &str, value: &str)
+        -> ZfsResult<()>
+    {
+        let p = self.layout.existing(dataset)?;
+
+        if !prop.contains(':') || prop == OWNER_PROP {
+            return Err(unsupported("btrfs",
+                &format!("setting property {:?}", prop)));
+        }
+        self.script(log, r#"
+            printf '%s\n' "$4" > "$2/.zfs/$3"
+            "#, &[&text(&p), prop, value])?;
+        Ok(())
+    }
+
+    fn snapshot_exists(&self, _log: &Logger, dataset: &str, snapname: &str)
+        -> ZfsResult<bool>
+    {
+        match self.snapshot_path(dataset, snapname) {
+            Ok(sp) => Ok(sp.exists()),
+            Err(ZfsError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn dataset_children(&self, _log: &Logger, dataset: &str)
+        -> ZfsResult<Vec<String>>
+    {
+        self.layout.dataset_children(dataset)
+    }
+
+    fn snapshot_list(&self, _log: &Logger, dataset: &str)
+        -> ZfsResult<Vec<String>>
+    {
+        let p = self.layout.existing(dataset)?;
+        self.snapshots(&p)
+    }
+
+    fn send_to_null(&self, log: &Logger, dataset: &str, snapold: &str,
+        snapnew: &str)
+        -> ZfsResult<bool>
+    {
+        let old = self.snapshot_path(dataset, snapold)?;
+        let new = self.snapshot_path(dataset, snapnew)?;
+
+        self.script(log, r#""$1" send -q -p "$2" "$3" >/dev/null"#,
+            &[&text(&old), &text(&new)])?;
+        Ok(true)
+    }
+
+    fn hold(&self, log: &Logger, dataset: &str, snapname: &str, tag: &str)
+        -> ZfsResult<()>
+    {
+        if !self.snapshot_exists(log, dataset, snapname)? {
+            return Err(ZfsError::NotFound);
+        }
+        self.holds.hold(&format!("{}@{}", dataset, snapname), tag)
+    }
+
+    fn release(&self, _log: &Logger, dataset: &str, snapname: &str,
+        tag: &str)
+        -> ZfsResult<()>
+    {
+        validate_snapshot_name(snapname)?;
+        self.holds.release(&format!("{}@{}", dataset, snapname), tag)
+    }
+
+    fn allow(&self, _log: &Logger, _dataset: &str, _user: &str,
+        _perms: &[&str])
+        -> ZfsResult<()>
+    {
+        Err(unsupported("btrfs", "delegation"))
+    }
+
+    fn allowed(&self, _log: &Logger, _dataset: &str)
+        -> ZfsResult<Vec<Delegation>>
+    {
+        Err(unsupported("btrfs", "delegation"))
+    }
+
+    fn failures(&self) -> Vec<CommandFailure> {
+        self.failures.list()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use super::super::testutil::{self, TempDir};
+    use super::super::tools::Privilege;
+
+    /*
+     * A stand-in for the subvolume commands we use, built from plain
+     * directories.  As with btrfs, a snapshot does not include the
+     * snapshots nested within the subvolume.
+     */
+    const FAKE_BTRFS: &str = r#"#!/bin/bash
+set -o errexit
+case "$1 $2" in
+"subvolume create")
+    mkdir "$3"
+    ;;
+"subvolume snapshot")
+    if [[ $3 == -r ]]; then
+        shift
+    fi
+    if [[ -e $4 ]]; then
+        echo "ERROR: target path already exists: $4" >&2
+        exit 1
+    fi
+    tmp=$(mktemp -d "$(dirname "$3")/.snap.XXXXXX")
+    cp -a "$3/." "$tmp"
+    rm -rf "$tmp/.zfs/snapshot"/*
+    mv "$tmp" "$4"
+    ;;
+"subvolume delete")
+    rm -rf "$3"
+    ;;
+*)
+    echo "unsupported: $*" >&2
+    exit 1
+    ;;
+esac
+"#;
+
+    fn setup(name: &str) -> (Logger, TempDir, BtrfsBackend) {
+        let log = testutil::logger();
+        let top = TempDir::new("btrfs", name);
+        fs::create_dir_all(top.path().join("pool")).unwrap();
+
+        let prog = top.path().join("btrfs");
+        fs::write(&prog, FAKE_BTRFS).unwrap();
+        fs::set_permissions(&prog, fs::Permissions::from_mode(0o755))
+            .unwrap();
+
+        let mut tools = Tools::detect();
+        tools.set_privilege(Privilege::None);
+        tools.set("btrfs", &prog).unwrap();
+
+        let zfs = BtrfsBackend::new(&log, tools, &top.path().join("pool"),
+            "tank", testutil::guard()).unwrap();
+        testutil::populate(&log, &zfs);
+        (log, top, zfs)
+    }
+
+    #[test]
+    fn snapshot_order() {
+        let (log, _f, zfs) = setup("order");
+        let ds = "tank/plant";
+
+        /*
+         * Snapshots are listed in the order they were taken, whatever their
+         * names, so that aging always keeps the most recent.
+         */
+        let maxsnaps = 3;
+        for i in 0..10 {
+            loop {
+                let snaps = zfs.snapshot_list(&log, ds).unwrap();
+                if snaps.len() < maxsnaps {
+                    break;
+                }
+                zfs.destroy_snapshot(&log, ds, &snaps[0]).unwrap();
+            }
+            zfs.snapshot(&log, ds, &format!("backup-{}", (i * 7) % 10),
+                false).unwrap();
+        }
+
+        assert_eq!(zfs.snapshot_list(&log, ds).unwrap(),
+            vec!["backup-9", "backup-6", "backup-3"]);
+        assert!(zfs.snapshot_exists(&log, ds, "backup-6").unwrap());
+        assert!(!zfs.snapshot_exists(&log, ds, "backup-7").unwrap());
+
+        let e = zfs.snapshot(&log, ds, "backup-9", false).unwrap_err();
+        assert!(matches!(e, ZfsError::AlreadyExists));
+    }
+
+    #[test]
+    fn destroy_guard() {
+        let (log, f, zfs) = setup("guard");
+
+        /*
+         * Neither the root nor a dataset we did not create may be destroyed
+         * recursively.
+         */
+        assert!(zfs.destroy(&log, "tank", true).is_err());
+        fs::create_dir_all(f.path().join("pool/other/.zfs")).unwrap();
+        assert!(matches!(zfs.destroy(&log, "tank/other", true),
+            Err(ZfsError::Unmarked(_))));
+
+        let e = zfs.destroy(&log, "tank/seed", false).unwrap_err();
+        assert!(e.to_string().contains("filesystem has children"));
+
+        /*
+         * A held snapshot keeps its dataset, too.
+         */
+        zfs.hold(&log, "tank/seed/0000", "final", "keep").unwrap();
+        assert!(matches!(zfs.destroy_snapshot(&log, "tank/seed/0000",
+            "final"), Err(ZfsError::Busy)));
+        assert!(matches!(zfs.destroy(&log, "tank/seed", true),
+            Err(ZfsError::Busy)));
+        zfs.release(&log, "tank/seed/0000", "final", "keep").unwrap();
+
+        zfs.destroy(&log, "tank/seed", true).unwrap();
+        assert!(!f.path().join("pool/seed").exists());
+        assert_eq!(zfs.dataset_children(&log, "tank").unwrap(),
+            vec!["tank", "tank/other", "tank/plant"]);
+        zfs.destroy(&log, "tank/seed", true).unwrap();
+    }
+
+    #[test]
+    fn clones() {
+        let (log, f, zfs) = setup("clones");
+
+        fs::write(f.path().join("pool/seed/0000/file"), "seed data").unwrap();
+        zfs.snapshot(&log, "tank/seed/0000", "later", false).unwrap();
+        zfs.set(&log, "tank/seed/0000", SEED_PROP, "42").unwrap();
+        assert_eq!(zfs.get(&log, "tank/seed/0000", SEED_PROP).unwrap(), "42");
+
+        zfs.clone_snapshot(&log, "tank/seed/0000", "later", "tank/plant/0000")
+            .unwrap();
+        let p = PathBuf::from(zfs.get(&log, "tank/plant/0000", "mountpoint")
+            .unwrap());
+        assert_eq!(fs::read_to_string(p.join("file")).unwrap(), "seed data");
+        assert_eq!(zfs.get(&log, "tank/plant/0000", "origin").unwrap(),
+            "tank/seed/0000@later");
+        assert_eq!(zfs.get(&log, "tank/plant/0000", OWNER_PROP).unwrap(),
+            "test");
+        assert_eq!(zfs.get(&log, "tank/plant/0000", SEED_PROP).unwrap(), "-");
+        assert!(zfs.snapshot_list(&log, "tank/plant/0000").unwrap()
+            .is_empty());
+
+        assert!(matches!(zfs.clone_snapshot(&log, "tank/seed/0000", "nope",
+            "tank/plant/0001"), Err(ZfsError::NotFound)));
+    }
+}
@@ -3,27 +3,201 @@
  */
 
 use atty::Stream;
-use slog::Drain;
+use serde::{Deserialize, Serialize};
+use slog::{Drain, Level};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 use std::sync::Mutex;
 
-pub use slog::{info, warn, error, debug, trace, o, Logger};
+pub use slog::{info, warn, error, crit, debug, trace, o, Logger};
 pub use anyhow::{bail, Result, Context};
 
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /*
+     * Human-readable text; "compact" groups messages under their keys.
+     */
+    Compact,
+    Full,
+    /*
+     * One JSON object per line, with every key as a field.
+     */
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<LogFormat> {
+        match s {
+            "compact" => Ok(LogFormat::Compact),
+            "full" => Ok(LogFormat::Full),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("invalid log format {:?} (expected \"compact\", \
+                \"full\" or \"json\")", s),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LogOptions {
+    /*
+     * If not specified, we use "compact" on an interactive terminal and
+     * "full" otherwise.
+     */
+    pub format: Option<LogFormat>,
+    /*
+     * If specified, log to this file (appending) rather than to stdout.
+     */
+    pub file: Option<PathBuf>,
+    /*
+     * Which messages to log; see "LogLevels".
+     */
+    pub level: Option<String>,
+}
+
+/*
+ * Messages from each subsystem are tagged (e.g., "debug!(log, #"zfs", ...)")
+ * so that their verbosity can be controlled separately:
+ *
+ *      zfs         the commands we execute, and their failures
+ *      seed        the creation of seed datasets
+ *      workload    the I/O threads within each plant
+ *      backup      the snapshot, send and verification loop
+ */
+pub const SUBSYSTEMS: &[&str] = &["zfs", "seed", "workload", "backup"];
+
+/**
+ * The minimum level at which to log, both overall and for particular
+ * subsystems.  This is parsed from a specification like "warn" or
+ * "info,zfs=debug,workload=warn".
+ */
+#[derive(Clone, Debug)]
+pub struct LogLevels {
+    default: Level,
+    subsystems: BTreeMap<String, Level>,
+}
+
+fn parse_level(s: &str) -> Result<Level> {
+    s.parse().map_err(|_| anyhow::anyhow!("invalid log level {:?} (expected \
+        e.g. \"info\" or \"debug\")", s))
+}
+
+impl std::str::FromStr for LogLevels {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<LogLevels> {
+        let mut out = LogLevels {
+            default: Level::Info,
+            subsystems: BTreeMap::new(),
+        };
+
+        for term in s.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            if let Some((sub, level)) = term.split_once('=') {
+                if !SUBSYSTEMS.contains(&sub) {
+                    bail!("unknown log subsystem {:?} (expected one of {})",
+                        sub, SUBSYSTEMS.join(", "));
+                }
+                out.subsystems.insert(sub.to_string(), parse_level(level)?);
+            } else {
+                out.default = parse_level(term)?;
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+impl LogLevels {
+    fn allows(&self, r: &slog::Record) -> bool {
+        let min = self.subsystems.get(r.tag()).unwrap_or(&self.default);
+        r.level().is_at_least(*min)
+    }
+}
+
+/*
+ * A drain which discards messages below the configured level.
+ */
+struct LevelFilter<D> {
+    drain: D,
+    levels: LogLevels,
+}
+
+impl<D: Drain> Drain for LevelFilter<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(&self, r: &slog::Record, kv: &slog::OwnedKVList)
+        -> std::result::Result<(), D::Err>
+    {
+        if self.levels.allows(r) {
+            self.drain.log(r, kv)?;
+        }
+        Ok(())
+    }
+}
+
+fn root<D>(drain: D, levels: LogLevels) -> Logger
+    where D: Drain + Send + Sync + std::panic::UnwindSafe
+        + std::panic::RefUnwindSafe + 'static,
+        D::Err: std::fmt::Debug,
+{
+    slog::Logger::root(LevelFilter { drain, levels }.fuse(), o!())
+}
+
 /**
- * Initialise a logger which writes to stdout, and which does the right thing on
- * both an interactive terminal and when stdout is not a tty.
+ * Initialise a logger which writes to stdout (or a file), and which does the
+ * right thing on both an interactive terminal and when stdout is not a tty.
  */
-pub fn init_log() -> Logger {
-    let dec = slog_term::TermDecorator::new().stdout().build();
-    if atty::is(Stream::Stdout) {
+pub fn init_log(opts: &LogOptions) -> Result<Logger> {
+    let levels = opts.level.as_deref().unwrap_or("info").parse::<LogLevels>()?;
+    let tty = opts.file.is_none() && atty::is(Stream::Stdout);
+    let format = opts.format.unwrap_or(if tty {
+        LogFormat::Compact
+    } else {
+        LogFormat::Full
+    });
+
+    let out: Box<dyn std::io::Write + Send> = match &opts.file {
+        Some(p) => Box::new(std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(p)
+            .with_context(|| format!("opening log file {:?}", p))?),
+        None => Box::new(std::io::stdout()),
+    };
+
+    if format == LogFormat::Json {
+        let dr = Mutex::new(slog_json::Json::new(out)
+            .add_default_keys()
+            .build());
+        return Ok(root(dr, levels));
+    }
+
+    /*
+     * Only use colour when writing to a terminal.
+     */
+    if tty {
+        Ok(text_log(slog_term::TermDecorator::new().stdout().build(), format,
+            levels))
+    } else {
+        Ok(text_log(slog_term::PlainDecorator::new(out), format, levels))
+    }
+}
+
+fn text_log<D>(dec: D, format: LogFormat, levels: LogLevels) -> Logger
+    where D: slog_term::Decorator + Send + 'static
+{
+    if format == LogFormat::Compact {
         let dr = Mutex::new(slog_term::CompactFormat::new(dec)
-            .build()).fuse();
-        slog::Logger::root(dr, o!())
+            .build());
+        root(dr, levels)
     } else {
         let dr = Mutex::new(slog_term::FullFormat::new(dec)
             .use_original_order()
-            .build()).fuse();
-        slog::Logger::root(dr, o!())
+            .build());
+        root(dr, levels)
     }
 }
 
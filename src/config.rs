@@ -0,0 +1,906 @@
+/*
+ * Configuration for a stress run.  A configuration starts out with the
+ * defaults below, and may then be adjusted by command line options or loaded
+ * from a TOML scenario file.
+ */
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::{Deserialize, Serialize, Serializer};
+use super::common::*;
+use super::integrity::BLOCK_SIZE;
+use super::tools::Tools;
+use super::workload;
+
+/**
+ * How to fill a seed dataset with files.
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct SeedRecipe {
+    pub files: u64,
+    /*
+     * File sizes are chosen uniformly between these bounds, in megabytes.
+     */
+    pub file_min: u64,
+    pub file_max: u64,
+    /*
+     * The probability that any particular 16KB chunk of a file is highly
+     * compressible, rather than random.
+     */
+    pub compressible: f64,
+}
+
+/**
+ * The workload run by the I/O threads within a plant, and the mix of
+ * operations it performs.
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct WorkloadMix {
+    /*
+     * The name of the workload; see "workload.rs".
+     */
+    pub name: String,
+    /*
+     * The probability that any particular operation is a write, rather than a
+     * read.
+     */
+    pub write_ratio: f64,
+    pub compressible: f64,
+    /*
+     * The probability that a write is followed by an fsync.
+     */
+    pub fsync_ratio: f64,
+    /*
+     * Each time a file is visited, we perform between one and this many
+     * operations on it.
+     */
+    pub max_ops: u64,
+    /*
+     * The size of each read or write, in bytes.  This must be a multiple of
+     * the size of the self-describing blocks we write; see "integrity.rs".
+     */
+    pub block_size: u64,
+}
+
+impl WorkloadMix {
+    /**
+     * Set a parameter by name, as given on the command line.
+     */
+    pub fn set(&mut self, param: &str, val: &str) -> Result<()> {
+        let ratio = |v: &str| v.parse::<f64>()
+            .map_err(|_| anyhow::anyhow!("invalid {} {:?}", param, v));
+        match param {
+            "write_ratio" => self.write_ratio = ratio(val)?,
+            "compressible" => self.compressible = ratio(val)?,
+            "fsync_ratio" => self.fsync_ratio = ratio(val)?,
+            "max_ops" => self.max_ops = parse_count(param, val)?,
+            "block_size" => self.block_size = parse_count(param, val)?,
+            p => bail!("unknown workload parameter {:?} (expected \
+                write_ratio, compressible, fsync_ratio, max_ops or \
+                block_size)", p),
+        }
+        Ok(())
+    }
+
+    /**
+     * Apply a specification like "NAME" or "NAME:PARAM=VALUE,...", where the
+     * name may be left empty to change only the parameters.
+     */
+    pub fn apply(&mut self, spec: &str) -> Result<()> {
+        let (name, params) = match spec.split_once(':') {
+            Some((n, p)) => (n, Some(p)),
+            None => (spec, None),
+        };
+        if !name.is_empty() {
+            self.name = name.to_string();
+        }
+        for p in params.into_iter().flat_map(|p| p.split(',')) {
+            match p.split_once('=') {
+                Some((param, val)) => self.set(param, val)?,
+                None => bail!("invalid workload parameter {:?} (expected \
+                    PARAM=VALUE)", p),
+            }
+        }
+        Ok(())
+    }
+
+    fn validate(&self, what: &str) -> Result<()> {
+        if !workload::exists(&self.name) {
+            bail!("{}: unknown workload {:?} (expected one of: {})", what,
+                self.name, workload::names().collect::<Vec<_>>().join(", "));
+        }
+        check_ratio(&format!("{}.write_ratio", what), self.write_ratio)?;
+        check_ratio(&format!("{}.compressible", what), self.compressible)?;
+        check_ratio(&format!("{}.fsync_ratio", what), self.fsync_ratio)?;
+        check_count(&format!("{}.max_ops", what), self.max_ops)?;
+        let bs = self.block_size;
+        if bs == 0 || !bs.is_multiple_of(BLOCK_SIZE as u64)
+            || bs > MAX_BLOCK_SIZE
+        {
+            bail!("{}.block_size must be a multiple of {} no larger than {}, \
+                not {}", what, BLOCK_SIZE, MAX_BLOCK_SIZE, bs);
+        }
+        Ok(())
+    }
+}
+
+/*
+ * Seed files are at least a megabyte in size, so no operation may be larger
+ * than that.
+ */
+const MAX_BLOCK_SIZE: u64 = 1024 * 1024;
+
+/**
+ * Parse a plant number, or an inclusive range of them like "0-3".
+ */
+pub fn parse_plants(s: &str) -> Result<std::ops::RangeInclusive<u64>> {
+    let bad = || anyhow::anyhow!("invalid plants {:?} (expected a plant \
+        number or a range like \"0-3\")", s);
+    let (a, b) = s.split_once('-').unwrap_or((s, s));
+    let (a, b) = (a.parse::<u64>().map_err(|_| bad())?,
+        b.parse::<u64>().map_err(|_| bad())?);
+    if a > b {
+        return Err(bad());
+    }
+    Ok(a..=b)
+}
+
+/**
+ * How the plants are backed up: each cycle takes a snapshot of every plant,
+ * ages out old snapshots, and sends the newest increment.
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct BackupPolicy {
+    /*
+     * Whether "run" performs backup activity alongside the I/O threads.
+     */
+    pub enabled: bool,
+    pub threads: u64,
+    /*
+     * The number of snapshots of each plant to keep around.
+     */
+    pub max_snaps: usize,
+    #[serde(serialize_with = "secs")]
+    pub interval: Duration,
+}
+
+/**
+ * Where plants are cloned from.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Origin {
+    /*
+     * The "final" snapshot of a seed chosen at random for each plant.
+     */
+    Random,
+    /*
+     * The "final" snapshot of each seed in turn.
+     */
+    RoundRobin,
+    /*
+     * An existing snapshot, given as a dataset and snapshot name.  No seeds
+     * are created.
+     */
+    Snapshot(String, String),
+}
+
+impl std::str::FromStr for Origin {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Origin> {
+        match s {
+            "random" => return Ok(Origin::Random),
+            "round-robin" => return Ok(Origin::RoundRobin),
+            _ => (),
+        }
+
+        match s.split_once('@') {
+            Some((ds, snap)) if !ds.is_empty() && !snap.is_empty()
+                && !ds.starts_with('/') && !ds.ends_with('/')
+                && !snap.contains('@') && !snap.contains('/') =>
+            {
+                Ok(Origin::Snapshot(ds.to_string(), snap.to_string()))
+            }
+            _ => bail!("invalid origin {:?} (expected \"random\", \
+                \"round-robin\", or a snapshot like \"pool/ds@snap\")", s),
+        }
+    }
+}
+
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Origin::Random => write!(f, "random"),
+            Origin::RoundRobin => write!(f, "round-robin"),
+            Origin::Snapshot(ds, snap) => write!(f, "{}@{}", ds, snap),
+        }
+    }
+}
+
+impl Serialize for Origin {
+    fn serialize<S: Serializer>(&self, s: S)
+        -> std::result::Result<S::Ok, S::Error>
+    {
+        s.serialize_str(&self.to_string())
+    }
+}
+
+/**
+ * What seeds and plants are made of.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /*
+     * ZFS datasets, with snapshots and clones.
+     */
+    Zfs,
+    /*
+     * Plain directories beneath the root directory, on any filesystem; see
+     * "dir.rs".
+     */
+    Dir,
+    /*
+     * Btrfs subvolumes beneath the root directory, with read-only snapshots
+     * and writable snapshots in place of clones; see "btrfs.rs".
+     */
+    Btrfs,
+}
+
+impl std::str::FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Backend> {
+        Ok(match s {
+            "zfs" => Backend::Zfs,
+            "dir" => Backend::Dir,
+            "btrfs" => Backend::Btrfs,
+            s => bail!("invalid backend {:?} (expected \"zfs\", \"dir\" or \
+                \"btrfs\")", s),
+        })
+    }
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Backend::Zfs => write!(f, "zfs"),
+            Backend::Dir => write!(f, "dir"),
+            Backend::Btrfs => write!(f, "btrfs"),
+        }
+    }
+}
+
+impl Serialize for Backend {
+    fn serialize<S: Serializer>(&self, s: S)
+        -> std::result::Result<S::Ok, S::Error>
+    {
+        s.serialize_str(&self.to_string())
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Config {
+    /*
+     * The pool in which to create seeds and plants.
+     */
+    pub pool: String,
+    pub backend: Backend,
+    /*
+     * For backends other than ZFS, the directory which stands in for the
+     * pool.
+     */
+    pub root: Option<PathBuf>,
+
+    pub seeds: u64,
+    pub seed: SeedRecipe,
+
+    pub plants: u64,
+    pub origin: Origin,
+    /*
+     * The number of I/O threads to run within each plant.
+     */
+    pub plant_threads: u64,
+    /*
+     * The workload run in every plant, unless another has been chosen for a
+     * particular plant.
+     */
+    pub workload: WorkloadMix,
+    pub plant_workloads: BTreeMap<u64, WorkloadMix>,
+
+    pub backup: BackupPolicy,
+
+    /*
+     * How long to run before exiting.  If not specified, we run until
+     * interrupted.
+     */
+    #[serde(serialize_with = "opt_secs")]
+    pub duration: Option<Duration>,
+    /*
+     * Whether to destroy the plants once the run has stopped.  Plants are
+     * always left in place if any integrity failure was found.
+     */
+    pub cleanup: bool,
+    /*
+     * Whether to destroy datasets that were not marked as ours when they
+     * were created; see "Guard".
+     */
+    pub destroy_unmarked: bool,
+    /*
+     * How often to report the latency of recent operations.
+     */
+    #[serde(serialize_with = "secs")]
+    pub report_interval: Duration,
+    /*
+     * If set, serve statistics in the Prometheus format at this address.
+     */
+    pub metrics: Option<SocketAddr>,
+    /*
+     * If set, write a JSON report of the run to this file, both periodically
+     * and when the run ends.
+     */
+    pub report: Option<PathBuf>,
+    pub log: LogOptions,
+    /*
+     * The programs we run, and how we run them with privileges.
+     */
+    pub tools: Tools,
+
+    /*
+     * All random choices are derived from this value; see "rng.rs".  Unless
+     * it was chosen by the user, rather than at random, existing seed
+     * datasets are reused whichever master seed they were generated from.
+     */
+    pub master_seed: u64,
+    pub master_seed_given: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            pool: "dynamite".to_string(),
+            backend: Backend::Zfs,
+            root: None,
+            seeds: 4,
+            seed: SeedRecipe {
+                files: 1_000,
+                file_min: 2,
+                file_max: 32,
+                compressible: 0.25,
+            },
+            plants: 60,
+            origin: Origin::Random,
+            plant_threads: 4,
+            workload: WorkloadMix {
+                name: "mixed".to_string(),
+                write_ratio: 0.40,
+                compressible: 0.25,
+                fsync_ratio: 0.30,
+                max_ops: 10_000,
+                block_size: BLOCK_SIZE as u64,
+            },
+            plant_workloads: BTreeMap::new(),
+            backup: BackupPolicy {
+                enabled: true,
+                threads: 8,
+                max_snaps: 6,
+                interval: Duration::from_secs(60),
+            },
+            duration: None,
+            cleanup: false,
+            destroy_unmarked: false,
+            report_interval: Duration::from_secs(60),
+            metrics: None,
+            report: None,
+            log: Default::default(),
+            tools: Tools::detect(),
+            /*
+             * TOML integers are signed, so keep the seed within that range
+             * to allow it to be used in a scenario file.
+             */
+            master_seed: rand::random::<u64>() & (i64::MAX as u64),
+            master_seed_given: false,
+        }
+    }
+}
+
+/*
+ * Durations appear in reports as a number of seconds.
+ */
+fn secs<S: Serializer>(d: &Duration, s: S)
+    -> std::result::Result<S::Ok, S::Error>
+{
+    s.serialize_u64(d.as_secs())
+}
+
+fn opt_secs<S: Serializer>(d: &Option<Duration>, s: S)
+    -> std::result::Result<S::Ok, S::Error>
+{
+    match d {
+        Some(d) => secs(d, s),
+        None => s.serialize_none(),
+    }
+}
+
+fn check_ratio(name: &str, v: f64) -> Result<()> {
+    if !(0.0..=1.0).contains(&v) {
+        bail!("{} must be between 0 and 1, not {}", name, v);
+    }
+    Ok(())
+}
+
+fn check_count(name: &str, v: u64) -> Result<()> {
+    if v == 0 {
+        bail!("{} must be at least 1", name);
+    }
+    Ok(())
+}
+
+impl Config {
+    /**
+     * Check that the configuration makes sense before we touch the pool.
+     */
+    pub fn validate(&self) -> Result<()> {
+        if self.pool.is_empty() || self.pool.contains('@')
+            || self.pool.starts_with('/') || self.pool.ends_with('/')
+        {
+            bail!("invalid pool name {:?}", self.pool);
+        }
+        if self.backend != Backend::Zfs && self.root.is_none() {
+            bail!("the {} backend requires a root directory", self.backend);
+        }
+
+        check_count("seed count", self.seeds)?;
+        check_count("seed.files", self.seed.files)?;
+        check_count("seed.file_min", self.seed.file_min)?;
+        if self.seed.file_min > self.seed.file_max {
+            bail!("seed.file_min ({}) must not exceed seed.file_max ({})",
+                self.seed.file_min, self.seed.file_max);
+        }
+        check_ratio("seed.compressible", self.seed.compressible)?;
+
+        check_count("plant count", self.plants)?;
+        check_count("plant thread count", self.plant_threads)?;
+        self.workload.validate("workload")?;
+        for (id, mix) in &self.plant_workloads {
+            if *id >= self.plants {
+                bail!("a workload was chosen for plant {}, but there are \
+                    only {} plants", id, self.plants);
+            }
+            mix.validate(&format!("workload for plant {}", id))?;
+        }
+
+        check_count("backup thread count", self.backup.threads)?;
+        check_count("backup.max_snaps", self.backup.max_snaps as u64)?;
+
+        Ok(())
+    }
+
+    /**
+     * The workload to run in a particular plant.
+     */
+    pub fn workload_for(&self, plant: u64) -> &WorkloadMix {
+        self.plant_workloads.get(&plant).unwrap_or(&self.workload)
+    }
+
+    /**
+     * Apply a specification like "PLANTS=NAME:PARAM=VALUE,..." to choose the
+     * workload for some plants.  Parameters not given are taken from the
+     * workload for every plant, so that should be set first.
+     */
+    pub fn apply_plant_workload(&mut self, spec: &str) -> Result<()> {
+        let (plants, spec) = spec.split_once('=')
+            .filter(|(p, _)| !p.contains(':'))
+            .ok_or_else(|| anyhow::anyhow!("invalid plant workload {:?} \
+                (expected PLANTS=NAME[:PARAM=VALUE,...])", spec))?;
+        let mut mix = self.workload.clone();
+        mix.apply(spec)?;
+        for id in parse_plants(plants)? {
+            self.plant_workloads.insert(id, mix.clone());
+        }
+        Ok(())
+    }
+
+    /**
+     * Load a scenario file, which describes an entire run.  Any setting not
+     * present in the file retains its default value.
+     */
+    pub fn load<P: AsRef<Path>>(p: P) -> Result<Config> {
+        let p = p.as_ref();
+        let s = std::fs::read_to_string(p)
+            .with_context(|| format!("reading scenario {:?}", p))?;
+        let sc: Scenario = toml::from_str(&s)
+            .with_context(|| format!("parsing scenario {:?}", p))?;
+
+        let cfg = sc.into_config()
+            .with_context(|| format!("scenario {:?}", p))?;
+        cfg.validate()
+            .with_context(|| format!("scenario {:?}", p))?;
+        Ok(cfg)
+    }
+}
+
+/*
+ * The on-disk form of a scenario file.  Every field is optional, but unknown
+ * fields are rejected so that a typo does not silently leave a default in
+ * place.
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Scenario {
+    pool: Option<String>,
+    backend: Option<String>,
+    root: Option<PathBuf>,
+    duration: Option<String>,
+    cleanup: Option<bool>,
+    destroy_unmarked: Option<bool>,
+    report_interval: Option<String>,
+    metrics: Option<String>,
+    report: Option<PathBuf>,
+    log: Option<ScenarioLog>,
+    tools: Option<ScenarioTools>,
+    master_seed: Option<u64>,
+    seed: Option<ScenarioSeed>,
+    plants: Option<ScenarioPlants>,
+    workload: Option<ScenarioWorkload>,
+    backup: Option<ScenarioBackup>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioLog {
+    format: Option<LogFormat>,
+    file: Option<PathBuf>,
+    level: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioTools {
+    privilege: Option<String>,
+    zfs: Option<PathBuf>,
+    zpool: Option<PathBuf>,
+    chown: Option<PathBuf>,
+    chmod: Option<PathBuf>,
+    bash: Option<PathBuf>,
+    btrfs: Option<PathBuf>,
+    owner: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioSeed {
+    count: Option<u64>,
+    files: Option<u64>,
+    file_min: Option<u64>,
+    file_max: Option<u64>,
+    compressible: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioPlants {
+    count: Option<u64>,
+    origin: Option<String>,
+    threads: Option<u64>,
+}
+
+/*
+ * Besides the workload for every plant, "[workload]" may contain a list of
+ * "[[workload.plant]]" tables which choose the workload for some plants.
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioWorkload {
+    plants: Option<String>,
+    name: Option<String>,
+    write_ratio: Option<f64>,
+    compressible: Option<f64>,
+    fsync_ratio: Option<f64>,
+    max_ops: Option<u64>,
+    block_size: Option<u64>,
+    plant: Option<Vec<ScenarioWorkload>>,
+}
+
+impl ScenarioWorkload {
+    fn apply(&self, w: &mut WorkloadMix) {
+        w.name = self.name.clone().unwrap_or_else(|| w.name.clone());
+        w.write_ratio = self.write_ratio.unwrap_or(w.write_ratio);
+        w.compressible = self.compressible.unwrap_or(w.compressible);
+        w.fsync_ratio = self.fsync_ratio.unwrap_or(w.fsync_ratio);
+        w.max_ops = self.max_ops.unwrap_or(w.max_ops);
+        w.block_size = self.block_size.unwrap_or(w.block_size);
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioBackup {
+    enabled: Option<bool>,
+    threads: Option<u64>,
+    max_snaps: Option<usize>,
+    interval: Option<String>,
+}
+
+impl Scenario {
+    fn into_config(self) -> Result<Config> {
+        let mut cfg = Config::default();
+
+        if let Some(pool) = self.pool {
+            cfg.pool = pool;
+        }
+        if let Some(b) = self.backend {
+            cfg.backend = b.parse().context("backend")?;
+        }
+        if self.root.is_some() {
+            cfg.root = self.root;
+        }
+        if let Some(d) = self.duration {
+            cfg.duration = Some(parse_duration(&d).context("duration")?);
+        }
+        if let Some(c) = self.cleanup {
+            cfg.cleanup = c;
+        }
+        if let Some(d) = self.destroy_unmarked {
+            cfg.destroy_unmarked = d;
+        }
+        if let Some(i) = self.report_interval {
+            cfg.report_interval = parse_duration(&i)
+                .context("report_interval")?;
+        }
+        if let Some(m) = self.metrics {
+            cfg.metrics = Some(parse_addr(&m).context("metrics")?);
+        }
+        if self.report.is_some() {
+            cfg.report = self.report;
+        }
+        if let Some(l) = self.log {
+            cfg.log.format = l.format.or(cfg.log.format);
+            cfg.log.file = l.file.or(cfg.log.file);
+            cfg.log.level = l.level.or(cfg.log.level);
+        }
+        if let Some(t) = self.tools {
+            if let Some(p) = t.privilege {
+                cfg.tools.set_privilege(p.parse().context("tools.privilege")?);
+            }
+            for (name, path) in [
+                ("zfs", t.zfs),
+                ("zpool", t.zpool),
+                ("chown", t.chown),
+                ("chmod", t.chmod),
+                ("bash", t.bash),
+                ("btrfs", t.btrfs),
+            ] {
+                if let Some(path) = path {
+                    cfg.tools.set(name, &path)
+                        .with_context(|| format!("tools.{}", name))?;
+                }
+            }
+            if let Some(o) = t.owner {
+                cfg.tools.owner = o.parse().context("tools.owner")?;
+            }
+        }
+        if let Some(ms) = self.master_seed {
+            cfg.master_seed = ms;
+            cfg.master_seed_given = true;
+        }
+
+        if let Some(seed) = self.seed {
+            let r = &mut cfg.seed;
+            cfg.seeds = seed.count.unwrap_or(cfg.seeds);
+            r.files = seed.files.unwrap_or(r.files);
+            r.file_min = seed.file_min.unwrap_or(r.file_min);
+            r.file_max = seed.file_max.unwrap_or(r.file_max);
+            r.compressible = seed.compressible.unwrap_or(r.compressible);
+        }
+
+        if let Some(plants) = self.plants {
+            cfg.plants = plants.count.unwrap_or(cfg.plants);
+            if let Some(o) = plants.origin {
+                cfg.origin = o.parse().context("plants.origin")?;
+            }
+            cfg.plant_threads = plants.threads.unwrap_or(cfg.plant_threads);
+        }
+
+        if let Some(wl) = self.workload {
+            if wl.plants.is_some() {
+                bail!("workload.plants is only valid in [[workload.plant]]");
+            }
+            wl.apply(&mut cfg.workload);
+
+            for p in wl.plant.unwrap_or_default() {
+                let plants = match (&p.plants, &p.plant) {
+                    (Some(plants), None) => parse_plants(plants)
+                        .context("workload.plant")?,
+                    _ => bail!("each [[workload.plant]] must have \
+                        \"plants\", and may not be nested"),
+                };
+                let mut mix = cfg.workload.clone();
+                p.apply(&mut mix);
+                for id in plants {
+                    cfg.plant_workloads.insert(id, mix.clone());
+                }
+            }
+        }
+
+        if let Some(b) = self.backup {
+            let p = &mut cfg.backup;
+            p.enabled = b.enabled.unwrap_or(p.enabled);
+            p.threads = b.threads.unwrap_or(p.threads);
+            p.max_snaps = b.max_snaps.unwrap_or(p.max_snaps);
+            if let Some(i) = b.interval {
+                p.interval = parse_duration(&i)
+                    .context("backup.interval")?;
+            }
+        }
+
+        Ok(cfg)
+    }
+}
+
+/**
+ * Parse a duration like "90", "90s", "15m", "12h" or "2d".  A bare number is
+ * a count of seconds.
+ */
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let (num, mult) = match s.chars().last() {
+        Some('s') => (&s[..s.len() - 1], 1),
+        Some('m') => (&s[..s.len() - 1], 60),
+        Some('h') => (&s[..s.len() - 1], 60 * 60),
+        Some('d') => (&s[..s.len() - 1], 24 * 60 * 60),
+        _ => (s, 1),
+    };
+
+    match num.parse::<u64>() {
+        Ok(n) if n > 0 => match n.checked_mul(mult) {
+            Some(secs) => Ok(Duration::from_secs(secs)),
+            None => bail!("duration too large: {:?}", s),
+        },
+        _ => bail!("invalid duration {:?} (expected e.g. \"90s\", \"15m\", \
+            \"12h\")", s),
+    }
+}
+
+/**
+ * Parse a listen address like "127.0.0.1:9464".
+ */
+pub fn parse_addr(s: &str) -> Result<SocketAddr> {
+    s.parse().map_err(|_| anyhow::anyhow!("invalid address {:?} (expected \
+        e.g. \"127.0.0.1:9464\")", s))
+}
+
+/**
+ * Parse a count that must be at least one.
+ */
+pub fn parse_count(name: &str, s: &str) -> Result<u64> {
+    match s.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => bail!("invalid {} {:?} (expected a positive integer)", name, s),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn scenario(s: &str) -> Result<Config> {
+        let cfg = toml::from_str::<Scenario>(s)?.into_config()?;
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    fn error(s: &str) -> String {
+        format!("{:#}", scenario(s).unwrap_err())
+    }
+
+    #[test]
+    fn default_scenario() {
+        /*
+         * The scenario shipped with the tool claims to show the defaults.
+         */
+        let p = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("scenarios/default.toml");
+        let mut cfg = Config::load(p).unwrap();
+        let mut def = Config::default();
+        cfg.master_seed = 0;
+        def.master_seed = 0;
+        assert_eq!(serde_json::to_value(&cfg).unwrap(),
+            serde_json::to_value(&def).unwrap());
+    }
+
+    #[test]
+    fn settings() {
+        let cfg = scenario(r#"
+            pool = "tank/stress"
+            backend = "dir"
+            root = "/var/tmp/stress"
+            duration = "15m"
+            master_seed = 99
+
+            [seed]
+            count = 2
+            file_min = 1
+            file_max = 1
+
+            [plants]
+            count = 8
+            origin = "round-robin"
+
+            [workload]
+            name = "sequential"
+            block_size = 8192
+
+            [[workload.plant]]
+            plants = "6-7"
+            name = "metadata"
+
+            [backup]
+            interval = "2m"
+        "#).unwrap();
+
+        assert_eq!(cfg.pool, "tank/stress");
+        assert_eq!(cfg.backend, Backend::Dir);
+        assert_eq!(cfg.duration, Some(Duration::from_secs(15 * 60)));
+        assert_eq!(cfg.master_seed, 99);
+        assert!(cfg.master_seed_given);
+        assert_eq!((cfg.seeds, cfg.seed.files, cfg.seed.file_max),
+            (2, 1_000, 1));
+        assert_eq!(cfg.plants, 8);
+        assert_eq!(cfg.origin, Origin::RoundRobin);
+        assert_eq!(cfg.backup.interval, Duration::from_secs(120));
+        assert_eq!(cfg.backup.threads, 8);
+
+        assert_eq!(cfg.workload_for(5).name, "sequential");
+        let w = cfg.workload_for(6);
+        assert_eq!((w.name.as_str(), w.block_size), ("metadata", 8192));
+    }
+
+    #[test]
+    fn file_sizes() {
+        let e = error("[seed]\nfile_min = 8\nfile_max = 4\n");
+        assert!(e.contains("seed.file_min (8) must not exceed seed.file_max \
+            (4)"), "{}", e);
+
+        /*
+         * Each bound is checked against the default for the other.
+         */
+        assert!(scenario("[seed]\nfile_max = 1\n").is_err());
+        assert!(scenario("[seed]\nfile_min = 1\nfile_max = 1\n").is_ok());
+        assert!(error("[seed]\nfile_min = 0\n").contains("at least 1"));
+    }
+
+    #[test]
+    fn unknown_fields() {
+        for s in [
+            "pol = \"tank\"\n",
+            "[seed]\nfile_size = 4\n",
+            "[workload]\nfsync = 0.5\n",
+            "[[workload.plant]]\nplants = \"0\"\nblocksize = 4096\n",
+            "[backups]\nenabled = false\n",
+        ] {
+            assert!(error(s).contains("unknown field"), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn invalid_values() {
+        for (s, msg) in [
+            ("duration = \"soon\"\n", "duration"),
+            ("backend = \"nfs\"\n", "backend"),
+            ("backend = \"dir\"\n", "requires a root directory"),
+            ("pool = \"tank@snap\"\n", "invalid pool name"),
+            ("[plants]\norigin = \"tank/base\"\n", "plants.origin"),
+            ("[workload]\nwrite_ratio = 1.5\n", "write_ratio"),
+            ("[workload]\nplants = \"0\"\n", "only valid in"),
+            ("[[workload.plant]]\nname = \"mixed\"\n", "must have"),
+            ("[plants]\ncount = 2\n[[workload.plant]]\nplants = \"1-2\"\n",
+                "only 2 plants"),
+            ("[backup]\ninterval = \"0\"\n", "backup.interval"),
+        ] {
+            let e = error(s);
+            assert!(e.contains(msg), "{:?}: {}", s, e);
+        }
+    }
+}
@@ -0,0 +1,116 @@
+/*
+ * State shared by every thread taking part in a run: a flag through which
+ * they can all be asked to stop, and the counters in which they record what
+ * they have done.
+ */
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use super::common::*;
+use super::stats::Stats;
+use super::latency::Latency;
+
+pub struct Control {
+    stop: AtomicBool,
+    /*
+     * Set by the signal handlers.  This is kept apart from "stop" so that
+     * only a second signal, and not one arriving after we were asked to stop
+     * for some other reason, causes us to exit immediately.
+     */
+    signalled: Arc<AtomicBool>,
+    pub stats: Stats,
+    pub latency: Latency,
+}
+
+impl Control {
+    pub fn new() -> Arc<Control> {
+        Arc::new(Control {
+            stop: AtomicBool::new(false),
+            signalled: Arc::new(AtomicBool::new(false)),
+            stats: Default::default(),
+            latency: Default::default(),
+        })
+    }
+
+    /**
+     * Arrange for SIGINT or SIGTERM to ask all threads to stop.  If a second
+     * signal arrives before we have finished stopping, exit immediately.
+     */
+    pub fn handle_signals(&self) -> Result<()> {
+        for sig in &[SIGINT, SIGTERM] {
+            signal_hook::flag::register_conditional_shutdown(*sig, 1,
+                Arc::clone(&self.signalled))?;
+            signal_hook::flag::register(*sig, Arc::clone(&self.signalled))?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Ask every thread to stop at its next operation boundary.
+     */
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    pub fn stopping(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+            || self.signalled.load(Ordering::SeqCst)
+    }
+
+    /**
+     * Sleep for up to "ms" milliseconds, but not past the deadline (if there
+     * is one) and not once we have been asked to stop.  Returns false if we
+     * should stop.
+     */
+    pub fn sleep_until(&self, deadline: Option<Instant>, ms: u64) -> bool {
+        let end = Instant::now() + Duration::from_millis(ms);
+        let end = deadline.map(|d| d.min(end)).unwrap_or(end);
+
+        loop {
+            if self.stopping() {
+                return false;
+            }
+
+            let now = Instant::now();
+            if now >= end {
+                return deadline.map(|d| now < d).unwrap_or(true);
+            }
+            std::thread::sleep((end - now).min(Duration::from_millis(100)));
+        }
+    }
+}
+
+/**
+ * Wait for threads to finish, for no longer than "timeout".  Returns the
+ * number of threads which did not finish in time; these are left to their
+ * own devices.
+ */
+pub fn join_all<T>(log: &Logger, threads: Vec<JoinHandle<T>>,
+    timeout: Duration)
+    -> usize
+{
+    let deadline = Instant::now() + timeout;
+
+    while threads.iter().any(|t| !t.is_finished())
+        && Instant::now() < deadline
+    {
+        sleep(100);
+    }
+
+    let mut stuck = 0;
+    for t in threads {
+        if !t.is_finished() {
+            stuck += 1;
+        } else if t.join().is_err() {
+            error!(log, "thread panicked");
+        }
+    }
+
+    if stuck > 0 {
+        warn!(log, "{} threads did not stop within {:?}", stuck, timeout);
+    }
+    stuck
+}
@@ -0,0 +1,933 @@
+/*
+ * A backend built from plain directories, so that the I/O workload can be run
+ * on a filesystem without snapshots or clones (e.g., ext4, xfs or tmpfs).
+ *
+ * The pool is a directory, "root", and each dataset is a directory beneath it
+ * with the same relative name.  The state that ZFS would keep for a dataset
+ * lives in a ".zfs" directory within it, laid out as ZFS presents snapshots,
+ * so that the rest of the tool need not know the difference:
+ *
+ *      .zfs/owner              the marker written when we created it
+ *      .zfs/PREFIX:NAME        the value of any other user property
+ *      .zfs/origin             for a clone, the snapshot it was cloned from
+ *      .zfs/snapshots          the names of the snapshots, oldest first
+ *      .zfs/snapshot/NAME/     the contents of each snapshot
+ *
+ * A snapshot is a copy of the files in the dataset, and a clone is a copy of
+ * the files in a snapshot.  Where the filesystem supports it, files are
+ * copied with the FICLONE ioctl, which shares their blocks rather than
+ * duplicating them; otherwise the data is copied in full.  As with ZFS, a
+ * snapshot cannot be destroyed while a clone of it remains.
+ *
+ * Holds exist only for the lifetime of the backend.
+ *
+ * The btrfs backend ("btrfs.rs") shares this layout, with subvolumes in place
+ * of the copies.
+ */
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use super::common::*;
+use super::zfs::*;
+
+pub(crate) const META: &str = ".zfs";
+
+/*
+ * _IOW(0x94, 9, int), from <linux/fs.h>.
+ */
+#[cfg(target_os = "linux")]
+const FICLONE: libc::c_ulong = 0x4004_9409;
+
+pub struct DirBackend {
+    layout: Layout,
+    guard: Guard,
+    /*
+     * Cleared once the filesystem has refused a reflink, after which we copy
+     * file data instead.
+     */
+    reflink: AtomicBool,
+    holds: Holds,
+    /*
+     * Held while the list of snapshots for any dataset is updated.
+     */
+    lock: Mutex<()>,
+}
+
+/**
+ * Classify a failed filesystem operation in the terms used for ZFS.
+ */
+pub(crate) fn failure(what: &str, p: &Path, e: io::Error) -> ZfsError {
+    match e.raw_os_error() {
+        Some(libc::ENOSPC) | Some(libc::EDQUOT) => ZfsError::OutOfSpace,
+        Some(libc::EACCES) | Some(libc::EPERM) => ZfsError::PermissionDenied,
+        _ => ZfsError::Other {
+            code: None,
+            stderr: format!("{} {:?}: {}", what, p, e),
+        },
+    }
+}
+
+pub(crate) fn unsupported(backend: &str, what: &str) -> ZfsError {
+    ZfsError::Other {
+        code: None,
+        stderr: format!("{} is not supported by the {} backend", what,
+            backend),
+    }
+}
+
+pub(crate) fn is_dataset(p: &Path) -> bool {
+    p.join(META).is_dir()
+}
+
+/*
+ * Read a small metadata file, if it exists.
+ */
+pub(crate) fn read_meta(p: &Path) -> ZfsResult<Option<String>> {
+    match fs::read_to_string(p) {
+        Ok(s) => Ok(Some(s.trim_end_matches('\n').to_string())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(failure("reading", p, e)),
+    }
+}
+
+fn write_meta(p: &Path, val: &str) -> ZfsResult<()> {
+    fs::write(p, format!("{}\n", val)).map_err(|e| failure("writing", p, e))
+}
+
+/*
+ * Share the blocks of "src" with "dst".  Returns false if reflinks are not
+ * available on this filesystem, or not between these files.
+ */
+#[cfg(target_os = "linux")]
+fn reflink(src: &fs::File, dst: &fs::File) -> io::Result<bool> {
+    let r = unsafe {
+        libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd())
+    };
+    if r == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::EXDEV) | Some(libc::EINVAL)
+            | Some(libc::ENOTTY) => Ok(false),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_src: &fs::File, _dst: &fs::File) -> io::Result<bool> {
+    Ok(false)
+}
+
+/**
+ * Where the datasets of a pool live, when the pool is a directory.
+ */
+pub(crate) struct Layout {
+    pub root: PathBuf,
+    pub pool: String,
+}
+
+impl Layout {
+    /**
+     * The directory for a dataset, which need not exist.
+     */
+    pub fn path(&self, dataset: &str) -> ZfsResult<PathBuf> {
+        validate_dataset_name(dataset)?;
+        if dataset == self.pool {
+            return Ok(self.root.clone());
+        }
+        match dataset.strip_prefix(&self.pool)
+            .and_then(|rest| rest.strip_prefix('/'))
+        {
+            Some(rest) if !rest.is_empty()
+                && rest.split('/').all(|c| !c.is_empty() && c != "."
+                    && c != ".." && c != META) =>
+            {
+                Ok(self.root.join(rest))
+            }
+            _ => Err(ZfsError::InvalidName(dataset.to_string())),
+        }
+    }
+
+    /**
+     * The directory for a dataset, which must exist.
+     */
+    pub fn existing(&self, dataset: &str) -> ZfsResult<PathBuf> {
+        let p = self.path(dataset)?;
+        if !is_dataset(&p) {
+            return Err(ZfsError::NotFound);
+        }
+        Ok(p)
+    }
+
+    /**
+     * The names of the datasets directly beneath the one in "dir".
+     */
+    pub fn children(&self, dir: &Path) -> ZfsResult<Vec<String>> {
+        let mut out = Vec::new();
+        for ent in fs::read_dir(dir).map_err(|e| failure("listing", dir, e))? {
+            let ent = ent.map_err(|e| failure("listing", dir, e))?;
+            if is_dataset(&ent.path()) {
+                out.push(ent.file_name().to_string_lossy().to_string());
+            }
+        }
+        out.sort();
+        Ok(out)
+    }
+
+    /**
+     * The full names of a dataset and those directly beneath it.
+     */
+    pub fn dataset_children(&self, dataset: &str) -> ZfsResult<Vec<String>> {
+        let p = self.existing(dataset)?;
+        Ok(std::iter::once(dataset.to_string())
+            .chain(self.children(&p)?.into_iter()
+                .map(|c| format!("{}/{}", dataset, c)))
+            .collect())
+    }
+}
+
+/**
+ * The tags held on each snapshot, by full snapshot name.
+ */
+#[derive(Default)]
+pub(crate) struct Holds {
+    tags: Mutex<BTreeMap<String, BTreeSet<String>>>,
+}
+
+impl Holds {
+    pub fn held(&self, fullname: &str) -> bool {
+        let tags = self.tags.lock().unwrap();
+        tags.get(fullname).map(|t| !t.is_empty()).unwrap_or(false)
+    }
+
+    /**
+     * Whether any snapshot of this dataset, or of those beneath it, is held.
+     */
+    pub fn any_held(&self, dataset: &str) -> bool {
+        let tags = self.tags.lock().unwrap();
+        tags.iter().any(|(name, t)| {
+            !t.is_empty() && name.split_once('@').map(|(ds, _)| {
+                ds == dataset || ds.starts_with(&format!("{}/", dataset))
+            }).unwrap_or(false)
+        })
+    }
+
+    pub fn hold(&self, fullname: &str, tag: &str) -> ZfsResult<()> {
+        let mut tags = self.tags.lock().unwrap();
+        if !tags.entry(fullname.to_string()).or_default()
+            .insert(tag.to_string())
+        {
+            return Err(ZfsError::Other {
+                code: None,
+                stderr: format!("cannot hold snapshot '{}': tag already \
+                    exists on this dataset", fullname),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn release(&self, fullname: &str, tag: &str) -> ZfsResult<()> {
+        let mut tags = self.tags.lock().unwrap();
+        if !tags.get_mut(fullname).map(|t| t.remove(tag)).unwrap_or(false) {
+            return Err(ZfsError::Other {
+                code: None,
+                stderr: format!("cannot release hold from snapshot '{}': no \
+                    such tag on this dataset", fullname),
+            });
+        }
+        Ok(())
+    }
+
+    /**
+     * Discard the holds on a dataset which has been destroyed, and on those
+     * beneath it.
+     */
+    pub fn forget(&self, dataset: &str) {
+        let mut tags = self.tags.lock().unwrap();
+        tags.retain(|name, _| {
+            !name.starts_with(&format!("{}@", dataset))
+                && !name.starts_with(&format!("{}/", dataset))
+        });
+    }
+}
+
+impl DirBackend {
+    /**
+     * Use "root" as the pool with the given name.  The directory must exist
+     * already.
+     */
+    pub fn new(log: &Logger, root: &Path, pool: &str, guard: Guard)
+        -> Result<DirBackend>
+    {
+        if !root.is_dir() {
+            bail!("root {:?} is not a directory", root);
+        }
+        let meta = root.join(META);
+        fs::create_dir_all(&meta)
+            .with_context(|| format!("creating {:?}", meta))?;
+        info!(log, "using directories in {:?}", root);
+
+        Ok(DirBackend {
+            layout: Layout {
+                root: root.to_path_buf(),
+                pool: pool.to_string(),
+            },
+            guard,
+            reflink: AtomicBool::new(true),
+            holds: Default::default(),
+            lock: Mutex::new(()),
+        })
+    }
+
+    fn snapshots(&self, dir: &Path) -> ZfsResult<Vec<String>> {
+        Ok(read_meta(&dir.join(META).join("snapshots"))?
+            .map(|s| s.lines().map(str::to_string).collect())
+            .unwrap_or_default())
+    }
+
+    fn set_snapshots(&self, dir: &Path, snaps: &[String]) -> ZfsResult<()> {
+        let p = dir.join(META).join("snapshots");
+        if snaps.is_empty() {
+            return match fs::remove_file(&p) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    Err(failure("removing", &p, e))
+                }
+                _ => Ok(()),
+            };
+        }
+        write_meta(&p, &snaps.join("\n"))
+    }
+
+    /**
+     * The datasets which were cloned from a snapshot whose full name
+     * satisfies "from".  Our clones are copies, but as with ZFS, they keep
+     * the snapshot from being destroyed, so that the rest of the tool cannot
+     * come to depend on destroying things in an order ZFS would refuse.
+     */
+    fn clones<F>(&self, from: F) -> ZfsResult<Vec<String>>
+        where F: Fn(&str) -> bool
+    {
+        let mut out = Vec::new();
+        let mut todo = vec![self.layout.pool.clone()];
+        while let Some(ds) = todo.pop() {
+            let p = self.layout.path(&ds)?;
+            if read_meta(&p.join(META).join("origin"))?
+                .map(|o| from(&o))
+                .unwrap_or(false)
+            {
+                out.push(ds.clone());
+            }
+            for c in self.layout.children(&p)? {
+                todo.push(format!("{}/{}", ds, c));
+            }
+        }
+        Ok(out)
+    }
+
+    /**
+     * Copy the files in "src" into the new directory "dst", leaving out the
+     * metadata directory and any datasets nested within.  The copy is not
+     * atomic, so files and directories may be removed from "src" while we
+     * copy it; anything which has gone is left out.
+     */
+    fn copy_tree(&self, log: &Logger, src: &Path, dst: &Path)
+        -> ZfsResult<()>
+    {
+        let list = match fs::read_dir(src) {
+            Ok(list) => list,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(failure("listing", src, e)),
+        };
+        fs::create_dir(dst).map_err(|e| failure("creating", dst, e))?;
+
+        for ent in list {
+            let ent = ent.map_err(|e| failure("listing", src, e))?;
+            let (from, to) = (ent.path(), dst.join(ent.file_name()));
+            if ent.file_name() == META {
+                continue;
+            }
+
+            let md = match fs::symlink_metadata(&from) {
+                Ok(md) => md,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(failure("examining", &from, e)),
+            };
+            let ft = md.file_type();
+            if ft.is_dir() {
+                if !is_dataset(&from) {
+                    self.copy_tree(log, &from, &to)?;
+                }
+            } else if ft.is_file() {
+                self.copy_file(log, &from, &to, &md)?;
+            } else if ft.is_symlink() {
+                let target = fs::read_link(&from)
+                    .map_err(|e| failure("reading", &from, e))?;
+                std::os::unix::fs::symlink(&target, &to)
+                    .map_err(|e| failure("creating", &to, e))?;
+            }
+        }
+
+        let md = match fs::metadata(src) {
+            Ok(md) => md,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(failure("examining", src, e)),
+        };
+        fs::set_permissions(dst, md.permissions())
+            .map_err(|e| failure("setting permissions on", dst, e))?;
+        Ok(())
+    }
+
+    /*
+     * Copy a file, sharing its blocks if we can.  The modification time is
+     * preserved, so that an incremental send can tell which files changed.
+     */
+    fn copy_file(&self, log: &Logger, from: &Path, to: &Path,
+        md: &fs::Metadata)
+        -> ZfsResult<()>
+    {
+        let mut src = match fs::File::open(from) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(failure("opening", from, e)),
+        };
+        let mut dst = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(to)
+            .map_err(|e| failure("creating", to, e))?;
+
+        let cloned = self.reflink.load(Ordering::Relaxed)
+            && reflink(&src, &dst).map_err(|e| failure("cloning", from, e))?;
+        if !cloned {
+            if self.reflink.swap(false, Ordering::Relaxed) {
+                warn!(log, #"zfs", "reflinks are not supported in {:?}, so \
+                    file data will be copied; snapshots cannot be checked \
+                    against acknowledged writes", self.layout.root);
+            }
+            io::copy(&mut src, &mut dst)
+                .map_err(|e| failure("copying", from, e))?;
+        }
+
+        dst.set_permissions(md.permissions())
+            .map_err(|e| failure("setting permissions on", to, e))?;
+        dst.set_modified(md.modified()
+            .map_err(|e| failure("examining", from, e))?)
+            .map_err(|e| failure("setting times on", to, e))?;
+        Ok(())
+    }
+
+    /**
+     * Build a new dataset in a scratch directory alongside it, using "fill"
+     * to populate it, and then move it into place.  An interrupted create
+     * leaves nothing behind that looks like a dataset.
+     */
+    fn make<F>(&self, dataset: &str, origin: Option<&str>, fill: F)
+        -> ZfsResult<()>
+        where F: FnOnce(&Path) -> ZfsResult<()>
+    {
+        let p = self.layout.path(dataset)?;
+        let parent = p.parent().unwrap();
+        if !is_dataset(parent) {
+            return Err(ZfsError::NotFound);
+        }
+        if p.exists() {
+            return Err(ZfsError::AlreadyExists);
+        }
+
+        let tmp = parent.join(META).join(format!("creating.{}.{}",
+            p.file_name().unwrap().to_string_lossy(), std::process::id()));
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)
+                .map_err(|e| failure("removing", &tmp, e))?;
+        }
+
+        fill(&tmp)?;
+        let meta = tmp.join(META);
+        fs::create_dir(&meta).map_err(|e| failure("creating", &meta, e))?;
+        write_meta(&meta.join("owner"), &self.guard.run_id)?;
+        if let Some(o) = origin {
+            write_meta(&meta.join("origin"), o)?;
+        }
+
+        fs::rename(&tmp, &p).map_err(|e| match e.raw_os_error() {
+            Some(libc::EEXIST) | Some(libc::ENOTEMPTY) => {
+                ZfsError::AlreadyExists
+            }
+            _ => failure("renaming", &tmp, e),
+        })
+    }
+}
+
+impl ZfsBackend for DirBackend {
+    fn destroy_snapshot(&self, log: &Logger, dataset: &str, snapname: &str)
+        -> ZfsResult<()>
+    {
+        validate_snapshot_name(snapname)?;
+        let p = match self.layout.existing(dataset) {
+            Ok(p) => p,
+            Err(ZfsError::NotFound) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let fullname = format!("{}@{}", dataset, snapname);
+        debug!(log, #"zfs", "destroy snapshot {}", fullname);
+
+        let dir = p.join(META).join("snapshot").join(snapname);
+        {
+            let _g = self.lock.lock().unwrap();
+            if self.holds.held(&fullname) {
+                return Err(ZfsError::Busy);
+            }
+            if !self.clones(|o| o == fullname)?.is_empty() {
+                return Err(ZfsError::HasDependentClones);
+            }
+
+            /*
+             * Drop the snapshot from the list first, so that it is gone even
+             * if we are interrupted while removing the files.
+             */
+            let mut snaps = self.snapshots(&p)?;
+            snaps.retain(|s| s != snapname);
+            self.set_snapshots(&p, &snaps)?;
+        }
+
+        match fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(failure("removing", &dir, e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn destroy(&self, log: &Logger, dataset: &str, recursive: bool)
+        -> ZfsResult<()>
+    {
+        let p = match self.layout.existing(dataset) {
+            Ok(p) => p,
+            Err(ZfsError::NotFound) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if dataset == self.layout.pool {
+            return Err(unsupported("directory", "destroying the root"));
+        }
+        debug!(log, #"zfs", "destroy {}", dataset; "recursive" => recursive);
+
+        if recursive {
+            if !self.guard.destroy_unmarked
+                && read_meta(&p.join(META).join("owner"))?.is_none()
+            {
+                return Err(ZfsError::Unmarked(dataset.to_string()));
+            }
+            if self.holds.any_held(dataset) {
+                return Err(ZfsError::Busy);
+            }
+            let within = |n: &str| n == dataset
+                || n.strip_prefix(dataset)
+                    .map(|r| r.starts_with('/') || r.starts_with('@'))
+                    .unwrap_or(false);
+            if self.clones(within)?.iter().any(|c| !within(c)) {
+                return Err(ZfsError::HasDependentClones);
+            }
+        } else if !self.layout.children(&p)?.is_empty()
+            || !self.snapshots(&p)?.is_empty()
+        {
+            return Err(ZfsError::Other {
+                code: None,
+                stderr: format!("cannot destroy '{}': filesystem has \
+                    children", dataset),
+            });
+        }
+
+        /*
+         * Move the dataset aside before removing it, so that an interrupted
+         * destroy does not leave part of it behind.
+         */
+        let parent = p.parent().unwrap();
+        let tmp = parent.join(META).join(format!("destroying.{}.{}",
+            p.file_name().unwrap().to_string_lossy(), std::process::id()));
+        fs::rename(&p, &tmp).map_err(|e| failure("renaming", &p, e))?;
+        fs::remove_dir_all(&tmp).map_err(|e| failure("removing", &tmp, e))?;
+
+        self.holds.forget(dataset);
+        Ok(())
+    }
+
+    fn create(&self, log: &Logger, dataset: &str, exists_ok: bool)
+        -> ZfsResult<()>
+    {
+        debug!(log, #"zfs", "create {}", dataset);
+
+        match self.make(dataset, None, |tmp| {
+            fs::create_dir(tmp).map_err(|e| failure("creating", tmp, e))
+        }) {
+            Err(ZfsError::AlreadyExists) if exists_ok => Ok(()),
+            res => res,
+        }
+    }
+
+    fn snapshot(&self, log: &Logger, dataset: &str, name: &str,
+        recursive: bool)
+        -> ZfsResult<()>
+    {
+        validate_snapshot_name(name)?;
+        let p = self.layout.existing(dataset)?;
+        debug!(log, #"zfs", "snapshot {}@{}", dataset, name);
+
+        let dir = p.join(META).join("snapshot");
+        let snap = dir.join(name);
+        if self.snapshots(&p)?.iter().any(|s| s == name) {
+            return Err(ZfsError::AlreadyExists);
+        }
+
+        /*
+         * Copy the files somewhere out of the way, in case we are interrupted,
+         * and only then make the snapshot visible.
+         */
+        fs::create_dir_all(&dir).map_err(|e| failure("creating", &dir, e))?;
+        let tmp = dir.join(format!(".{}.{}", name, std::process::id()));
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)
+                .map_err(|e| failure("removing", &tmp, e))?;
+        }
+        if snap.exists() {
+            fs::remove_dir_all(&snap)
+                .map_err(|e| failure("removing", &snap, e))?;
+        }
+        self.copy_tree(log, &p, &tmp)?;
+        fs::rename(&tmp, &snap).map_err(|e| failure("renaming", &tmp, e))?;
+
+        {
+            let _g = self.lock.lock().unwrap();
+            let mut snaps = self.snapshots(&p)?;
+            snaps.push(name.to_string());
+            self.set_snapshots(&p, &snaps)?;
+        }
+
+        if recursive {
+            for child in self.layout.children(&p)? {
+                self.snapshot(log, &format!("{}/{}", dataset, child), name,
+                    true)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn clone_snapshot(&self, log: &Logger, dataset: &str, snapname: &str,
+        target: &str)
+        -> ZfsResult<()>
+    {
+        validate_snapshot_name(snapname)?;
+        if !self.snapshot_exists(log, dataset, snapname)? {
+            return Err(ZfsError::NotFound);
+        }
+        let fullname = format!("{}@{}", dataset, snapname);
+        debug!(log, #"zfs", "clone {} {}", fullname, target);
+
+        let snap = self.layout.path(dataset)?.join(META).join("snapshot")
+            .join(snapname);
+        self.make(target, Some(&fullname),
+            |tmp| self.copy_tree(log, &snap, tmp))
+    }
+
+    fn get(&self, log: &Logger, dataset: &str, prop: &str)
+        -> ZfsResult<String>
+    {
+        let p = self.layout.existing(dataset)?;
+        debug!(log, #"zfs", "get {} {}", prop, dataset);
+
+        let meta = |name: &str| -> ZfsResult<String> {
+            Ok(read_meta(&p.join(META).join(name))?
+                .unwrap_or_else(|| "-".to_string()))
+        };
+
+        match prop {
+            "mountpoint" => Ok(p.to_string_lossy().to_string()),
+            "origin" => meta("origin"),
+            OWNER_PROP => meta("owner"),
+            p if p.contains(':') => meta(p),
+            _ => Err(unsupported("directory",
+                &format!("property {:?}", prop))),
+        }
+    }
+
+    fn set(&self, log: &Logger, dataset: &str, prop: &str, value: &str)
+        -> ZfsResult<()>
+    {
+        let p = self.layout.existing(dataset)?;
+        debug!(log, #"zfs", "set {}={} {}", prop, value, dataset);
+
+        if !prop.contains(':') || prop == OWNER_PROP {
+            return Err(unsupported("directory",
+                &format!("setting property {:?}", prop)));
+        }
+        write_meta(&p.join(META).join(prop), value)
+    }
+
+    fn snapshot_exists(&self, _log: &Logger, dataset: &str, snapname: &str)
+        -> ZfsResult<bool>
+    {
+        validate_snapshot_name(snapname)?;
+        match self.layout.existing(dataset) {
+            Ok(p) => Ok(self.snapshots(&p)?.iter().any(|s| s == snapname)),
+            Err(ZfsError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn dataset_children(&self, _log: &Logger, dataset: &str)
+        -> ZfsResult<Vec<String>>
+    {
+        self.layout.dataset_children(dataset)
+    }
+
+    fn snapshot_list(&self, _log: &Logger, dataset: &str)
+        -> ZfsResult<Vec<String>>
+    {
+        let p = self.layout.existing(dataset)?;
+        self.snapshots(&p)
+    }
+
+    /**
+     * Read every file which differs between the two snapshots, as far as
+     * their size and modification time can tell.
+     */
+    fn send_to_null(&self, log: &Logger, dataset: &str, snapold: &str,
+        snapnew: &str)
+        -> ZfsResult<bool>
+    {
+        for s in &[snapold, snapnew] {
+            if !self.snapshot_exists(log, dataset, s)? {
+                return Err(ZfsError::NotFound);
+            }
+        }
+        debug!(log, #"zfs", "send -i {}@{} {}@{}", dataset, snapold, dataset,
+            snapnew);
+
+        let dir = self.layout.path(dataset)?.join(META).join("snapshot");
+        let (old, new) = (dir.join(snapold), dir.join(snapnew));
+
+        for ent in walkdir::WalkDir::new(&new) {
+            let ent = ent.map_err(|e| ZfsError::Other {
+                code: None,
+                stderr: format!("walking {:?}: {}", new, e),
+            })?;
+            if !ent.file_type().is_file() {
+                continue;
+            }
+
+            let md = ent.metadata().map_err(|e| ZfsError::Other {
+                code: None,
+                stderr: format!("examining {:?}: {}", ent.path(), e),
+            })?;
+            let prev = fs::metadata(old.join(ent.path()
+                .strip_prefix(&new).unwrap())).ok();
+            if let Some(prev) = prev {
+                if prev.len() == md.len()
+                    && prev.modified().ok() == md.modified().ok()
+                {
+                    continue;
+                }
+            }
+
+            let mut f = fs::File::open(ent.path())
+                .map_err(|e| failure("opening", ent.path(), e))?;
+            io::copy(&mut f, &mut io::sink())
+                .map_err(|e| failure("reading", ent.path(), e))?;
+        }
+        Ok(true)
+    }
+
+    fn hold(&self, log: &Logger, dataset: &str, snapname: &str, tag: &str)
+        -> ZfsResult<()>
+    {
+        if !self.snapshot_exists(log, dataset, snapname)? {
+            return Err(ZfsError::NotFound);
+        }
+        let fullname = format!("{}@{}", dataset, snapname);
+        debug!(log, #"zfs", "hold {} {}", tag, fullname);
+
+        self.holds.hold(&fullname, tag)
+    }
+
+    fn release(&self, log: &Logger, dataset: &str, snapname: &str, tag: &str)
+        -> ZfsResult<()>
+    {
+        validate_snapshot_name(snapname)?;
+        let fullname = format!("{}@{}", dataset, snapname);
+        debug!(log, #"zfs", "release {} {}", tag, fullname);
+
+        self.holds.release(&fullname, tag)
+    }
+
+    fn allow(&self, _log: &Logger, _dataset: &str, _user: &str,
+        _perms: &[&str])
+        -> ZfsResult<()>
+    {
+        Err(unsupported("directory", "delegation"))
+    }
+
+    fn allowed(&self, _log: &Logger, _dataset: &str)
+        -> ZfsResult<Vec<Delegation>>
+    {
+        Err(unsupported("directory", "delegation"))
+    }
+
+    fn consistent_snapshots(&self) -> bool {
+        self.reflink.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::testutil::{self, TempDir};
+
+    fn setup(name: &str) -> (Logger, TempDir, DirBackend) {
+        let log = testutil::logger();
+        let top = TempDir::new("dir", name);
+
+        let zfs = DirBackend::new(&log, top.path(), "tank", testutil::guard())
+            .unwrap();
+        testutil::populate(&log, &zfs);
+        (log, top, zfs)
+    }
+
+    #[test]
+    fn datasets() {
+        let (log, top, zfs) = setup("datasets");
+
+        assert_eq!(zfs.dataset_children(&log, "tank").unwrap(),
+            vec!["tank", "tank/plant", "tank/seed"]);
+        assert_eq!(zfs.get(&log, "tank/seed/0000", "mountpoint").unwrap(),
+            top.path().join("seed/0000").to_string_lossy());
+        assert_eq!(zfs.get(&log, "tank/seed/0000", OWNER_PROP).unwrap(),
+            "test");
+
+        assert!(matches!(zfs.create(&log, "tank/seed", false),
+            Err(ZfsError::AlreadyExists)));
+        zfs.create(&log, "tank/seed", true).unwrap();
+        assert!(matches!(zfs.create(&log, "tank/nothere/child", true),
+            Err(ZfsError::NotFound)));
+        assert!(matches!(zfs.create(&log, "tank/seed/.zfs", false),
+            Err(ZfsError::InvalidName(_))));
+
+        zfs.destroy(&log, "tank/plant", false).unwrap();
+        zfs.destroy(&log, "tank/plant", false).unwrap();
+        assert_eq!(zfs.dataset_children(&log, "tank").unwrap(),
+            vec!["tank", "tank/seed"]);
+    }
+
+    #[test]
+    fn snapshots() {
+        let (log, top, zfs) = setup("snapshots");
+        let ds = "tank/plant";
+        let file = top.path().join("plant/file");
+
+        /*
+         * Each snapshot keeps the files as they were when it was taken, and
+         * snapshots are listed in the order they were taken.
+         */
+        for i in 0..4 {
+            fs::write(&file, format!("version {}", i)).unwrap();
+            zfs.snapshot(&log, ds, &format!("backup-{}", 3 - i), false)
+                .unwrap();
+        }
+        assert_eq!(zfs.snapshot_list(&log, ds).unwrap(),
+            vec!["backup-3", "backup-2", "backup-1", "backup-0"]);
+        let snap = PathBuf::from(zfs.get(&log, ds, "mountpoint").unwrap())
+            .join(".zfs/snapshot/backup-2/file");
+        assert_eq!(fs::read_to_string(snap).unwrap(), "version 1");
+
+        assert!(matches!(zfs.snapshot(&log, ds, "backup-2", false),
+            Err(ZfsError::AlreadyExists)));
+
+        zfs.destroy_snapshot(&log, ds, "backup-2").unwrap();
+        zfs.destroy_snapshot(&log, ds, "backup-2").unwrap();
+        assert!(!zfs.snapshot_exists(&log, ds, "backup-2").unwrap());
+        assert_eq!(zfs.snapshot_list(&log, ds).unwrap(),
+            vec!["backup-3", "backup-1", "backup-0"]);
+
+        /*
+         * A dataset with snapshots may only be destroyed recursively.
+         */
+        let e = zfs.destroy(&log, ds, false).unwrap_err();
+        assert!(e.to_string().contains("filesystem has children"));
+        zfs.destroy(&log, ds, true).unwrap();
+        assert!(!top.path().join("plant").exists());
+    }
+
+    #[test]
+    fn clones() {
+        let (log, top, zfs) = setup("clones");
+
+        fs::write(top.path().join("seed/0000/file"), "seed data").unwrap();
+        zfs.snapshot(&log, "tank/seed/0000", "later", false).unwrap();
+        zfs.set(&log, "tank/seed/0000", SEED_PROP, "42").unwrap();
+
+        zfs.clone_snapshot(&log, "tank/seed/0000", "later", "tank/plant/0000")
+            .unwrap();
+        let p = PathBuf::from(zfs.get(&log, "tank/plant/0000", "mountpoint")
+            .unwrap());
+        assert_eq!(fs::read_to_string(p.join("file")).unwrap(), "seed data");
+        assert_eq!(zfs.get(&log, "tank/plant/0000", "origin").unwrap(),
+            "tank/seed/0000@later");
+        assert_eq!(zfs.get(&log, "tank/plant/0000", SEED_PROP).unwrap(), "-");
+        assert!(zfs.snapshot_list(&log, "tank/plant/0000").unwrap()
+            .is_empty());
+
+        assert!(matches!(zfs.clone_snapshot(&log, "tank/seed/0000", "nope",
+            "tank/plant/0001"), Err(ZfsError::NotFound)));
+
+        /*
+         * The clone keeps its origin from being destroyed, but not any other
+         * snapshot, until the clone itself is gone.
+         */
+        assert!(matches!(zfs.destroy_snapshot(&log, "tank/seed/0000",
+            "later"), Err(ZfsError::HasDependentClones)));
+        assert!(matches!(zfs.destroy(&log, "tank/seed", true),
+            Err(ZfsError::HasDependentClones)));
+        zfs.snapshot(&log, "tank/plant/0000", "backup-1", false).unwrap();
+        zfs.clone_snapshot(&log, "tank/plant/0000", "backup-1",
+            "tank/plant/0001").unwrap();
+        assert!(matches!(zfs.destroy(&log, "tank/plant/0000", true),
+            Err(ZfsError::HasDependentClones)));
+        zfs.destroy(&log, "tank/plant", true).unwrap();
+
+        zfs.destroy_snapshot(&log, "tank/seed/0000", "later").unwrap();
+        zfs.destroy(&log, "tank/seed", true).unwrap();
+    }
+
+    #[test]
+    fn destroy_guard() {
+        let (log, top, zfs) = setup("guard");
+
+        /*
+         * Neither the root nor a dataset we did not create may be destroyed
+         * recursively.
+         */
+        assert!(zfs.destroy(&log, "tank", true).is_err());
+        fs::create_dir_all(top.path().join("other/.zfs")).unwrap();
+        assert!(matches!(zfs.destroy(&log, "tank/other", true),
+            Err(ZfsError::Unmarked(_))));
+
+        /*
+         * A held snapshot keeps its dataset, too.
+         */
+        zfs.hold(&log, "tank/seed/0000", "final", "keep").unwrap();
+        assert!(matches!(zfs.destroy_snapshot(&log, "tank/seed/0000",
+            "final"), Err(ZfsError::Busy)));
+        assert!(matches!(zfs.destroy(&log, "tank/seed", true),
+            Err(ZfsError::Busy)));
+        zfs.release(&log, "tank/seed/0000", "final", "keep").unwrap();
+
+        zfs.destroy(&log, "tank/seed", true).unwrap();
+        assert!(!top.path().join("seed").exists());
+        assert_eq!(zfs.dataset_children(&log, "tank").unwrap(),
+            vec!["tank", "tank/other", "tank/plant"]);
+    }
+}
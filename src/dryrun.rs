@@ -0,0 +1,81 @@
+/*
+ * A dry run plans the changes that a run would make to a pool, without making
+ * any of them.  The commands are constructed exactly as they would be for a
+ * real run, but are handed to a simulation of the pool which starts out in the
+ * same state as the real one.  Each command that would change something is
+ * logged, so that a scenario can be reviewed before it is let loose.
+ */
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use super::common::*;
+use super::sim::SimZfs;
+use super::tools::Tools;
+use super::zfs::{CliZfs, CommandRunner, Guard};
+
+pub struct DryRun {
+    log: Logger,
+    zfs: PathBuf,
+    sim: SimZfs,
+}
+
+impl DryRun {
+    pub fn new(log: &Logger, tools: &Tools, mut sim: SimZfs) -> DryRun {
+        sim.use_tools(tools);
+        DryRun {
+            log: log.clone(),
+            zfs: tools.zfs.clone(),
+            sim,
+        }
+    }
+
+    /**
+     * Create a backend which plans its changes against this dry run.
+     */
+    pub fn backend(self, tools: Tools, guard: Guard) -> CliZfs {
+        CliZfs::with_runner(tools, guard, Box::new(self))
+    }
+
+    /**
+     * Whether a command would change the pool.  Only listing and getting
+     * properties (or delegated permissions) do not; the one command we run
+     * other than "zfs" is the "zfs send" pipeline.
+     */
+    fn changes(&self, argv: &[String]) -> bool {
+        let i = match argv.iter().position(|a| Path::new(a) == self.zfs) {
+            Some(i) => i,
+            None => return true,
+        };
+        match argv.get(i + 1).map(|a| a.as_str()) {
+            Some("list") | Some("get") => false,
+            Some("allow") => argv.len() > i + 3,
+            _ => true,
+        }
+    }
+}
+
+impl CommandRunner for DryRun {
+    fn run(&self, cmd: &mut Command) -> std::io::Result<Output> {
+        let argv = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|a| a.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+
+        let out = self.sim.run(cmd)?;
+        if out.status.success() && self.changes(&argv) {
+            let words = argv.iter()
+                .map(|a| if a.contains(char::is_whitespace) {
+                    format!("{:?}", a)
+                } else {
+                    a.to_string()
+                })
+                .collect::<Vec<_>>();
+            info!(self.log, #"zfs", "would run: {}", words.join(" "));
+        }
+        Ok(out)
+    }
+
+    fn dry_run(&self) -> bool {
+        true
+    }
+}
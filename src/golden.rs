@@ -0,0 +1,435 @@
+/*
+ * A record of the writes within a plant that the filesystem has acknowledged
+ * as durable (i.e., that were followed by a successful fsync).  Every such
+ * block must appear in any snapshot taken after the acknowledgement, with the
+ * same or a later generation.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileExt;
+use super::common::*;
+use super::integrity::{self, BlockState, Finding, BLOCK_SIZE};
+
+#[derive(Default)]
+struct Acked {
+    /*
+     * Incremented whenever the file is replaced wholesale, at which point any
+     * previous acknowledgements no longer apply.
+     */
+    epoch: u64,
+    blocks: BTreeMap<u64, u64>,
+}
+
+#[derive(Default)]
+pub struct FileLog {
+    /*
+     * Held while allocating a generation and issuing the write, so that the
+     * writes to any block of the file land in generation order.
+     */
+    write_lock: Mutex<()>,
+    acked: Mutex<Acked>,
+}
+
+impl FileLog {
+    /**
+     * Write a run of consecutive blocks to the file, in a single write,
+     * returning the generation they were given.
+     */
+    pub fn write(&self, f: &fs::File, buf: &mut Vec<u8>, fid: u64,
+        offset: u64, count: usize, compressible: bool)
+        -> Result<u64>
+    {
+        let _g = self.write_lock.lock().unwrap();
+
+        let gen = integrity::next_generation();
+        integrity::make_blocks(buf, fid, offset, count, gen, compressible);
+        f.write_all_at(buf, offset)?;
+        Ok(gen)
+    }
+
+    /**
+     * Record that these writes, each an (offset, generation) pair, have been
+     * made durable by an fsync.
+     */
+    pub fn ack(&self, writes: &[(u64, u64)]) {
+        let mut acked = self.acked.lock().unwrap();
+        for (offset, gen) in writes {
+            let e = acked.blocks.entry(*offset).or_insert(0);
+            *e = (*e).max(*gen);
+        }
+    }
+
+    /**
+     * Capture the acknowledged writes to the blocks within "len" bytes of
+     * "offset", before reading them back with "check()".
+     */
+    pub fn acked(&self, offset: u64, len: u64) -> Acks {
+        let acked = self.acked.lock().unwrap();
+        Acks {
+            epoch: acked.epoch,
+            blocks: acked.blocks.range(offset..offset + len)
+                .map(|(o, g)| (*o, *g))
+                .collect(),
+        }
+    }
+
+    /**
+     * Check the state of a block, read after "acks" was captured, against any
+     * write to it that had been acknowledged by then.  Damaged blocks are not
+     * the business of the model; see "Finding::new()".
+     */
+    pub fn check(&self, acks: &Acks, path: &Path, offset: u64,
+        state: &BlockState)
+        -> Option<Finding>
+    {
+        let gen = *acks.blocks.get(&offset)?;
+        let actual = match state {
+            BlockState::Valid { gen: found } if *found >= gen => return None,
+            BlockState::Valid { gen: found } => {
+                format!("generation {}", found)
+            }
+            BlockState::Unwritten => "block was never written".to_string(),
+            BlockState::Corrupt => return None,
+        };
+
+        if self.acked.lock().unwrap().epoch != acks.epoch {
+            /*
+             * The file was replaced while we were reading it.
+             */
+            return None;
+        }
+
+        Some(lost(path, (offset, gen), "it was read", &actual))
+    }
+
+    /**
+     * Replace the file entirely, discarding any record of what was in it.
+     */
+    pub fn replace<F>(&self, func: F) -> Result<()>
+        where F: FnOnce() -> Result<()>
+    {
+        let _g = self.write_lock.lock().unwrap();
+
+        {
+            let mut acked = self.acked.lock().unwrap();
+            acked.epoch += 1;
+            acked.blocks.clear();
+        }
+        func()
+    }
+}
+
+/**
+ * The acknowledged writes within part of a file at a particular instant.
+ */
+pub struct Acks {
+    epoch: u64,
+    blocks: BTreeMap<u64, u64>,
+}
+
+/**
+ * The golden model for one plant, keyed by the path of each file relative to
+ * the plant mountpoint.
+ */
+#[derive(Default)]
+pub struct GoldenModel {
+    files: Mutex<HashMap<PathBuf, Arc<FileLog>>>,
+}
+
+struct Expected {
+    relpath: PathBuf,
+    log: Arc<FileLog>,
+    epoch: u64,
+    blocks: BTreeMap<u64, u64>,
+}
+
+/**
+ * The set of acknowledged blocks at a particular instant.
+ */
+pub struct Expectation {
+    files: Vec<Expected>,
+}
+
+impl GoldenModel {
+    pub fn file<P: AsRef<Path>>(&self, relpath: P) -> Arc<FileLog> {
+        let mut files = self.files.lock().unwrap();
+        Arc::clone(files.entry(relpath.as_ref().to_path_buf())
+            .or_default())
+    }
+
+    /**
+     * Capture the acknowledged state of every file.  This must be called
+     * before the snapshot is taken.
+     */
+    pub fn expect(&self) -> Expectation {
+        let files = self.files.lock().unwrap();
+        Expectation {
+            files: files.iter().map(|(p, fl)| {
+                let acked = fl.acked.lock().unwrap();
+                Expected {
+                    relpath: p.clone(),
+                    log: Arc::clone(fl),
+                    epoch: acked.epoch,
+                    blocks: acked.blocks.clone(),
+                }
+            }).collect(),
+        }
+    }
+}
+
+impl Expectation {
+    /**
+     * Check a snapshot, whose contents are visible under "snapdir", against
+     * the acknowledged state.  Returns the number of blocks checked, or the
+     * first block found to be missing or damaged.
+     */
+    pub fn verify<P: AsRef<Path>>(&self, log: &Logger, snapdir: P)
+        -> Result<u64>
+    {
+        let snapdir = snapdir.as_ref();
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        let mut count = 0;
+
+        for Expected { relpath, log: fl, epoch, blocks } in &self.files {
+            if fl.acked.lock().unwrap().epoch != *epoch {
+                /*
+                 * The file was replaced while we were taking the snapshot, so
+                 * we cannot know which version the snapshot should hold.
+                 */
+                debug!(log, #"backup", "skipping replaced file {:?}", relpath);
+                continue;
+            }
+
+            let path = snapdir.join(relpath);
+            let fid = integrity::file_id(relpath);
+            let first = match blocks.iter().next() {
+                Some((offset, gen)) => (*offset, *gen),
+                None => continue,
+            };
+
+            /*
+             * A file that has gone missing from the snapshot, or has been cut
+             * short, has lost acknowledged writes just as surely as one with
+             * a damaged block.
+             */
+            let f = match fs::File::open(&path) {
+                Ok(f) => f,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    bail!(lost(&path, first, "the snapshot", "no such file"));
+                }
+                Err(e) => {
+                    return Err(anyhow::Error::from(e)
+                        .context(format!("opening {:?}", path)));
+                }
+            };
+
+            for (offset, gen) in blocks {
+                match f.read_exact_at(&mut buf, *offset) {
+                    Ok(()) => (),
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                        bail!(lost(&path, (*offset, *gen), "the snapshot",
+                            "file ends before this block"));
+                    }
+                    Err(e) => {
+                        return Err(anyhow::Error::from(e)
+                            .context(format!("reading {:?}", path)));
+                    }
+                }
+
+                match integrity::check_block(&buf, fid, *offset) {
+                    BlockState::Valid { gen: found } if found >= *gen => (),
+                    BlockState::Valid { gen: found } => {
+                        bail!(lost(&path, (*offset, *gen), "the snapshot",
+                            &format!("generation {}", found)));
+                    }
+                    BlockState::Unwritten => {
+                        bail!(lost(&path, (*offset, *gen), "the snapshot",
+                            "block was never written"));
+                    }
+                    BlockState::Corrupt => {
+                        bail!(Finding::new(&path, &buf, fid, *offset));
+                    }
+                }
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+/**
+ * Describe an acknowledged write, as an (offset, generation) pair, that is
+ * missing from something taken after the acknowledgement.
+ */
+fn lost(path: &Path, (offset, gen): (u64, u64), when: &str, actual: &str)
+    -> Finding
+{
+    Finding {
+        path: path.to_path_buf(),
+        offset,
+        expected: format!("generation {} or later (acknowledged before {})",
+            gen, when),
+        actual: actual.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::testutil::{self, TempDir};
+
+    /*
+     * A live directory for the plant, and another standing in for the
+     * snapshot, which are removed at the end of the test.
+     */
+    struct Dirs {
+        _top: TempDir,
+        live: PathBuf,
+        snap: PathBuf,
+    }
+
+    fn setup(name: &str) -> (Logger, Dirs, GoldenModel) {
+        let top = TempDir::new("golden", name);
+        let dirs = Dirs {
+            live: top.path().join("live"),
+            snap: top.path().join("snap"),
+            _top: top,
+        };
+        fs::create_dir_all(&dirs.live).unwrap();
+        fs::create_dir_all(&dirs.snap).unwrap();
+        (testutil::logger(), dirs, GoldenModel::default())
+    }
+
+    /*
+     * Write one block to the live file and acknowledge it, as an I/O thread
+     * would, returning its generation.
+     */
+    fn write(model: &GoldenModel, dirs: &Dirs, name: &str, offset: u64)
+        -> u64
+    {
+        let f = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dirs.live.join(name))
+            .unwrap();
+        let mut buf = Vec::new();
+        let gen = model.file(name)
+            .write(&f, &mut buf, integrity::file_id(name), offset, 1, false)
+            .unwrap();
+        f.sync_all().unwrap();
+        model.file(name).ack(&[(offset, gen)]);
+        gen
+    }
+
+    fn snapshot(dirs: &Dirs, name: &str) {
+        fs::copy(dirs.live.join(name), dirs.snap.join(name)).unwrap();
+    }
+
+    fn finding(e: anyhow::Error) -> Finding {
+        match e.downcast::<Finding>() {
+            Ok(f) => f,
+            Err(e) => panic!("expected a finding, not {:?}", e),
+        }
+    }
+
+    #[test]
+    fn intact() {
+        let (log, dirs, model) = setup("intact");
+
+        write(&model, &dirs, "a", 0);
+        write(&model, &dirs, "a", 4096);
+        write(&model, &dirs, "b", 1024);
+        write(&model, &dirs, "a", 0);
+        snapshot(&dirs, "a");
+        snapshot(&dirs, "b");
+
+        assert_eq!(model.expect().verify(&log, &dirs.snap).unwrap(), 3);
+    }
+
+    #[test]
+    fn stale_generation() {
+        let (log, dirs, model) = setup("stale");
+
+        let old = write(&model, &dirs, "a", 1024);
+        snapshot(&dirs, "a");
+        let new = write(&model, &dirs, "a", 1024);
+
+        let f = finding(model.expect().verify(&log, &dirs.snap).unwrap_err());
+        assert_eq!(f.path, dirs.snap.join("a"));
+        assert_eq!(f.offset, 1024);
+        assert!(f.expected.contains(&format!("generation {} ", new)));
+        assert_eq!(f.actual, format!("generation {}", old));
+    }
+
+    #[test]
+    fn missing_file() {
+        let (log, dirs, model) = setup("missing");
+
+        let gen = write(&model, &dirs, "a", 2048);
+
+        let f = finding(model.expect().verify(&log, &dirs.snap).unwrap_err());
+        assert_eq!(f.path, dirs.snap.join("a"));
+        assert_eq!(f.offset, 2048);
+        assert!(f.expected.contains(&format!("generation {} ", gen)));
+        assert_eq!(f.actual, "no such file");
+
+        /*
+         * A file cut short in the snapshot has lost the blocks past its end.
+         */
+        snapshot(&dirs, "a");
+        write(&model, &dirs, "a", 8192);
+        let f = finding(model.expect().verify(&log, &dirs.snap).unwrap_err());
+        assert_eq!(f.offset, 8192);
+        assert_eq!(f.actual, "file ends before this block");
+    }
+
+    #[test]
+    fn live_read() {
+        let (_, dirs, model) = setup("live");
+        let path = dirs.live.join("a");
+
+        let gen = write(&model, &dirs, "a", 1024);
+        let fl = model.file("a");
+        let acks = fl.acked(0, 4096);
+        let check = |state| fl.check(&acks, &path, 1024, &state);
+
+        assert!(check(BlockState::Valid { gen }).is_none());
+        assert!(check(BlockState::Valid { gen: gen + 1 }).is_none());
+        assert!(check(BlockState::Corrupt).is_none());
+
+        let f = check(BlockState::Valid { gen: gen - 1 }).unwrap();
+        assert_eq!(f.actual, format!("generation {}", gen - 1));
+        let f = check(BlockState::Unwritten).unwrap();
+        assert_eq!(f.offset, 1024);
+        assert_eq!(f.actual, "block was never written");
+
+        /*
+         * Blocks never acknowledged, or acknowledged in a previous version of
+         * the file, may hold anything.
+         */
+        assert!(fl.check(&acks, &path, 0, &BlockState::Unwritten).is_none());
+        fl.replace(|| Ok(())).unwrap();
+        assert!(check(BlockState::Unwritten).is_none());
+    }
+
+    #[test]
+    fn replaced_file() {
+        let (log, dirs, model) = setup("replaced");
+
+        write(&model, &dirs, "a", 0);
+        let expect = model.expect();
+
+        /*
+         * Once the file has been replaced, we cannot say what the snapshot
+         * should contain, so it is not checked.
+         */
+        model.file("a").replace(|| Ok(())).unwrap();
+        assert_eq!(expect.verify(&log, &dirs.snap).unwrap(), 0);
+    }
+}
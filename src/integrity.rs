@@ -0,0 +1,292 @@
+/*
+ * Self-describing data blocks, so that any block we have written can be
+ * checked for damage when it is read back later.
+ *
+ * Each block begins with a header:
+ *
+ *      0..8        magic
+ *      8..16       file id (a hash of the file name)
+ *      16..24      offset of the block within the file
+ *      24..32      generation (unique to each write)
+ *      32..40      flags
+ *      40..48      checksum of everything else in the block
+ *
+ * The payload that follows is generated from the header, so that when a block
+ * is damaged we can also show what it should have contained.  All integers
+ * are little endian.
+ */
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::Serialize;
+
+pub const BLOCK_SIZE: usize = 1024;
+
+const MAGIC: &[u8; 8] = b"FoStress";
+const HEADER_SIZE: usize = 48;
+const FLAG_COMPRESSIBLE: u64 = 1;
+
+/*
+ * Generation numbers are allocated in increasing order across the whole
+ * process, so that a later write to a block always has a larger generation.
+ */
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+pub fn next_generation() -> u64 {
+    GENERATION.fetch_add(1, Ordering::SeqCst)
+}
+
+fn fnv1a(h: u64, data: &[u8]) -> u64 {
+    data.iter().fold(h, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+const FNV_BASIS: u64 = 0xcbf29ce484222325;
+
+/**
+ * Derive a stable identifier for a file from its name.  Plants are clones of
+ * seeds, so the same name may appear in many plants; that is fine, as blocks
+ * are only ever compared within one file.
+ */
+pub fn file_id<P: AsRef<Path>>(p: P) -> u64 {
+    let name = p.as_ref().file_name().map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+    fnv1a(FNV_BASIS, name.as_bytes())
+}
+
+fn get(buf: &[u8], i: usize) -> u64 {
+    let mut b = [0u8; 8];
+    b.copy_from_slice(&buf[i..i + 8]);
+    u64::from_le_bytes(b)
+}
+
+fn put(buf: &mut [u8], i: usize, v: u64) {
+    buf[i..i + 8].copy_from_slice(&v.to_le_bytes());
+}
+
+fn checksum(buf: &[u8]) -> u64 {
+    fnv1a(fnv1a(FNV_BASIS, &buf[0..40]), &buf[HEADER_SIZE..])
+}
+
+fn fill_payload(buf: &mut [u8]) {
+    if get(buf, 32) & FLAG_COMPRESSIBLE != 0 {
+        buf[HEADER_SIZE..].iter_mut().for_each(|b| *b = b'A');
+    } else {
+        let mut rng = ChaCha8Rng::seed_from_u64(fnv1a(FNV_BASIS, &buf[0..40]));
+        rng.fill(&mut buf[HEADER_SIZE..]);
+    }
+}
+
+/**
+ * Fill "buf" with the block we would write for this file, offset and
+ * generation.
+ */
+pub fn make_block(buf: &mut Vec<u8>, file_id: u64, offset: u64, gen: u64,
+    compressible: bool)
+{
+    make_blocks(buf, file_id, offset, 1, gen, compressible);
+}
+
+/**
+ * Fill "buf" with a run of consecutive blocks, starting at the given offset,
+ * which are all written with the same generation.
+ */
+pub fn make_blocks(buf: &mut Vec<u8>, file_id: u64, offset: u64, count: usize,
+    gen: u64, compressible: bool)
+{
+    buf.clear();
+    buf.resize(count * BLOCK_SIZE, 0);
+
+    for (i, block) in buf.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+        fill_block(block, file_id, offset + (i * BLOCK_SIZE) as u64, gen,
+            compressible);
+    }
+}
+
+fn fill_block(buf: &mut [u8], file_id: u64, offset: u64, gen: u64,
+    compressible: bool)
+{
+    buf[0..8].copy_from_slice(MAGIC);
+    put(buf, 8, file_id);
+    put(buf, 16, offset);
+    put(buf, 24, gen);
+    put(buf, 32, if compressible { FLAG_COMPRESSIBLE } else { 0 });
+    fill_payload(buf);
+    let sum = checksum(buf);
+    put(buf, 40, sum);
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BlockState {
+    /*
+     * The block does not carry our header, so it still contains the original
+     * seed data and cannot be checked.
+     */
+    Unwritten,
+    Valid {
+        gen: u64,
+    },
+    Corrupt,
+}
+
+/**
+ * Check a block that was read from the given file and offset.
+ */
+pub fn check_block(buf: &[u8], file_id: u64, offset: u64) -> BlockState {
+    if buf.len() != BLOCK_SIZE || &buf[0..8] != MAGIC {
+        return BlockState::Unwritten;
+    }
+
+    if get(buf, 8) != file_id || get(buf, 16) != offset
+        || get(buf, 40) != checksum(buf)
+    {
+        return BlockState::Corrupt;
+    }
+
+    BlockState::Valid {
+        gen: get(buf, 24),
+    }
+}
+
+fn hex(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/**
+ * A block that failed verification.  This is always a fatal finding: it means
+ * the filesystem has returned data that we did not write.
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct Finding {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl Finding {
+    /**
+     * Describe the damage to a block which failed "check_block()".
+     */
+    pub fn new<P: AsRef<Path>>(path: P, buf: &[u8], file_id: u64, offset: u64)
+        -> Finding
+    {
+        /*
+         * Reconstruct what the block should have contained, assuming that the
+         * generation and flags in the header are intact.
+         */
+        let mut want = Vec::with_capacity(BLOCK_SIZE);
+        make_block(&mut want, file_id, offset, get(buf, 24),
+            get(buf, 32) & FLAG_COMPRESSIBLE != 0);
+
+        let first = want.iter().zip(buf.iter()).position(|(a, b)| a != b)
+            .unwrap_or(0);
+        let start = first - first % 16;
+        let end = (start + 32).min(BLOCK_SIZE);
+
+        Finding {
+            path: path.as_ref().to_path_buf(),
+            offset,
+            expected: format!("file id {:016x} offset {} checksum {:016x}; \
+                bytes {}..{}: {}", file_id, offset, checksum(&want),
+                start, end, hex(&want[start..end])),
+            actual: format!("file id {:016x} offset {} gen {} checksum \
+                {:016x} (computed {:016x}); bytes {}..{}: {}",
+                get(buf, 8), get(buf, 16), get(buf, 24), get(buf, 40),
+                checksum(buf), start, end, hex(&buf[start..end])),
+        }
+    }
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "integrity failure in {:?} at offset {}: expected {}; \
+            actual {}", self.path, self.offset, self.expected, self.actual)
+    }
+}
+
+impl std::error::Error for Finding {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FID: u64 = 0x1234;
+    const OFFSET: u64 = 8 * BLOCK_SIZE as u64;
+
+    fn block(compressible: bool) -> Vec<u8> {
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        fill_block(&mut buf, FID, OFFSET, 42, compressible);
+        buf
+    }
+
+    #[test]
+    fn good_block() {
+        for compressible in [false, true] {
+            let buf = block(compressible);
+            assert_eq!(check_block(&buf, FID, OFFSET),
+                BlockState::Valid { gen: 42 });
+        }
+
+        let mut run = Vec::new();
+        make_blocks(&mut run, FID, OFFSET, 3, 7, false);
+        for (i, b) in run.chunks_exact(BLOCK_SIZE).enumerate() {
+            let offset = OFFSET + (i * BLOCK_SIZE) as u64;
+            assert_eq!(check_block(b, FID, offset),
+                BlockState::Valid { gen: 7 });
+        }
+    }
+
+    #[test]
+    fn misplaced_block() {
+        let buf = block(false);
+
+        assert_eq!(check_block(&buf, FID + 1, OFFSET), BlockState::Corrupt);
+        assert_eq!(check_block(&buf, FID, OFFSET + BLOCK_SIZE as u64),
+            BlockState::Corrupt);
+
+        let f = Finding::new("a", &buf, FID, 0);
+        assert!(f.expected.contains("offset 0 "));
+        assert!(f.actual.contains(&format!("offset {} gen 42 ", OFFSET)));
+    }
+
+    #[test]
+    fn bad_checksum() {
+        /*
+         * Damage to the payload, or to the checksum itself, is shown where it
+         * occurs.
+         */
+        for i in [HEADER_SIZE + 100, 44] {
+            let mut buf = block(true);
+            buf[i] ^= 0x01;
+            assert_eq!(check_block(&buf, FID, OFFSET), BlockState::Corrupt);
+
+            let f = Finding::new("a", &buf, FID, OFFSET);
+            let start = i - i % 16;
+            assert!(f.expected.contains(&format!("bytes {}..", start)));
+        }
+
+        /*
+         * Damage to the generation in the header is caught by the checksum.
+         */
+        let mut buf = block(false);
+        buf[24] ^= 0x01;
+        assert_eq!(check_block(&buf, FID, OFFSET), BlockState::Corrupt);
+        let f = Finding::new("a", &buf, FID, OFFSET);
+        assert!(f.actual.contains(" gen 43 "));
+    }
+
+    #[test]
+    fn damaged_magic() {
+        /*
+         * Without our magic, a block looks like seed data; only the golden
+         * model can say that it should have held a write.
+         */
+        let mut buf = block(false);
+        buf[0] = b'f';
+        assert_eq!(check_block(&buf, FID, OFFSET), BlockState::Unwritten);
+        assert_eq!(check_block(&vec![0u8; BLOCK_SIZE], FID, OFFSET),
+            BlockState::Unwritten);
+    }
+}
@@ -0,0 +1,289 @@
+/*
+ * Latency histograms for the operations we perform, kept separately for each
+ * plant so that a stall in one can be told apart from a stall everywhere.
+ */
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use hdrhistogram::Histogram;
+use serde::Serialize;
+use super::common::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Op {
+    Read,
+    Write,
+    Fsync,
+    Create,
+    Rename,
+    Link,
+    Unlink,
+    Rmdir,
+    List,
+    Snapshot,
+    Destroy,
+    Hold,
+    Release,
+    Send,
+}
+
+impl Op {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::Read => "read",
+            Op::Write => "write",
+            Op::Fsync => "fsync",
+            Op::Create => "create",
+            Op::Rename => "rename",
+            Op::Link => "link",
+            Op::Unlink => "unlink",
+            Op::Rmdir => "rmdir",
+            Op::List => "zfs_list",
+            Op::Snapshot => "zfs_snapshot",
+            Op::Destroy => "zfs_destroy",
+            Op::Hold => "zfs_hold",
+            Op::Release => "zfs_release",
+            Op::Send => "zfs_send",
+        }
+    }
+}
+
+/*
+ * Latencies are recorded in microseconds, up to an hour, with three
+ * significant figures.
+ */
+const MAX_MICROS: u64 = 60 * 60 * 1_000_000;
+
+fn histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, MAX_MICROS, 3).unwrap()
+}
+
+type Set = BTreeMap<Op, Histogram<u64>>;
+
+fn merge(into: &mut Set, from: &Set) {
+    for (op, h) in from {
+        into.entry(*op).or_insert_with(histogram).add(h).unwrap();
+    }
+}
+
+/**
+ * A histogram in the form that Prometheus expects: the number of samples at
+ * or below each of a set of bounds.
+ */
+pub struct Buckets {
+    pub cumulative: Vec<u64>,
+    pub count: u64,
+    pub sum_micros: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Percentiles {
+    pub count: u64,
+    pub p50_us: u64,
+    pub p99_us: u64,
+    pub p999_us: u64,
+    pub max_us: u64,
+}
+
+impl Percentiles {
+    fn of(h: &Histogram<u64>) -> Percentiles {
+        Percentiles {
+            count: h.len(),
+            p50_us: h.value_at_quantile(0.50),
+            p99_us: h.value_at_quantile(0.99),
+            p999_us: h.value_at_quantile(0.999),
+            max_us: h.max(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Sets {
+    /*
+     * Samples recorded since the last periodic report...
+     */
+    interval: Set,
+    /*
+     * ... and those from before it.
+     */
+    total: Set,
+}
+
+/**
+ * The histograms for one plant (or for activity not tied to a plant).
+ */
+#[derive(Default)]
+pub struct Histograms {
+    sets: Mutex<Sets>,
+}
+
+impl Histograms {
+    pub fn record(&self, op: Op, d: Duration) {
+        let mut sets = self.sets.lock().unwrap();
+        sets.interval.entry(op).or_insert_with(histogram)
+            .saturating_record(d.as_micros().max(1) as u64);
+    }
+
+    /**
+     * Run "func", recording how long it took.
+     */
+    pub fn time<T, F: FnOnce() -> T>(&self, op: Op, func: F) -> T {
+        let start = Instant::now();
+        let res = func();
+        self.record(op, start.elapsed());
+        res
+    }
+
+    /**
+     * Fold the samples from the current interval into the totals, returning
+     * them.
+     */
+    fn end_interval(&self) -> Set {
+        let mut sets = self.sets.lock().unwrap();
+        let interval = std::mem::take(&mut sets.interval);
+        merge(&mut sets.total, &interval);
+        interval
+    }
+
+    fn total(&self) -> Set {
+        self.sets.lock().unwrap().total.clone()
+    }
+
+    /**
+     * All samples so far, including those from the current interval.
+     */
+    fn current(&self) -> Set {
+        let sets = self.sets.lock().unwrap();
+        let mut out = sets.total.clone();
+        merge(&mut out, &sets.interval);
+        out
+    }
+
+    fn buckets(&self, bounds: &[u64]) -> BTreeMap<Op, Buckets> {
+        let sets = self.sets.lock().unwrap();
+        let mut out = BTreeMap::new();
+
+        for set in &[&sets.total, &sets.interval] {
+            for (op, h) in set.iter() {
+                let b = out.entry(*op).or_insert_with(|| Buckets {
+                    cumulative: vec![0; bounds.len()],
+                    count: 0,
+                    sum_micros: 0.0,
+                });
+                for (i, bound) in bounds.iter().enumerate() {
+                    b.cumulative[i] += h.count_between(0, *bound);
+                }
+                b.count += h.len();
+                b.sum_micros += h.mean() * h.len() as f64;
+            }
+        }
+
+        out
+    }
+}
+
+fn report(log: &Logger, what: &str, set: &Set) {
+    for (op, h) in set {
+        if h.is_empty() {
+            continue;
+        }
+        let p = Percentiles::of(h);
+        info!(log, "{} latency", what;
+            "op" => op.name(),
+            "count" => p.count,
+            "p50_us" => p.p50_us,
+            "p99_us" => p.p99_us,
+            "p999_us" => p.p999_us,
+            "max_us" => p.max_us);
+    }
+}
+
+/**
+ * Percentiles by operation name, for each plant and across all of them.
+ */
+pub type PercentileSet = BTreeMap<&'static str, Percentiles>;
+
+#[derive(Default)]
+pub struct Latency {
+    plants: Mutex<BTreeMap<String, Arc<Histograms>>>,
+}
+
+impl Latency {
+    /**
+     * Get the histograms for a particular plant, by dataset name.
+     */
+    pub fn plant(&self, dataset: &str) -> Arc<Histograms> {
+        let mut plants = self.plants.lock().unwrap();
+        Arc::clone(plants.entry(dataset.to_string()).or_default())
+    }
+
+    fn all(&self) -> Vec<(String, Arc<Histograms>)> {
+        self.plants.lock().unwrap().iter()
+            .map(|(n, h)| (n.to_string(), Arc::clone(h)))
+            .collect()
+    }
+
+    /**
+     * Summarise the latencies seen so far in each plant, with the given
+     * bucket bounds (in microseconds).
+     */
+    pub fn buckets(&self, bounds: &[u64]) -> Vec<(String, Op, Buckets)> {
+        let mut out = Vec::new();
+        for (name, h) in self.all() {
+            for (op, b) in h.buckets(bounds) {
+                out.push((name.to_string(), op, b));
+            }
+        }
+        out
+    }
+
+    /**
+     * Compute percentiles for everything recorded so far: for each plant, and
+     * across all of them.
+     */
+    pub fn percentiles(&self) -> (BTreeMap<String, PercentileSet>,
+        PercentileSet)
+    {
+        let pct = |set: &Set| set.iter()
+            .filter(|(_, h)| !h.is_empty())
+            .map(|(op, h)| (op.name(), Percentiles::of(h)))
+            .collect::<PercentileSet>();
+
+        let mut plants = BTreeMap::new();
+        let mut sum = Set::new();
+        for (name, h) in self.all() {
+            let current = h.current();
+            plants.insert(name, pct(&current));
+            merge(&mut sum, &current);
+        }
+        (plants, pct(&sum))
+    }
+
+    /**
+     * Report the latencies seen across all plants since the last periodic
+     * report.
+     */
+    pub fn report_interval(&self, log: &Logger) {
+        let mut sum = Set::new();
+        for (_, h) in self.all() {
+            merge(&mut sum, &h.end_interval());
+        }
+        report(log, "interval", &sum);
+    }
+
+    /**
+     * Report the latencies seen over the whole run, both for each plant and
+     * across all of them.
+     */
+    pub fn report_total(&self, log: &Logger) {
+        let mut sum = Set::new();
+        for (name, h) in self.all() {
+            h.end_interval();
+            let total = h.total();
+            report(&log.new(o!("plant" => name)), "plant", &total);
+            merge(&mut sum, &total);
+        }
+        report(log, "overall", &sum);
+    }
+}
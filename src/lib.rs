@@ -0,0 +1,239 @@
+/*!
+ * The festival of stress: an engine which fills seed datasets with files,
+ * clones them into plants, runs I/O threads in each plant, and backs the
+ * plants up with snapshots and sends while checking that every acknowledged
+ * write survives.  The "stress" program is a command line front end over
+ * this library; other programs (e.g., integration test harnesses) may drive
+ * the same engine with a "Config" of their own.
+ */
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub mod common;
+use common::*;
+
+pub mod zfs;
+use zfs::*;
+
+pub mod sim;
+use sim::SimZfs;
+
+pub mod tools;
+
+pub mod perms;
+
+pub mod dryrun;
+use dryrun::DryRun;
+
+pub mod dir;
+use dir::DirBackend;
+
+pub mod btrfs;
+use btrfs::BtrfsBackend;
+
+pub mod config;
+use config::*;
+
+mod rng;
+use rng::Stream;
+
+pub mod integrity;
+use integrity::Finding;
+
+pub mod golden;
+
+pub mod stats;
+
+pub mod control;
+use control::Control;
+
+pub mod latency;
+
+pub mod metrics;
+
+pub mod report;
+
+pub mod seed;
+pub use seed::Seed;
+
+pub mod workload;
+pub use workload::{Worker, Workload};
+
+pub mod metadata;
+
+pub mod plant;
+pub use plant::{setup_plants, Plant};
+use plant::fatal_finding;
+
+pub mod backup;
+pub use backup::backup;
+
+#[cfg(test)]
+mod testutil;
+
+/*
+ * How long to wait for worker threads to finish their current operation once
+ * they have been asked to stop.
+ */
+pub const JOIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/**
+ * Open the backend chosen in the configuration.  If "sim" is given, seeds
+ * and plants are simulated, with mountpoints in that directory.  A dry run
+ * logs the commands it would run rather than running them, planning against
+ * an empty simulated pool if "sim" is given, and against the current state
+ * of the real pool otherwise.
+ */
+pub fn open_backend(log: &Logger, cfg: &Config, guard: Guard,
+    sim: Option<&Path>, dry_run: bool)
+    -> Result<Arc<dyn ZfsBackend>>
+{
+    let tools = cfg.tools.clone();
+
+    if cfg.backend != Backend::Zfs && (dry_run || sim.is_some()) {
+        bail!("--dry-run and --sim are only supported with the zfs backend");
+    }
+
+    Ok(match (sim, dry_run) {
+        _ if cfg.backend == Backend::Dir => {
+            Arc::new(DirBackend::new(log, cfg.root.as_ref().unwrap(),
+                &cfg.pool, guard)?)
+        }
+        _ if cfg.backend == Backend::Btrfs => {
+            Arc::new(BtrfsBackend::new(log, tools,
+                cfg.root.as_ref().unwrap(), &cfg.pool, guard)?)
+        }
+        (Some(root), false) => {
+            info!(log, "using simulated pool in {:?}", root);
+            Arc::new(SimZfs::new(&cfg.pool, Some(root.to_path_buf()))?
+                .backend(tools, guard))
+        }
+        (Some(_), true) => {
+            info!(log, "dry run: planning against an empty simulated pool");
+            let sim = SimZfs::new(&cfg.pool, None)?;
+            Arc::new(DryRun::new(log, &tools, sim).backend(tools, guard))
+        }
+        (None, false) => Arc::new(CliZfs::new(tools, guard)),
+        (None, true) => {
+            info!(log, "dry run: planning against the current state of {}",
+                cfg.pool);
+            let real = CliZfs::new(tools.clone(), guard.clone());
+            let sim = SimZfs::import(&cfg.pool,
+                &real.inventory(log, &cfg.pool)?)?;
+            Arc::new(DryRun::new(log, &tools, sim).backend(tools, guard))
+        }
+    })
+}
+
+/**
+ * Set up the seeds and plants, and run the I/O threads in every plant for the
+ * given duration (which does not include the time spent in setup), or until
+ * we are asked to stop.  If "backup" is set, the calling thread performs
+ * backup activity according to the backup policy while the I/O threads run.
+ */
+pub fn run_plants(log: &Logger, zfs: &Arc<dyn ZfsBackend>, cfg: &Arc<Config>,
+    ctl: &Arc<Control>, duration: Option<Duration>, backup: bool)
+    -> Result<()>
+{
+    let plants = setup_plants(log, zfs, cfg)?;
+
+    let workloads = plants.iter()
+        .map(|p| workload::create(cfg.workload_for(p.id()),
+            &mut rng::stream(cfg.master_seed, Stream::PlantWorkload(p.id()))))
+        .collect::<Result<Vec<_>>>()?;
+
+    /*
+     * Start all the I/O threads, unless the plants exist only in the plan
+     * for a dry run:
+     */
+    let threads = plants.iter().zip(&workloads)
+        .filter(|_| !zfs.dry_run())
+        .flat_map(|(p, wl)| p.start(cfg, ctl, wl))
+        .collect::<Vec<_>>();
+    let deadline = deadline(duration);
+
+    let res = if backup {
+        /*
+         * Perform backup activity on this thread while the I/O threads run.
+         */
+        let models = plants.iter()
+            .filter(|_| !zfs.dry_run())
+            .map(|p| (p.dataset().to_string(), Arc::clone(p.model())))
+            .collect();
+        self::backup(log, zfs, &cfg.pool, &cfg.backup, ctl, deadline, models)
+    } else {
+        while ctl.sleep_until(deadline, 1_000) { }
+        Ok(())
+    };
+
+    stopping(log, ctl, deadline);
+    let stuck = control::join_all(log, threads, JOIN_TIMEOUT);
+
+    /*
+     * Workloads which keep a record of what their plant should contain can
+     * check it, unless some thread may still be changing it.
+     */
+    if stuck == 0 && !zfs.dry_run() {
+        for (p, wl) in plants.iter().zip(&workloads) {
+            if let Err(e) = wl.finish(log, p.mountpoint()) {
+                match e.downcast_ref::<Finding>() {
+                    Some(f) => fatal_finding(log, ctl, f),
+                    None => {
+                        error!(log, "checking {}: {:?}", p.dataset(), e);
+                        ctl.stats.error(&e);
+                    }
+                }
+            }
+        }
+    }
+
+    if !cfg.cleanup {
+        res
+    } else if !ctl.stats.findings().is_empty() || stuck > 0 {
+        warn!(log, "leaving plants in place for inspection");
+        res
+    } else {
+        info!(log, "destroying plants");
+        res.and(zfs.destroy(log, &format!("{}/plant", cfg.pool), true)
+            .map_err(|e| e.into()))
+    }
+}
+
+/**
+ * Perform backup activity against plants whose I/O threads are running
+ * elsewhere (e.g., in another process), for the given duration or until we
+ * are asked to stop.
+ */
+pub fn run_backup(log: &Logger, zfs: &Arc<dyn ZfsBackend>, cfg: &Config,
+    ctl: &Arc<Control>, duration: Option<Duration>)
+    -> Result<()>
+{
+    let deadline = deadline(duration);
+    let res = backup(log, zfs, &cfg.pool, &cfg.backup, ctl, deadline,
+        HashMap::new());
+    stopping(log, ctl, deadline);
+    res
+}
+
+/**
+ * When a run of this duration, starting now, should stop.  A duration too long
+ * for the clock to represent is as good as no limit at all.
+ */
+fn deadline(duration: Option<Duration>) -> Option<Instant> {
+    duration.and_then(|d| Instant::now().checked_add(d))
+}
+
+/**
+ * Report why we are stopping, and make sure every thread knows to stop.
+ */
+fn stopping(log: &Logger, ctl: &Control, deadline: Option<Instant>) {
+    if ctl.stopping() {
+        info!(log, "stop requested");
+    } else if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+        info!(log, "run duration elapsed");
+    }
+    ctl.stop();
+}
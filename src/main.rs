@@ -1,508 +1,363 @@
-#![feature(command_access)]
-
 use anyhow::{anyhow, Result};
-use std::process::Command;
 use std::thread;
 use std::path::{PathBuf, Path};
-use std::fs;
-use std::io;
-use std::io::{Read, Write, Seek};
-use rand::prelude::*;
-use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
-use std::os::unix::io::AsRawFd;
-
-mod common;
-use common::*;
-
-mod zfs;
-use zfs::*;
-
-/*
- * Produce a "seed" dataset.  This will be filled with a set of random files,
- * and a snapshot will be taken.  This snapshot will be used to create many
- * clones in which various concurrent operations will occur.
+use std::sync::Arc;
+use std::time::Duration;
+
+use festival::common::*;
+use festival::config::*;
+use festival::control::Control;
+use festival::metrics;
+use festival::perms;
+use festival::report::Reporter;
+use festival::tools::Privilege;
+use festival::zfs::{CliZfs, Guard};
+
+/**
+ * Options which choose the workload run in the plants, for the commands
+ * which run I/O threads.
  */
-struct Seed {
-    log: Logger,
-    id: u64,
-    dataset: String,
+fn workload_opts(opts: &mut getopts::Options) {
+    let names = festival::workload::names()
+        .map(|n| format!("\"{}\"", n))
+        .collect::<Vec<_>>().join(", ");
+    opts.optopt("", "workload", &format!("workload to run in every plant \
+        ({}), with any parameters (write_ratio, compressible, fsync_ratio, \
+        max_ops, block_size) to change, e.g. \"mixed:write_ratio=0.9\"",
+        names), "NAME[:PARAM=VALUE,...]");
+    opts.optmulti("", "plant-workload", "workload to run in particular \
+        plants, e.g. \"0-3=sequential:block_size=65536\"; parameters not \
+        given are taken from --workload", "PLANTS=NAME[:PARAM=VALUE,...]");
 }
 
-const KILOBYTE: u64 = 1024;
-const MEGABYTE: u64 = KILOBYTE * 1024;
-
-const SEED_FILE_COUNT: usize = 1_000;
-const FILE_MIN: u64 = 2; /* MB */
-const FILE_MAX: u64 = 32; /* MB */
-
-const PLANT_COUNT: u64 = 60;
-
-fn chown_to_me<P: AsRef<Path>>(p: P) -> Result<()> {
-    /*
-     * Fix permissions so we can write to the directory.
-     */
-    Command::new("/bin/pfexec")
-        .env_clear()
-        .arg("/bin/chown")
-        .arg("-R")
-        .arg("jclulow")
-        .arg(p.as_ref())
-        .output()?;
-    Command::new("/bin/pfexec")
-        .env_clear()
-        .arg("/bin/chmod")
-        .arg("-R")
-        .arg("u+rwx")
-        .arg(p.as_ref())
-        .output()?;
-    Ok(())
+fn usage(cmd: &str, opts: &getopts::Options) -> String {
+    opts.usage(&format!("Usage: stress {} [OPTIONS]", cmd))
 }
 
-impl Seed {
-    fn setup(log: Logger, pool: &str, id: u64) -> Result<Seed> {
-        let root = format!("{}/seed", pool);
-        zfs_create(&log, &root, true)?;
-
-        let dataset = format!("{}/{:<04}", root, id);
-
-        if !zfs_snapshot_exists(&log, &dataset, "final")? {
-            /*
-             * A previous setup run did not complete.  Destroy and recreate
-             * the entire thing.
-             */
-            zfs_destroy(&log, &dataset, true)?;
-            zfs_create(&log, &dataset, false)?;
-
-            let mountpoint = PathBuf::from(zfs_get(&log, &dataset, 
-                "mountpoint")?);
-            chown_to_me(&mountpoint)?;
-
-            /*
-             * Create a fan-out directory structure full of files of random
-             * size.
-             */
-            let mut rng = rand_chacha::ChaCha20Rng::from_entropy();
-
-            for _ in 0..SEED_FILE_COUNT {
-                let l0 = rng.gen_range::<u64, _>(0..16);
-                let l1 = rng.gen_range::<u64, _>(0..16);
-                let l2 = rng.gen::<u64>();
-
-                let mut fp = mountpoint.clone();
-                fp.push(format!("{:<04X}", l0));
-                fp.push(format!("{:<04X}", l1));
-                std::fs::create_dir_all(&fp)?;
-                fp.push(format!("{:<016X}.dat", l2));
-
-                let sz_mb = rng.gen_range::<u64, _>(FILE_MIN..=FILE_MAX);
-
-                let f = fs::OpenOptions::new()
-                    .write(true)
-                    .truncate(true)
-                    .create(true)
-                    .open(&fp)?;
-                let mut bw = io::BufWriter::new(f);
-
-                /*
-                 * Create a file with random data:
-                 */
-                let mut buf = Vec::with_capacity(8192);
-                for _ in 0..(sz_mb * 64) {
-                    buf.clear();
-
-                    /*
-                     * Generate mostly random data, with some compressible data:
-                     */
-                    let random = rng.gen_bool(0.75);
-
-                    while buf.len() < (16 * KILOBYTE) as usize {
-                        if random {
-                            buf.push(rng.gen::<u8>());
-                        } else {
-                            buf.push(b'A');
-                        }
-                    }
-
-                    bw.write(&buf)?;
-                }
-
-                bw.flush()?;
-            }
+fn main() -> Result<()> {
+    let args = std::env::args().collect::<Vec<_>>();
+    let cmd = args.get(1)
+        .ok_or_else(|| anyhow!("usage: stress io|backup|run|setup-perms \
+            [OPTIONS]"))?
+        .to_string();
+
+    let mut opts = getopts::Options::new();
+    opts.optflag("h", "help", "print this usage information");
+    opts.optopt("d", "duration", "run for this long (e.g., \"90m\"), then \
+        exit", "DURATION");
+    opts.optopt("", "sim", "use a simulated pool, with mountpoints in DIR",
+        "DIR");
+    opts.optopt("", "backend", "build seeds and plants from \"zfs\" \
+        datasets, plain directories (\"dir\") or \"btrfs\" subvolumes",
+        "BACKEND");
+    opts.optopt("", "root", "directory which stands in for the pool, for \
+        backends other than zfs", "DIR");
+    opts.optopt("", "seed", "master seed from which all random choices are \
+        derived, to replay a previous run", "SEED");
+    opts.optopt("", "report-interval", "time between reports of operation \
+        latency", "DURATION");
+    opts.optopt("", "metrics", "serve statistics for Prometheus at \
+        http://ADDR/metrics", "ADDR");
+    opts.optopt("", "report", "write a JSON report of the run to FILE",
+        "FILE");
+    opts.optopt("", "log-format", "log as \"compact\" or \"full\" text, or \
+        as \"json\" (one object per line)", "FORMAT");
+    opts.optopt("", "log-file", "append log messages to FILE rather than \
+        writing them to stdout", "FILE");
+    opts.optopt("", "log-level", "minimum level to log, overall and for \
+        subsystems zfs, seed, workload and backup (e.g., \
+        \"warn,zfs=debug\"); overrides $STRESS_LOG", "SPEC");
+    opts.optflag("n", "dry-run", "log the changes that would be made to the \
+        pool, through one backup cycle, without making them");
+    opts.optflag("", "destroy-unmarked", "allow the recursive destroy of \
+        datasets which were not marked as ours when created");
+    opts.optopt("", "privilege", "how to run privileged commands: \"none\", \
+        \"delegated\" (see setup-perms), \"sudo\", \"doas\", \"pfexec\", or \
+        the path of a wrapper program", "STRATEGY");
+    opts.optopt("", "owner", "user who should own the files in seeds and \
+        plants (default: the invoking user)", "USER[:GROUP]");
+    opts.optmulti("", "tool", "path of a program we run (zfs, zpool, \
+        chown, chmod, bash or btrfs), e.g. \"zfs=/usr/local/sbin/zfs\"",
+        "NAME=PATH");
 
-            /*
-             * Take the "final" snapshot that we will use to create clones.
-             */
-            zfs_snapshot(&log, &dataset, "final", false)?;
-        } else {
-            info!(&log, "seed {} already setup", id);
+    match cmd.as_str() {
+        "io" => {
+            opts.optopt("p", "pool", "pool in which to create seeds and \
+                plants", "POOL");
+            opts.optopt("", "seeds", "number of seed datasets to create",
+                "COUNT");
+            opts.optopt("", "plants", "number of plants to clone from the \
+                seeds", "COUNT");
+            opts.optopt("t", "threads", "number of I/O threads per plant",
+                "COUNT");
+            opts.optopt("", "origin", "clone plants from a \"random\" seed, \
+                each seed in turn (\"round-robin\"), or an existing \
+                snapshot", "ORIGIN");
+            opts.optflag("", "cleanup", "destroy the plants on exit");
+            workload_opts(&mut opts);
+        }
+        "backup" => {
+            opts.optopt("p", "pool", "pool in which the plants live", "POOL");
+            opts.optopt("t", "threads", "number of backup threads", "COUNT");
+            opts.optopt("", "max-snaps", "number of snapshots to keep for \
+                each plant", "COUNT");
+            opts.optopt("i", "interval", "time between backup cycles",
+                "DURATION");
+        }
+        "run" => {
+            opts.optflag("", "cleanup", "destroy the plants on exit");
+            workload_opts(&mut opts);
+        }
+        "setup-perms" => {
+            opts.optopt("p", "pool", "pool on which to grant permissions",
+                "POOL");
+        }
+        "-h" | "--help" | "help" => {
+            println!("usage: stress io|backup|setup-perms [OPTIONS]");
+            println!("       stress run [OPTIONS] SCENARIO.toml");
+            return Ok(());
+        }
+        n => {
+            bail!("unknown command {}", n);
         }
-
-        Ok(Seed {
-            log,
-            id,
-            dataset,
-        })
-    }
-
-    fn dataset(&self) -> &str {
-        &self.dataset
     }
-}
-
-struct Plant {
-    log: Logger,
-    id: u64,
-    parent: String,
-    dataset: String,
-    mountpoint: PathBuf,
-}
-
-fn file_futz<P: AsRef<Path>, T: rand::Rng>(p: P, rng: &mut T,
-    buf: &mut Vec<u8>)
-    -> Result<()>
-{
-    let mut f = fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(false)
-        .open(p.as_ref())?;
-
-    let sz = f.metadata()?.len();
-
-    if sz < 2048 {
-        /*
-         * Small file found.  Pad it out to meet our expectations.
-         */
-        let sz_mb = rng.gen_range::<u64, _>(FILE_MIN..=FILE_MAX);
-
-        let f = fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(p.as_ref())?;
-        let mut bw = io::BufWriter::new(f);
-
-        /*
-         * Create a file with random data:
-         */
-        let mut buf = Vec::with_capacity(8192);
-        for _ in 0..(sz_mb * 64) {
-            buf.clear();
 
-            /*
-             * Generate mostly random data, with some compressible data:
-             */
-            let random = rng.gen_bool(0.75);
-
-            while buf.len() < (16 * KILOBYTE) as usize {
-                if random {
-                    buf.push(rng.gen::<u8>());
-                } else {
-                    buf.push(b'A');
-                }
-            }
-
-            bw.write(&buf)?;
+    let usage = |opts: &getopts::Options| {
+        if cmd == "run" {
+            opts.usage("Usage: stress run [OPTIONS] SCENARIO.toml")
+        } else {
+            usage(&cmd, opts)
         }
-
+    };
+
+    let mat = match opts.parse(&args[2..]) {
+        Ok(mat) => mat,
+        Err(e) => bail!("{}\n{}", e, usage(&opts)),
+    };
+    if mat.opt_present("help") {
+        println!("{}", usage(&opts));
         return Ok(());
     }
 
+    let mut cfg = if cmd == "run" {
+        if mat.free.len() != 1 {
+            bail!("specify one scenario file\n{}", usage(&opts));
+        }
+        Config::load(&mat.free[0])?
+    } else {
+        if !mat.free.is_empty() {
+            bail!("unexpected arguments: {:?}\n{}", mat.free, usage(&opts));
+        }
+        Config::default()
+    };
+
     /*
-     * Determine how many operations we will perform on this
-     * file.
+     * Not every option is defined for every command:
      */
-    let iops = rng.gen_range(1..10_000);
+    let opt = |n: &str| {
+        if mat.opt_defined(n) {
+            mat.opt_str(n)
+        } else {
+            None
+        }
+    };
 
-    buf.clear();
-    while buf.len() < (1 * KILOBYTE) as usize {
-        buf.push(b'\0');
+    if let Some(pool) = opt("pool") {
+        cfg.pool = pool;
     }
-
-    for _ in 0..iops {
-        /*
-         * Are we looking to read or write?
-         */
-        let write = rng.gen_bool(0.40);
-
-        let target = rng.gen_range(0..(sz / 1024 - 1));
-        f.seek(io::SeekFrom::Start(target))?;
-
-        if write {
-            let random = rng.gen_bool(0.75);
-
-            buf.clear();
-            while buf.len() < (1 * KILOBYTE) as usize {
-                if random {
-                    buf.push(rng.gen::<u8>());
-                } else {
-                    buf.push(b'A');
-                }
-            }
-
-            f.write_all(buf)?;
-            f.flush()?;
-
-            if rng.gen_bool(0.30) {
-                unsafe { libc::fsync(f.as_raw_fd()) }; /* XXX */
-            }
-
-        } else {
-            f.read_exact(buf)?;
+    if let Some(b) = opt("backend") {
+        cfg.backend = b.parse()?;
+    }
+    if let Some(r) = opt("root") {
+        cfg.root = Some(PathBuf::from(r));
+    }
+    if let Some(d) = opt("duration") {
+        cfg.duration = Some(parse_duration(&d)?);
+    }
+    if let Some(n) = opt("seeds") {
+        cfg.seeds = parse_count("seed count", &n)?;
+    }
+    if let Some(n) = opt("plants") {
+        cfg.plants = parse_count("plant count", &n)?;
+    }
+    if let Some(o) = opt("origin") {
+        cfg.origin = o.parse()?;
+    }
+    if let Some(n) = opt("threads") {
+        match cmd.as_str() {
+            "io" => cfg.plant_threads = parse_count("thread count", &n)?,
+            _ => cfg.backup.threads = parse_count("thread count", &n)?,
         }
     }
-
-    Ok(())
-}
-
-impl Plant {
-    fn setup(log: Logger, pool: &str, id: u64, parent: &str) -> Result<Plant> {
-        /*
-         * Start with a clean slate.
-         */
-        let dataset = format!("{}/plant/{:<04}", pool, id);
-        zfs_destroy(&log, &dataset, true)?;
-
-        /*
-         * Clone the seed:
-         */
-        zfs_clone(&log, parent, /* XXX "final" */ "20130419223617", &dataset)?;
-
-        let mountpoint = PathBuf::from(zfs_get(&log, &dataset, "mountpoint")?);
-        chown_to_me(&mountpoint)?;
-
-        let plant = Plant {
-            log,
-            id,
-            parent: parent.to_string(),
-            mountpoint,
-            dataset,
-        };
-
-        plant.start(4).ok(); /* XXX */
-        Ok(plant)
+    if let Some(n) = opt("max-snaps") {
+        cfg.backup.max_snaps = parse_count("snapshot count", &n)? as usize;
     }
-
-    fn start(&self, nthreads: u64) -> Result<()> {
-        /*
-         * Create I/O threads to act within this plant.
-         */
-        for _ in 0..nthreads {
-            let log = self.log.clone();
-            let mp = self.mountpoint.clone();
-            thread::spawn(move || {
-                let mut rng = rand_chacha::ChaCha20Rng::from_entropy();
-                let mut buf = Vec::with_capacity((1 * KILOBYTE) as usize);
-
-                loop {
-                    /*
-                     * List all files in the plant at this time.
-                     */
-                    let mut files = Vec::new();
-                    let walk = walkdir::WalkDir::new(&mp);
-                    for ent in walk.into_iter() {
-                        match ent {
-                            Ok(ent) => {
-                                if !ent.file_type().is_file() {
-                                    continue;
-                                }
-                                files.push(ent.path().to_path_buf());
-                            }
-                            Err(e) => {
-                                error!(&log, "walk failure: {:?}", e);
-                                continue;
-                            }
-                        }
-                    }
-
-                    /*
-                     * Shuffle the deck.
-                     */
-                    let mut neworder = VecDeque::new();
-                    for i in 0..files.len() {
-                        neworder.push_back(i);
-                    }
-
-                    if !neworder.is_empty() {
-                        let mut i = neworder.len() - 1;
-                        while i >= 1 {
-                            let j = rng.gen_range(0..i);
-                            neworder.swap(i, j);
-                            i -= 1;
-                        }
-                    }
-
-                    while let Some(i) = neworder.pop_front() {
-                        if let Err(e) = file_futz(&files[i], &mut rng,
-                            &mut buf)
-                        {
-                            error!(&log, "file futz error: {:?}", e);
-                        }
-                    }
-                }
-            });
+    if let Some(d) = opt("interval") {
+        cfg.backup.interval = parse_duration(&d)?;
+    }
+    if let Some(w) = opt("workload") {
+        cfg.workload.apply(&w)?;
+    }
+    if mat.opt_defined("plant-workload") {
+        for w in mat.opt_strs("plant-workload") {
+            cfg.apply_plant_workload(&w)?;
         }
-
-        Ok(())
     }
-
-
-    fn dataset(&self) -> &str {
-        &self.dataset
+    if mat.opt_defined("cleanup") && mat.opt_present("cleanup") {
+        cfg.cleanup = true;
     }
-}
-
-#[allow(dead_code)]
-fn jobs() -> Result<usize> {
-    let out = Command::new("/usr/sbin/psrinfo")
-        .env_clear()
-        .arg("-t")
-        .output()?;
-
-    if !out.status.success() {
-        bail!("{}", out.info());
+    if mat.opt_present("destroy-unmarked") {
+        cfg.destroy_unmarked = true;
     }
-
-    Ok(String::from_utf8(out.stdout)?.trim().parse()?)
-}
-
-fn main() -> Result<()> {
-    let cmd = std::env::args().nth(1).ok_or(anyhow!("no argument?"))?;
-
-    let log = init_log();
-
-    info!(log, "stress: {}", cmd);
-
-    match cmd.as_str() {
-        "io" => {
-            /*
-             * Prepare seed datasets:
-             */
-            let seeds = (0..4u64).map(|id| {
-                let log = log.new(o! { "seed" => id });
-
-                info!(log, "creating seed {}", id);
-
-                Seed::setup(log.clone(), "dynamite", id)
-            }).collect::<Result<Vec<_>>>()?;
-
-            /*
-             * Destroy all previous plants:
-             */
-            zfs_destroy(&log, "dynamite/plant", true)?;
-            zfs_create(&log, "dynamite/plant", false)?;
-
-            /*
-             * Establish plants, each from a random seed:
-             */
-            let mut rng = rand_chacha::ChaCha20Rng::from_entropy();
-            let plants = (0..PLANT_COUNT).map(|id| {
-                let log = log.new(o! { "plant" => id });
-
-                let seed = "dynamite/joyent/base64-13.1.0";
-                // let si = rng.gen_range(0..seeds.len());
-                //let seed = seeds[si].dataset().to_string();
-                info!(log, "creating plant {} from {}", id, seed);
-
-                Plant::setup(log.clone(), "dynamite", id, &seed)
-            }).collect::<Result<Vec<_>>>()?;
-
-            /*
-             * Start all the I/O threads:
-             */
-            //for p in &plants {
-            //    p.start(4)?;
-            //}
-
-            loop {
-                /*
-                 * XXX Could join threads.
-                 */
-                sleep(1_000_000);
-            }
+    if let Some(d) = opt("report-interval") {
+        cfg.report_interval = parse_duration(&d)?;
+    }
+    if let Some(r) = opt("report") {
+        cfg.report = Some(PathBuf::from(r));
+    }
+    if let Some(m) = opt("metrics") {
+        cfg.metrics = Some(parse_addr(&m)?);
+    }
+    if let Some(f) = opt("log-format") {
+        cfg.log.format = Some(f.parse()?);
+    }
+    if let Some(f) = opt("log-file") {
+        cfg.log.file = Some(PathBuf::from(f));
+    }
+    if let Some(l) = opt("log-level").or_else(|| {
+        std::env::var("STRESS_LOG").ok()
+    }) {
+        cfg.log.level = Some(l);
+    }
+    if let Some(p) = opt("privilege") {
+        cfg.tools.set_privilege(p.parse()?);
+    }
+    if let Some(o) = opt("owner") {
+        cfg.tools.owner = o.parse()?;
+    }
+    for t in mat.opt_strs("tool") {
+        match t.split_once('=') {
+            Some((name, path)) => cfg.tools.set(name, Path::new(path))?,
+            None => bail!("invalid tool {:?} (expected NAME=PATH)", t),
         }
-        "backup" => {
-            /*
-             * Use the main thread to perform periodic "backup" activity.  For
-             * each plant, we want to:
-             *      - take a new snapshot
-             *      - delete the oldest snapshot until there are only N
-             *        snapshots left
-             *      - if there are at least two snapshots, do an incremental
-             *        zfs send of the current snapshot using the second most
-             *        recent snapshot as the comparison base
-             */
-            let maxsnaps = 6;
-            loop {
-                let snapnum = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-
-                let datasets = Arc::new(Mutex::new(
-                    zfs_dataset_children(&log, "dynamite/plant")?));
-
-                //for ds in zfs_dataset_children(&log, "dynamite/plant")? {
-                //    //zfs_send_to_null(&log, &ds, &sold, &snew)?;
-                //}
+    }
+    if let Some(ms) = opt("seed") {
+        cfg.master_seed = ms.parse()
+            .map_err(|_| anyhow!("invalid master seed {:?}", ms))?;
+        cfg.master_seed_given = true;
+    }
+    cfg.validate()?;
+    let cfg = Arc::new(cfg);
 
-                let mut threads = Vec::<thread::JoinHandle<Result<()>>>::new();
-                for _ in 0..8 {
-                    let log = log.clone();
-                    let datasets = Arc::clone(&datasets);
-                    let snapname = format!("backup-{}", snapnum);
+    let log = init_log(&cfg.log)?;
 
-                    threads.push(thread::spawn(move || {
-                        loop {
-                            let ds = {
-                                let mut datasets = datasets.lock().unwrap();
-                                if let Some(x) = datasets.pop() {
-                                    x
-                                } else {
-                                    return Ok(());
-                                }
-                            };
+    info!(log, "stress: {}", cmd; "pool" => &cfg.pool);
+    info!(log, "master seed {} (use \"--seed {}\" to replay this run)",
+        cfg.master_seed, cfg.master_seed);
+    info!(log, "privilege strategy: {}", cfg.tools.privilege;
+        "zfs" => cfg.tools.zfs.display().to_string(),
+        "owner" => format!("{}:{}", cfg.tools.owner.uid, cfg.tools.owner.gid));
 
-                            /*
-                             * Age out old snapshots.
-                             */
-                            let snaps = loop {
-                                let snaps = zfs_snapshot_list(&log, &ds)?;
+    /*
+     * A dry run plans a single backup cycle, and does not wait around.
+     */
+    let dry_run = mat.opt_present("dry-run");
+    let duration = if dry_run {
+        Some(Duration::ZERO)
+    } else {
+        cfg.duration
+    };
+
+    let ctl = Control::new();
+    ctl.handle_signals()?;
+
+    if let Some(addr) = cfg.metrics {
+        metrics::start(&log, &ctl, addr)?;
+    }
 
-                                if snaps.len() < maxsnaps {
-                                    break snaps;
-                                }
+    /*
+     * Mark the datasets we create with an identifier for this run.
+     */
+    let guard = Guard::new(cfg.destroy_unmarked);
+    info!(log, "run id {}", guard.run_id);
 
-                                zfs_destroy_snapshot(&log, &ds, &snaps[0])?;
-                            };
+    if cfg.backend != Backend::Zfs && cmd == "setup-perms" {
+        bail!("permissions can only be delegated with the zfs backend");
+    }
 
-                            /*
-                             * Take snapshot.
-                             */
-                            zfs_snapshot(&log, &ds, &snapname, false)?;
+    if cfg.tools.privilege == Privilege::Delegated && cmd != "setup-perms"
+        && cfg.backend == Backend::Zfs
+    {
+        if mat.opt_present("sim") {
+            info!(log, "not checking delegated permissions on a simulated \
+                pool");
+        } else {
+            perms::check(&log, &CliZfs::new(cfg.tools.clone(),
+                guard.clone()), &cfg)?;
+        }
+    }
 
-                            if snaps.len() < 2 {
-                                continue;
-                            }
+    let sim = mat.opt_str("sim").map(PathBuf::from);
+    let zfs = festival::open_backend(&log, &cfg, guard, sim.as_deref(),
+        dry_run)?;
 
-                            let sold = snaps[snaps.len() - 2].to_string();
-                            let snew = snaps[snaps.len() - 1].to_string();
+    if cmd == "setup-perms" {
+        return perms::setup(&log, zfs.as_ref(), &cfg);
+    }
 
-                            zfs_send_to_null(&log, &ds, &sold, &snew)?;
-                        }
-                    }));
-                }
+    let report = cfg.report.as_ref()
+        .map(|p| Arc::new(Reporter::new(p, &cmd)));
 
-                while let Some(t) = threads.pop() {
-                    t.join().unwrap()?;
+    /*
+     * Report recent operation latency periodically, so that stalls are
+     * visible while the run is in progress.  If we are writing a JSON report,
+     * bring it up to date as well.
+     */
+    let reporter = {
+        let log = log.clone();
+        let ctl = Arc::clone(&ctl);
+        let cfg = Arc::clone(&cfg);
+        let zfs = Arc::clone(&zfs);
+        let report = report.clone();
+        let ms = cfg.report_interval.as_millis() as u64;
+        thread::spawn(move || {
+            while ctl.sleep_until(None, ms) {
+                ctl.latency.report_interval(&log);
+                if let Some(r) = &report {
+                    if let Err(e) = r.write(&cfg, &ctl, zfs.as_ref(), false) {
+                        warn!(log, "could not write report: {:?}", e);
+                    }
                 }
-
-                sleep(60_000);
             }
+        })
+    };
+
+    let res = match cmd.as_str() {
+        "io" | "run" => {
+            festival::run_plants(&log, &zfs, &cfg, &ctl, duration,
+                cmd == "run" && cfg.backup.enabled)
         }
-        n => {
-            bail!("unknown command {}", n);
+        "backup" => festival::run_backup(&log, &zfs, &cfg, &ctl, duration),
+        _ => unreachable!(),
+    };
+
+    ctl.stop();
+    reporter.join().unwrap();
+    ctl.latency.report_total(&log);
+    if let Err(e) = &res {
+        ctl.stats.error(e);
+    }
+    ctl.stats.summary(&log);
+    if let Some(r) = &report {
+        match r.write(&cfg, &ctl, zfs.as_ref(), true) {
+            Ok(()) => info!(log, "wrote report to {:?}",
+                cfg.report.as_ref().unwrap()),
+            Err(e) => error!(log, "could not write report: {:?}", e),
         }
     }
+    if !ctl.stats.findings().is_empty() {
+        crit!(log, "integrity failures were found");
+        std::process::exit(2);
+    }
+    res
 }
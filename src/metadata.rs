@@ -0,0 +1,578 @@
+/*
+ * A workload which exercises directories rather than file data.  Within the
+ * fan-out tree of a plant (the "{l0}/{l1}/" directories made by
+ * "Seed::setup()"), each I/O thread creates small files, renames them across
+ * directories, makes hard links to them and unlinks them, and removes any
+ * directory it empties.  A thread only operates on the names it created, but
+ * the directories are shared, so the threads race to create and remove them.
+ *
+ * Each file we create holds the number of the object it was created as, and
+ * we keep a record of the name of every object.  Once the threads have
+ * stopped, the plant is checked against that record: every name must exist
+ * and hold the right object, the names of each object must be links to the
+ * same inode with the right link count, and no other names may be left.
+ *
+ * Of the workload parameters, "max_ops" is the most names each thread keeps
+ * at once, "block_size" the largest size of each file, and "fsync_ratio" the
+ * probability that the directory is synced after each operation.
+ */
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use rand::prelude::*;
+use super::common::*;
+use super::config::WorkloadMix;
+use super::integrity::Finding;
+use super::latency::Op;
+use super::stats::Stats;
+use super::workload::{Worker, Workload};
+
+const SUFFIX: &str = "meta";
+const MAGIC: &str = "festival object";
+
+#[derive(Default)]
+struct Record {
+    /*
+     * The object that each name, relative to the mountpoint, should hold.
+     */
+    names: BTreeMap<PathBuf, u64>,
+    /*
+     * Names, and the objects they held, whose state we cannot know because
+     * an operation on them failed in some unexpected way.  These are not
+     * checked.
+     */
+    uncertain: BTreeMap<PathBuf, u64>,
+}
+
+pub struct Metadata {
+    mix: WorkloadMix,
+    /*
+     * Every name we create carries this token, so that names left in the
+     * plant by another run (e.g., in a snapshot from which the plant was
+     * cloned) are neither touched nor checked.  It is drawn from the stream
+     * for the plant, so only a replay of the same run chooses the same one.
+     */
+    token: u32,
+    /*
+     * Allocates both object numbers and names.
+     */
+    next: AtomicU64,
+    record: Mutex<Record>,
+}
+
+/*
+ * The names a thread has created, with the object each holds.
+ */
+type Names = Vec<(PathBuf, u64)>;
+
+/*
+ * Perform an operation which creates a name in "dir".  Another thread may
+ * have removed the directory (or it may never have existed), so if the
+ * operation fails for want of it, create the directory and try again.
+ */
+fn in_dir<T, F>(dir: &Path, mut func: F) -> io::Result<T>
+    where F: FnMut() -> io::Result<T>
+{
+    for _ in 0..10 {
+        match func() {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                /*
+                 * If this fails, so will the retry.
+                 */
+                fs::create_dir_all(dir).ok();
+            }
+            res => return res,
+        }
+    }
+    func()
+}
+
+/*
+ * Whether rmdir failed only because the directory is not empty (illumos
+ * reports EEXIST for that), or is already gone.
+ */
+fn in_use(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::NotFound
+        || matches!(e.raw_os_error(), Some(libc::ENOTEMPTY | libc::EEXIST))
+}
+
+fn missing<P: AsRef<Path>>(path: P, expected: String) -> Finding {
+    Finding {
+        path: path.as_ref().to_path_buf(),
+        offset: 0,
+        expected,
+        actual: "no such file".to_string(),
+    }
+}
+
+impl Metadata {
+    pub fn new(mix: &WorkloadMix, rng: &mut dyn RngCore) -> Metadata {
+        Metadata {
+            mix: mix.clone(),
+            token: rng.gen(),
+            next: AtomicU64::new(0),
+            record: Default::default(),
+        }
+    }
+
+    fn ours(&self, p: &Path) -> bool {
+        let prefix = format!("{:08X}-", self.token);
+        p.extension().map(|e| e == SUFFIX).unwrap_or(false)
+            && p.file_name().unwrap().to_string_lossy().starts_with(&prefix)
+    }
+
+    /**
+     * Choose a new name in a random directory of the fan-out tree.
+     */
+    fn new_name(&self, rng: &mut dyn RngCore) -> PathBuf {
+        let l0 = rng.gen_range::<u64, _>(0..16);
+        let l1 = rng.gen_range::<u64, _>(0..16);
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+
+        let mut p = PathBuf::new();
+        p.push(format!("{:<04X}", l0));
+        p.push(format!("{:<04X}", l1));
+        p.push(format!("{:08X}-{:08X}.{}", self.token, n, SUFFIX));
+        p
+    }
+
+    fn set(&self, name: &Path, obj: Option<u64>) {
+        let mut record = self.record.lock().unwrap();
+        match obj {
+            Some(obj) => record.names.insert(name.to_path_buf(), obj),
+            None => record.names.remove(name),
+        };
+    }
+
+    /**
+     * Account for an operation which failed, on the name at index "i" (if
+     * any) and possibly a new name.  If the existing name has gone, a name
+     * which should exist was lost; otherwise, we no longer know what state
+     * the names are in, so we stop using and checking them.
+     */
+    fn failure(&self, w: &Worker, names: &mut Names, i: Option<usize>,
+        new: Option<(&Path, u64)>, e: io::Error)
+        -> anyhow::Error
+    {
+        let old = i.map(|i| names.swap_remove(i));
+
+        let mut record = self.record.lock().unwrap();
+        for (name, obj) in old.iter().map(|(n, o)| (n.as_path(), *o))
+            .chain(new)
+        {
+            record.names.remove(name);
+            record.uncertain.insert(name.to_path_buf(), obj);
+        }
+
+        match old {
+            Some((name, obj)) if e.kind() == io::ErrorKind::NotFound
+                && fs::symlink_metadata(w.mountpoint.join(&name)).is_err() =>
+            {
+                missing(w.mountpoint.join(&name), format!("{} {}", MAGIC, obj))
+                    .into()
+            }
+            _ => e.into(),
+        }
+    }
+
+    fn create(&self, w: &Worker, names: &mut Names, rng: &mut dyn RngCore)
+        -> Result<PathBuf>
+    {
+        let name = self.new_name(rng);
+        let obj = self.next.fetch_add(1, Ordering::Relaxed);
+        let path = w.mountpoint.join(&name);
+        let dir = path.parent().unwrap();
+
+        let mut data = format!("{} {}\n", MAGIC, obj).into_bytes();
+        let len = rng.gen_range(data.len() as u64..=self.mix.block_size);
+        while (data.len() as u64) < len {
+            data.push(rng.gen());
+        }
+
+        let res = w.lat.time(Op::Create, || in_dir(dir, || {
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)?
+                .write_all(&data)
+        }));
+        if let Err(e) = res {
+            return Err(self.failure(w, names, None, Some((&name, obj)), e));
+        }
+
+        Stats::add(&w.counts.bytes_written, len);
+        self.set(&name, Some(obj));
+        names.push((name, obj));
+        Ok(dir.to_path_buf())
+    }
+
+    fn rename(&self, w: &Worker, names: &mut Names, i: usize,
+        rng: &mut dyn RngCore)
+        -> Result<PathBuf>
+    {
+        let (from, obj) = names[i].clone();
+        let to = self.new_name(rng);
+        let (src, dst) = (w.mountpoint.join(&from), w.mountpoint.join(&to));
+        let dir = dst.parent().unwrap();
+
+        let res = w.lat.time(Op::Rename,
+            || in_dir(dir, || fs::rename(&src, &dst)));
+        if let Err(e) = res {
+            return Err(self.failure(w, names, Some(i), Some((&to, obj)), e));
+        }
+
+        self.set(&from, None);
+        self.set(&to, Some(obj));
+        names[i].0 = to;
+        self.tidy(w, src.parent().unwrap())?;
+        Ok(dir.to_path_buf())
+    }
+
+    fn link(&self, w: &Worker, names: &mut Names, i: usize,
+        rng: &mut dyn RngCore)
+        -> Result<PathBuf>
+    {
+        let (from, obj) = names[i].clone();
+        let to = self.new_name(rng);
+        let (src, dst) = (w.mountpoint.join(&from), w.mountpoint.join(&to));
+        let dir = dst.parent().unwrap();
+
+        let res = w.lat.time(Op::Link,
+            || in_dir(dir, || fs::hard_link(&src, &dst)));
+        if let Err(e) = res {
+            return Err(self.failure(w, names, Some(i), Some((&to, obj)), e));
+        }
+
+        self.set(&to, Some(obj));
+        names.push((to, obj));
+        Ok(dir.to_path_buf())
+    }
+
+    fn unlink(&self, w: &Worker, names: &mut Names, i: usize)
+        -> Result<PathBuf>
+    {
+        let path = w.mountpoint.join(&names[i].0);
+
+        if let Err(e) = w.lat.time(Op::Unlink, || fs::remove_file(&path)) {
+            return Err(self.failure(w, names, Some(i), None, e));
+        }
+
+        let (name, _) = names.swap_remove(i);
+        self.set(&name, None);
+        let dir = path.parent().unwrap();
+        self.tidy(w, dir)?;
+        Ok(dir.to_path_buf())
+    }
+
+    /**
+     * Having removed a name from "dir", remove the directory (and then its
+     * parent) if it is now empty.  It is not an error if it is not.
+     */
+    fn tidy(&self, w: &Worker, dir: &Path) -> Result<()> {
+        for d in dir.ancestors().take(2) {
+            match w.lat.time(Op::Rmdir, || fs::remove_dir(d)) {
+                Ok(()) => Stats::add(&w.counts.namespace_ops, 1),
+                Err(e) if in_use(&e) => break,
+                Err(e) => {
+                    return Err(anyhow::Error::from(e)
+                        .context(format!("removing {:?}", d)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn step(&self, w: &Worker, names: &mut Names, rng: &mut dyn RngCore)
+        -> Result<()>
+    {
+        /*
+         * Create a name three times in ten, rename or unlink one three times
+         * in ten each, and link one the rest of the time.  Once we have as
+         * many names as we may keep, only rename or remove them.
+         */
+        let choice = if names.is_empty() {
+            0
+        } else if names.len() as u64 >= self.mix.max_ops {
+            rng.gen_range(3..9)
+        } else {
+            rng.gen_range(0..10)
+        };
+        let i = rng.gen_range(0..names.len().max(1));
+
+        let dir = match choice {
+            0..=2 => self.create(w, names, rng)?,
+            3..=5 => self.rename(w, names, i, rng)?,
+            6..=8 => self.unlink(w, names, i)?,
+            _ => self.link(w, names, i, rng)?,
+        };
+        Stats::add(&w.counts.namespace_ops, 1);
+
+        if rng.gen_bool(self.mix.fsync_ratio) {
+            /*
+             * The directory may already have been removed.
+             */
+            match fs::File::open(&dir) {
+                Ok(d) => {
+                    w.lat.time(Op::Fsync, || d.sync_all())?;
+                    Stats::add(&w.counts.fsyncs, 1);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Read the object number from the start of a file.
+     */
+    fn object(path: &Path) -> Result<Option<u64>> {
+        let mut line = String::new();
+        io::BufReader::new(fs::File::open(path)?).read_line(&mut line)?;
+        Ok(line.trim_end().strip_prefix(MAGIC)
+            .and_then(|n| n.trim().parse().ok()))
+    }
+}
+
+impl Workload for Metadata {
+    fn run(&self, w: &Worker, rng: &mut dyn RngCore) {
+        let mut names = Vec::new();
+
+        while !w.ctl.stopping() {
+            if let Err(e) = self.step(w, &mut names, rng) {
+                w.failed(&e);
+            }
+        }
+    }
+
+    fn finish(&self, log: &Logger, mountpoint: &Path) -> Result<()> {
+        let record = self.record.lock().unwrap();
+
+        /*
+         * How many links each object should have.  If some names of an
+         * object are uncertain, we cannot know.
+         */
+        let mut links = BTreeMap::new();
+        for obj in record.names.values() {
+            *links.entry(*obj).or_insert(0u64) += 1;
+        }
+        for obj in record.uncertain.values() {
+            links.remove(obj);
+        }
+
+        /*
+         * The first name found for each object, and its inode.
+         */
+        let mut objects: BTreeMap<u64, (PathBuf, u64)> = BTreeMap::new();
+        let mut found = BTreeSet::new();
+
+        let walk = walkdir::WalkDir::new(mountpoint).into_iter()
+            .filter_entry(|ent| ent.file_name() != ".zfs");
+        for ent in walk {
+            let ent = ent?;
+            let path = ent.path();
+            if !ent.file_type().is_file() || !self.ours(path) {
+                continue;
+            }
+            let name = path.strip_prefix(mountpoint)?;
+            if record.uncertain.contains_key(name) {
+                continue;
+            }
+
+            let want = match record.names.get(name) {
+                Some(obj) => *obj,
+                None => bail!(Finding {
+                    path: path.to_path_buf(),
+                    offset: 0,
+                    expected: "no such file (it was renamed or unlinked)"
+                        .to_string(),
+                    actual: format!("a file holding {:?}",
+                        Metadata::object(path)?),
+                }),
+            };
+            match Metadata::object(path)? {
+                Some(obj) if obj == want => (),
+                obj => bail!(Finding {
+                    path: path.to_path_buf(),
+                    offset: 0,
+                    expected: format!("{} {}", MAGIC, want),
+                    actual: match obj {
+                        Some(obj) => format!("{} {}", MAGIC, obj),
+                        None => "a file without an object number".to_string(),
+                    },
+                }),
+            }
+
+            let md = ent.metadata()?;
+            let o = objects.entry(want)
+                .or_insert_with(|| (path.to_path_buf(), md.ino()));
+            if o.1 != md.ino() {
+                bail!(Finding {
+                    path: path.to_path_buf(),
+                    offset: 0,
+                    expected: format!("a link to inode {} (as is {:?})", o.1,
+                        o.0),
+                    actual: format!("inode {}", md.ino()),
+                });
+            }
+            match links.get(&want) {
+                Some(n) if *n != md.nlink() => bail!(Finding {
+                    path: path.to_path_buf(),
+                    offset: 0,
+                    expected: format!("{} links", n),
+                    actual: format!("{} links", md.nlink()),
+                }),
+                _ => (),
+            }
+            found.insert(name.to_path_buf());
+        }
+
+        if let Some((name, obj)) = record.names.iter()
+            .find(|(name, _)| !found.contains(*name))
+        {
+            bail!(missing(mountpoint.join(name), format!("{} {}", MAGIC, obj)));
+        }
+
+        info!(log, #"workload", "verified {} names in {:?}", found.len(),
+            mountpoint; "uncertain" => record.uncertain.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use super::super::config::Config;
+    use super::super::control::Control;
+    use super::super::golden::GoldenModel;
+    use super::super::rng::{self, Stream};
+    use super::super::testutil::{self, TempDir};
+
+    /*
+     * Run a few threads of the workload in a scratch directory, for a fixed
+     * number of operations each.
+     */
+    fn run(name: &str) -> (Logger, TempDir, Metadata) {
+        let log = testutil::logger();
+        let dir = TempDir::new("metadata", name);
+        let ctl = Control::new();
+
+        let mut cfg = Config::default();
+        cfg.workload.max_ops = 40;
+        cfg.workload.block_size = 512;
+        cfg.workload.fsync_ratio = 0.05;
+        let cfg = Arc::new(cfg);
+
+        let m = Metadata::new(&cfg.workload,
+            &mut rng::stream(1, Stream::PlantWorkload(0)));
+
+        std::thread::scope(|s| {
+            for thread in 0..4 {
+                let w = Worker {
+                    log: log.clone(),
+                    ctl: Arc::clone(&ctl),
+                    counts: ctl.stats.thread("test", thread),
+                    lat: ctl.latency.plant("test"),
+                    cfg: Arc::clone(&cfg),
+                    mountpoint: dir.path().to_path_buf(),
+                    model: Arc::new(GoldenModel::default()),
+                };
+                let m = &m;
+                s.spawn(move || {
+                    let mut rng = rng::stream(1, Stream::PlantThread(0,
+                        thread));
+                    let mut names = Vec::new();
+                    for _ in 0..300 {
+                        m.step(&w, &mut names, &mut rng).unwrap();
+                    }
+                });
+            }
+        });
+
+        (log, dir, m)
+    }
+
+    /*
+     * A name recorded for an object which has no other names.
+     */
+    fn single(m: &Metadata) -> (PathBuf, u64) {
+        let record = m.record.lock().unwrap();
+        record.names.iter()
+            .find(|(_, o)| {
+                record.names.values().filter(|p| p == o).count() == 1
+            })
+            .map(|(n, o)| (n.clone(), *o))
+            .unwrap()
+    }
+
+    fn finding(e: anyhow::Error) -> Finding {
+        e.downcast::<Finding>().unwrap()
+    }
+
+    #[test]
+    fn threads() {
+        let (log, dir, m) = run("threads");
+
+        let record = m.record.lock().unwrap();
+        assert!(!record.names.is_empty());
+        assert!(record.uncertain.is_empty());
+        drop(record);
+
+        m.finish(&log, dir.path()).unwrap();
+    }
+
+    #[test]
+    fn tampered() {
+        /*
+         * A name which should have been removed:
+         */
+        let (log, dir, m) = run("extra");
+        let (name, _) = single(&m);
+        let extra = dir.path().join(name.with_file_name(
+            format!("{:08X}-FFFFFFFF.{}", m.token, SUFFIX)));
+        fs::write(&extra, format!("{} 1\n", MAGIC)).unwrap();
+        let f = finding(m.finish(&log, dir.path()).unwrap_err());
+        assert_eq!(f.path, extra);
+        assert!(f.expected.starts_with("no such file"), "{}", f);
+
+        /*
+         * A name which has gone:
+         */
+        let (log, dir, m) = run("gone");
+        let (name, obj) = single(&m);
+        fs::remove_file(dir.path().join(&name)).unwrap();
+        let f = finding(m.finish(&log, dir.path()).unwrap_err());
+        assert_eq!(f.path, dir.path().join(&name));
+        assert_eq!(f.expected, format!("{} {}", MAGIC, obj));
+        assert_eq!(f.actual, "no such file");
+
+        /*
+         * A name holding the wrong object:
+         */
+        let (log, dir, m) = run("object");
+        let (name, obj) = single(&m);
+        fs::write(dir.path().join(&name), format!("{} {}\n", MAGIC, obj + 1))
+            .unwrap();
+        let f = finding(m.finish(&log, dir.path()).unwrap_err());
+        assert_eq!(f.path, dir.path().join(&name));
+        assert_eq!(f.actual, format!("{} {}", MAGIC, obj + 1));
+
+        /*
+         * An object with a link we did not make, under a name which is not
+         * ours:
+         */
+        let (log, dir, m) = run("links");
+        let (name, _) = single(&m);
+        fs::hard_link(dir.path().join(&name), dir.path().join("other"))
+            .unwrap();
+        let f = finding(m.finish(&log, dir.path()).unwrap_err());
+        assert!(f.actual.ends_with(" links"), "{}", f);
+    }
+}
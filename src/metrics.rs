@@ -0,0 +1,167 @@
+/*
+ * A minimal HTTP endpoint which exposes the statistics for a run in the
+ * Prometheus text exposition format, so that long runs can be watched from a
+ * dashboard.  Any request for "/metrics" gets the current values; anything
+ * else gets a 404.
+ */
+
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use super::common::*;
+use super::control::Control;
+use super::stats::get;
+
+/*
+ * Bucket bounds for latency histograms, in microseconds.
+ */
+const BOUNDS: &[u64] = &[
+    100,
+    1_000,
+    10_000,
+    100_000,
+    1_000_000,
+    10_000_000,
+    60_000_000,
+];
+
+/*
+ * Requests are served one at a time, so a client which stalls (or connects
+ * and never sends anything) must not hold up the endpoint for long.  We also
+ * read no more than a modest request's worth of data from each client.
+ */
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST: u64 = 64 * 1024;
+
+fn counter(out: &mut String, name: &str, help: &str,
+    values: &[(&str, u64)])
+{
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} counter", name).unwrap();
+    for (labels, v) in values {
+        writeln!(out, "{}{} {}", name, labels, v).unwrap();
+    }
+}
+
+fn render(ctl: &Control) -> String {
+    let s = &ctl.stats;
+    let io = s.io_total();
+    let mut out = String::new();
+
+    counter(&mut out, "stress_io_operations_total",
+        "Plant I/O operations completed.", &[
+            ("{op=\"read\"}", io.reads),
+            ("{op=\"write\"}", io.writes),
+            ("{op=\"fsync\"}", io.fsyncs),
+            ("{op=\"namespace\"}", io.namespace_ops),
+        ]);
+    counter(&mut out, "stress_io_bytes_total", "Bytes read and written.", &[
+        ("{direction=\"read\"}", io.bytes_read),
+        ("{direction=\"write\"}", io.bytes_written),
+    ]);
+
+    let errors = s.errors().into_iter()
+        .map(|(kind, n)| (format!("{{kind=\"{}\"}}", kind), n))
+        .collect::<Vec<_>>();
+    counter(&mut out, "stress_errors_total", "Failed operations, by kind.",
+        &errors.iter().map(|(l, n)| (l.as_str(), *n)).collect::<Vec<_>>());
+
+    counter(&mut out, "stress_walk_failures_total",
+        "Errors while listing the files in a plant.",
+        &[("", get(&s.walk_failures))]);
+    counter(&mut out, "stress_backup_cycles_total",
+        "Backup cycles completed.", &[("", get(&s.backup_cycles))]);
+    counter(&mut out, "stress_snapshots_taken_total",
+        "Backup snapshots taken.", &[("", get(&s.snapshots))]);
+    counter(&mut out, "stress_snapshots_destroyed_total",
+        "Backup snapshots destroyed as they aged out.",
+        &[("", get(&s.snapshots_destroyed))]);
+    counter(&mut out, "stress_sends_total",
+        "Incremental sends completed.", &[("", get(&s.sends))]);
+    counter(&mut out, "stress_verified_blocks_total",
+        "Acknowledged blocks found intact in a snapshot.",
+        &[("", get(&s.verified_blocks))]);
+    counter(&mut out, "stress_integrity_failures_total",
+        "Blocks found to be damaged or missing.",
+        &[("", s.findings().len() as u64)]);
+
+    let name = "stress_operation_latency_seconds";
+    writeln!(out, "# HELP {} Latency of I/O and ZFS operations.", name)
+        .unwrap();
+    writeln!(out, "# TYPE {} histogram", name).unwrap();
+    for (plant, op, b) in ctl.latency.buckets(BOUNDS) {
+        let labels = format!("plant=\"{}\",op=\"{}\"", plant, op.name());
+        for (bound, n) in BOUNDS.iter().zip(b.cumulative.iter()) {
+            writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels,
+                *bound as f64 / 1_000_000.0, n).unwrap();
+        }
+        writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels,
+            b.count).unwrap();
+        writeln!(out, "{}_sum{{{}}} {}", name, labels,
+            b.sum_micros / 1_000_000.0).unwrap();
+        writeln!(out, "{}_count{{{}}} {}", name, labels, b.count).unwrap();
+    }
+
+    out
+}
+
+fn serve(ctl: &Control, conn: TcpStream) -> Result<()> {
+    conn.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    conn.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut r = BufReader::new(conn.try_clone()?.take(MAX_REQUEST));
+
+    let mut request = String::new();
+    r.read_line(&mut request)?;
+
+    /*
+     * Discard the headers.
+     */
+    loop {
+        let mut l = String::new();
+        if r.read_line(&mut l)? == 0 || l.trim().is_empty() {
+            break;
+        }
+    }
+
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let (status, ctype, body) = if path == "/metrics" {
+        ("200 OK", "text/plain; version=0.0.4", render(ctl))
+    } else {
+        ("404 Not Found", "text/plain", "not found\n".to_string())
+    };
+
+    let mut w = conn;
+    write!(w, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+        Connection: close\r\n\r\n{}", status, ctype, body.len(), body)?;
+    w.flush()?;
+    Ok(())
+}
+
+/**
+ * Start serving metrics on the given address.  The listener runs until the
+ * process exits.
+ */
+pub fn start(log: &Logger, ctl: &Arc<Control>, addr: SocketAddr)
+    -> Result<()>
+{
+    let listener = TcpListener::bind(addr)
+        .with_context(|| format!("listening for metrics on {}", addr))?;
+    info!(log, "serving metrics at http://{}/metrics", addr);
+
+    let log = log.clone();
+    let ctl = Arc::clone(ctl);
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let res = conn.map_err(|e| e.into())
+                .and_then(|conn| serve(&ctl, conn));
+            if let Err(e) = res {
+                warn!(log, "metrics request failed: {:?}", e);
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,96 @@
+/*
+ * Delegated permissions, which allow a run to proceed without any privileges
+ * once "stress setup-perms" has granted the running user what it needs on the
+ * pool with "zfs allow".
+ */
+
+use std::collections::BTreeSet;
+use super::common::*;
+use super::config::Config;
+use super::tools::Privilege;
+use super::zfs::ZfsBackend;
+
+/*
+ * Everything a run does to the datasets beneath the pool.
+ */
+pub const PERMISSIONS: &[&str] = &[
+    "create",
+    "destroy",
+    "snapshot",
+    "clone",
+    "send",
+    "mount",
+    "hold",
+    "release",
+    "rollback",
+    /*
+     * Every dataset we create or clone is marked with a user property (see
+     * "OWNER_PROP").
+     */
+    "userprop",
+];
+
+/**
+ * Grant the owner of seeds and plants the permissions it needs on the pool.
+ * This is the one step which must be run with privileges.
+ */
+pub fn setup(log: &Logger, zfs: &dyn ZfsBackend, cfg: &Config) -> Result<()> {
+    if cfg.tools.privilege == Privilege::Delegated {
+        bail!("granting permissions requires privileges; use \"--privilege\" \
+            to choose how to obtain them");
+    }
+
+    let owner = cfg.tools.owner;
+    let user = owner.name().unwrap_or_else(|| owner.uid.to_string());
+    info!(log, "granting {} to user {} on {}", PERMISSIONS.join(","), user,
+        cfg.pool);
+    zfs.allow(log, &cfg.pool, &user, PERMISSIONS)?;
+
+    check(log, zfs, cfg)?;
+    info!(log, "run with \"--privilege delegated\" to use these permissions");
+    Ok(())
+}
+
+/**
+ * Make sure the owner of seeds and plants has been delegated everything a run
+ * needs, so that we do not fail part of the way through.  Permissions may
+ * have been delegated to the owner, to any group the owner belongs to, or to
+ * everyone.
+ */
+pub fn check(log: &Logger, zfs: &dyn ZfsBackend, cfg: &Config) -> Result<()> {
+    let owner = cfg.tools.owner;
+    let name = owner.name();
+
+    let mut who = vec![
+        "everyone".to_string(),
+        format!("user {}", owner.uid),
+    ];
+    if let Some(n) = &name {
+        who.push(format!("user {}", n));
+    }
+    for (gid, group) in owner.groups() {
+        who.push(format!("group {}", gid));
+        if let Some(g) = group {
+            who.push(format!("group {}", g));
+        }
+    }
+
+    let granted = zfs.allowed(log, &cfg.pool)?.into_iter()
+        .filter(|d| who.contains(&d.who))
+        .flat_map(|d| d.perms)
+        .collect::<BTreeSet<_>>();
+    let missing = PERMISSIONS.iter()
+        .filter(|p| !granted.contains(**p))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        bail!("user {} has not been delegated {} on {}; run \"stress \
+            setup-perms -p {}\" with privileges first",
+            name.unwrap_or_else(|| owner.uid.to_string()), missing.join(","),
+            cfg.pool, cfg.pool);
+    }
+
+    debug!(log, #"zfs", "delegated permissions on {} are in place", cfg.pool);
+    Ok(())
+}
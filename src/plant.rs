@@ -0,0 +1,183 @@
+/*
+ * Plants: clones of a seed, in which the I/O threads do their work.
+ */
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use rand::prelude::*;
+use super::common::*;
+use super::config::{Config, Origin};
+use super::control::Control;
+use super::golden::GoldenModel;
+use super::integrity::Finding;
+use super::rng::{self, Stream};
+use super::seed::Seed;
+use super::tools::Tools;
+use super::workload::{Worker, Workload};
+use super::zfs::ZfsBackend;
+
+pub struct Plant {
+    log: Logger,
+    id: u64,
+    dataset: String,
+    mountpoint: PathBuf,
+    /*
+     * The writes acknowledged in this plant, against which each backup
+     * snapshot is checked.
+     */
+    model: Arc<GoldenModel>,
+}
+
+/**
+ * Report damaged data.  This is the most important thing this tool can find,
+ * so stop everything in order that the evidence is preserved.
+ */
+pub(crate) fn fatal_finding(log: &Logger, ctl: &Control, f: &Finding) {
+    crit!(log, "{}", f; "path" => ?f.path, "offset" => f.offset,
+        "expected" => &f.expected, "actual" => &f.actual);
+    ctl.stats.finding(f);
+    ctl.stop();
+}
+
+impl Plant {
+    pub fn setup(log: Logger, zfs: &dyn ZfsBackend, tools: &Tools,
+        pool: &str, id: u64, origin: (&str, &str))
+        -> Result<Plant>
+    {
+        /*
+         * Start with a clean slate.
+         */
+        let dataset = format!("{}/plant/{:<04}", pool, id);
+        zfs.destroy(&log, &dataset, true)?;
+
+        /*
+         * Clone the seed:
+         */
+        let (parent, snap) = origin;
+        zfs.clone_snapshot(&log, parent, snap, &dataset)?;
+
+        let mountpoint = PathBuf::from(zfs.get(&log, &dataset,
+            "mountpoint")?);
+        if !zfs.dry_run() {
+            tools.chown_to_owner(&log, &mountpoint)?;
+        }
+
+        Ok(Plant {
+            log,
+            id,
+            mountpoint,
+            dataset,
+            model: Default::default(),
+        })
+    }
+
+    /**
+     * Start the I/O threads for this plant, which run the workload until
+     * asked to stop.
+     */
+    pub fn start(&self, cfg: &Arc<Config>, ctl: &Arc<Control>,
+        workload: &Arc<dyn Workload>)
+        -> Vec<thread::JoinHandle<()>>
+    {
+        (0..cfg.plant_threads).map(|thread| {
+            let w = Worker {
+                log: self.log.clone(),
+                ctl: Arc::clone(ctl),
+                counts: ctl.stats.thread(&self.dataset, thread),
+                lat: ctl.latency.plant(&self.dataset),
+                cfg: Arc::clone(cfg),
+                mountpoint: self.mountpoint.clone(),
+                model: Arc::clone(&self.model),
+            };
+            let workload = Arc::clone(workload);
+            let mut rng = rng::stream(cfg.master_seed,
+                Stream::PlantThread(self.id, thread));
+            thread::spawn(move || workload.run(&w, &mut rng))
+        }).collect()
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    pub fn dataset(&self) -> &str {
+        &self.dataset
+    }
+
+    /**
+     * The writes acknowledged by the I/O threads in this plant.
+     */
+    pub fn model(&self) -> &Arc<GoldenModel> {
+        &self.model
+    }
+}
+
+/**
+ * Prepare the seed datasets, then destroy any previous plants and establish
+ * new ones.  The I/O threads are not started.
+ */
+pub fn setup_plants(log: &Logger, zfs: &Arc<dyn ZfsBackend>, cfg: &Config)
+    -> Result<Vec<Plant>>
+{
+    let pool = cfg.pool.as_str();
+    let plantroot = format!("{}/plant", pool);
+
+    /*
+     * If we are cloning an existing snapshot, make sure it exists before we
+     * destroy anything.
+     */
+    if let Origin::Snapshot(ds, snap) = &cfg.origin {
+        if !zfs.snapshot_exists(log, ds, snap)? {
+            bail!("origin snapshot {}@{} does not exist", ds, snap);
+        }
+    }
+
+    /*
+     * Destroy all previous plants.  This must happen before the seeds are
+     * prepared, as a seed cannot be recreated while plants are still cloned
+     * from it.
+     */
+    zfs.destroy(log, &plantroot, true)?;
+
+    /*
+     * Prepare seed datasets, unless we are cloning an existing snapshot:
+     */
+    let seeds = if let Origin::Snapshot(..) = &cfg.origin {
+        Vec::new()
+    } else {
+        (0..cfg.seeds).map(|id| {
+            let log = log.new(o! { "seed" => id });
+
+            info!(log, #"seed", "creating seed {}", id);
+
+            Seed::setup(&log, zfs.as_ref(), cfg, id)
+        }).collect::<Result<Vec<_>>>()?
+    };
+
+    zfs.create(log, &plantroot, false)?;
+
+    /*
+     * Establish plants, each from its origin:
+     */
+    let mut rng = rng::stream(cfg.master_seed, Stream::PlantSetup);
+    (0..cfg.plants).map(|id| {
+        let log = log.new(o! { "plant" => id });
+
+        let origin = match &cfg.origin {
+            Origin::Snapshot(ds, snap) => (ds.as_str(), snap.as_str()),
+            Origin::Random => seeds[rng.gen_range(0..seeds.len())].origin(),
+            Origin::RoundRobin => {
+                seeds[(id % seeds.len() as u64) as usize].origin()
+            }
+        };
+        info!(log, "creating plant {} from {}@{}", id, origin.0, origin.1;
+            "workload" => &cfg.workload_for(id).name);
+
+        Plant::setup(log.clone(), zfs.as_ref(), &cfg.tools, pool, id, origin)
+    }).collect::<Result<Vec<_>>>()
+}
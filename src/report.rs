@@ -0,0 +1,119 @@
+/*
+ * A machine-readable report of a run, written as JSON so that the results of
+ * runs against different ZFS builds can be compared mechanically.
+ */
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use super::common::*;
+use super::config::Config;
+use super::control::Control;
+use super::integrity::Finding;
+use super::latency::PercentileSet;
+use super::stats::{get, IoCounts};
+use super::zfs::{CommandFailure, ZfsBackend};
+
+#[derive(Default, Serialize)]
+struct PlantReport {
+    io: IoCounts,
+    threads: BTreeMap<u64, IoCounts>,
+    latency: PercentileSet,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    command: &'a str,
+    /*
+     * False for the reports written periodically while the run is still in
+     * progress.
+     */
+    finished: bool,
+    started: u64,
+    elapsed_secs: f64,
+    master_seed: u64,
+    config: &'a Config,
+    io: IoCounts,
+    counters: BTreeMap<&'static str, u64>,
+    latency: PercentileSet,
+    plants: BTreeMap<String, PlantReport>,
+    errors: BTreeMap<String, u64>,
+    zfs_failures: Vec<CommandFailure>,
+    findings: Vec<Finding>,
+}
+
+pub struct Reporter {
+    path: PathBuf,
+    command: String,
+    started: SystemTime,
+    start: Instant,
+}
+
+impl Reporter {
+    pub fn new<P: AsRef<Path>>(path: P, command: &str) -> Reporter {
+        Reporter {
+            path: path.as_ref().to_path_buf(),
+            command: command.to_string(),
+            started: SystemTime::now(),
+            start: Instant::now(),
+        }
+    }
+
+    /**
+     * Write the report as it stands.  The file is replaced atomically, so
+     * that a reader never sees a partial report.
+     */
+    pub fn write(&self, cfg: &Config, ctl: &Control, zfs: &dyn ZfsBackend,
+        finished: bool)
+        -> Result<()>
+    {
+        let s = &ctl.stats;
+        let (latency_plants, latency) = ctl.latency.percentiles();
+
+        let mut plants = BTreeMap::new();
+        for (plant, thread, c) in s.io_by_thread() {
+            let p: &mut PlantReport = plants.entry(plant).or_default();
+            p.io.add(&c);
+            p.threads.insert(thread, c);
+        }
+        for (plant, pct) in latency_plants {
+            plants.entry(plant).or_default().latency = pct;
+        }
+
+        let mut counters = BTreeMap::new();
+        counters.insert("walk_failures", get(&s.walk_failures));
+        counters.insert("backup_cycles", get(&s.backup_cycles));
+        counters.insert("snapshots", get(&s.snapshots));
+        counters.insert("snapshots_destroyed", get(&s.snapshots_destroyed));
+        counters.insert("sends", get(&s.sends));
+        counters.insert("verified_blocks", get(&s.verified_blocks));
+
+        let report = Report {
+            command: &self.command,
+            finished,
+            started: self.started.duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs()).unwrap_or(0),
+            elapsed_secs: self.start.elapsed().as_secs_f64(),
+            master_seed: cfg.master_seed,
+            config: cfg,
+            io: s.io_total(),
+            counters,
+            latency,
+            plants,
+            errors: s.errors(),
+            zfs_failures: zfs.failures(),
+            findings: s.findings(),
+        };
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let f = std::fs::File::create(&tmp)
+            .with_context(|| format!("creating report {:?}", tmp))?;
+        serde_json::to_writer_pretty(&f, &report)?;
+        f.sync_all()?;
+        std::fs::rename(&tmp, &self.path)
+            .with_context(|| format!("writing report {:?}", self.path))?;
+        Ok(())
+    }
+}
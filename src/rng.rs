@@ -0,0 +1,109 @@
+/*
+ * Every random choice made during a run comes from a stream derived from a
+ * single master seed.  Each seed dataset, each plant workload and each of its
+ * I/O threads, and the plant setup logic gets its own independent stream, so
+ * that the choices made by one do not depend on how far along any other has
+ * progressed.  Replaying a run with the same master seed reproduces the same
+ * file layout and the same sequence of operations on each thread.
+ */
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+
+pub enum Stream {
+    /*
+     * The generation of files for a particular seed dataset.
+     */
+    Seed(u64),
+    /*
+     * Choices made while establishing plants, such as which seed to clone.
+     */
+    PlantSetup,
+    /*
+     * The I/O performed by a particular thread within a plant.
+     */
+    PlantThread(u64, u64),
+    /*
+     * Choices made by the workload of a particular plant before any of its
+     * threads start.
+     */
+    PlantWorkload(u64),
+}
+
+impl Stream {
+    fn id(&self) -> u64 {
+        /*
+         * The top bits identify the kind of stream, and the rest identify
+         * the instance.
+         */
+        match self {
+            Stream::Seed(id) => (1 << 60) | (id & 0xFFFF_FFFF),
+            Stream::PlantSetup => 2 << 60,
+            Stream::PlantThread(plant, thread) => {
+                (3 << 60) | ((plant & 0xFFFF_FFFF) << 16) | (thread & 0xFFFF)
+            }
+            Stream::PlantWorkload(plant) => (4 << 60) | (plant & 0xFFFF_FFFF),
+        }
+    }
+}
+
+/**
+ * Produce the random number generator for a particular stream.
+ */
+pub fn stream(master: u64, s: Stream) -> ChaCha20Rng {
+    let mut rng = ChaCha20Rng::seed_from_u64(master);
+    rng.set_stream(s.id());
+    rng
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::RngCore;
+
+    fn first(master: u64, s: Stream) -> [u64; 4] {
+        let mut rng = stream(master, s);
+        [rng.next_u64(), rng.next_u64(), rng.next_u64(), rng.next_u64()]
+    }
+
+    #[test]
+    fn reproducible() {
+        let streams: [fn() -> Stream; 4] = [
+            || Stream::Seed(3),
+            || Stream::PlantSetup,
+            || Stream::PlantThread(7, 2),
+            || Stream::PlantWorkload(7),
+        ];
+        for s in streams {
+            assert_eq!(first(1234, s()), first(1234, s()));
+            assert_ne!(first(1234, s()), first(1235, s()));
+        }
+    }
+
+    #[test]
+    fn independent() {
+        /*
+         * Every stream drawn from one master seed is distinct, including
+         * those whose instance numbers look alike.
+         */
+        let streams = vec![
+            Stream::Seed(0),
+            Stream::Seed(1),
+            Stream::PlantSetup,
+            Stream::PlantThread(0, 0),
+            Stream::PlantThread(0, 1),
+            Stream::PlantThread(1, 0),
+            Stream::PlantWorkload(0),
+            Stream::PlantWorkload(1),
+        ];
+        let ids = streams.iter().map(|s| s.id()).collect::<Vec<_>>();
+        let firsts = streams.into_iter().map(|s| first(99, s))
+            .collect::<Vec<_>>();
+        for i in 0..ids.len() {
+            for j in 0..i {
+                assert_ne!(ids[i], ids[j]);
+                assert_ne!(firsts[i], firsts[j]);
+            }
+        }
+    }
+}
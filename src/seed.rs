@@ -0,0 +1,174 @@
+/*
+ * Seed datasets, from which the plants are cloned.
+ */
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use rand::prelude::*;
+use super::common::*;
+use super::config::Config;
+use super::rng::{self, Stream};
+use super::zfs::{ZfsBackend, SEED_PROP};
+
+pub const KILOBYTE: u64 = 1024;
+pub const MEGABYTE: u64 = KILOBYTE * 1024;
+
+/*
+ * Produce a "seed" dataset.  This will be filled with a set of random files,
+ * and a snapshot will be taken.  This snapshot will be used to create many
+ * clones in which various concurrent operations will occur.
+ */
+pub struct Seed {
+    dataset: String,
+}
+
+/**
+ * Create (or replace) a file of the given size, filled with a mixture of
+ * random and compressible data.
+ */
+pub fn fill_file<P: AsRef<Path>, T: rand::Rng>(p: P, rng: &mut T, sz_mb: u64,
+    compressible: f64)
+    -> Result<()>
+{
+    let f = fs::OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(p.as_ref())?;
+    let mut bw = io::BufWriter::new(f);
+
+    let mut buf = Vec::with_capacity(8192);
+    for _ in 0..(sz_mb * 64) {
+        buf.clear();
+
+        /*
+         * Generate mostly random data, with some compressible data:
+         */
+        let random = !rng.gen_bool(compressible);
+
+        while buf.len() < (16 * KILOBYTE) as usize {
+            if random {
+                buf.push(rng.gen::<u8>());
+            } else {
+                buf.push(b'A');
+            }
+        }
+
+        bw.write_all(&buf)?;
+    }
+
+    bw.flush()?;
+    Ok(())
+}
+
+impl Seed {
+    /**
+     * Make sure the seed dataset exists and has been snapshotted.  A complete
+     * seed from an earlier run is reused, unless the master seed was chosen
+     * by the user and the seed was generated from a different one.
+     */
+    pub fn setup(log: &Logger, zfs: &dyn ZfsBackend, cfg: &Config, id: u64)
+        -> Result<Seed>
+    {
+        let recipe = &cfg.seed;
+        let master_seed = cfg.master_seed;
+
+        let root = format!("{}/seed", cfg.pool);
+        zfs.create(log, &root, true)?;
+
+        let dataset = format!("{}/{:<04}", root, id);
+        let made_from = master_seed.to_string();
+
+        let ready = zfs.snapshot_exists(log, &dataset, "final")? && {
+            /*
+             * The files in a seed are part of what the master seed
+             * reproduces, so a seed generated from another one will not do
+             * when replaying a run.  Otherwise, regenerating every seed on
+             * every run would be far too expensive.  Seeds made before we
+             * recorded the master seed have no value for the property, and
+             * are reused.
+             */
+            let prev = zfs.get(log, &dataset, SEED_PROP)?;
+            if prev == made_from || prev == "-" {
+                true
+            } else if cfg.master_seed_given {
+                warn!(log, #"seed", "seed {} was generated from master seed \
+                    {}, not {}; recreating it", id, prev, master_seed);
+                false
+            } else {
+                info!(log, #"seed", "reusing seed {} generated from master \
+                    seed {}", id, prev);
+                true
+            }
+        };
+
+        if !ready {
+            /*
+             * A previous setup run did not complete, or used another master
+             * seed.  Destroy and recreate the entire thing.  Any plants
+             * cloned from it must have been destroyed already.
+             */
+            zfs.destroy(log, &dataset, true)
+                .with_context(|| format!("destroying {} to recreate it",
+                    dataset))?;
+            zfs.create(log, &dataset, false)?;
+            zfs.set(log, &dataset, SEED_PROP, &made_from)?;
+
+            if zfs.dry_run() {
+                info!(log, #"seed", "would fill {} with {} files", dataset,
+                    recipe.files);
+            } else {
+                let mountpoint = PathBuf::from(zfs.get(log, &dataset,
+                    "mountpoint")?);
+                cfg.tools.chown_to_owner(log, &mountpoint)?;
+
+                /*
+                 * Create a fan-out directory structure full of files of random
+                 * size.
+                 */
+                let mut rng = rng::stream(master_seed, Stream::Seed(id));
+
+                for _ in 0..recipe.files {
+                    let l0 = rng.gen_range::<u64, _>(0..16);
+                    let l1 = rng.gen_range::<u64, _>(0..16);
+                    let l2 = rng.gen::<u64>();
+
+                    let mut fp = mountpoint.clone();
+                    fp.push(format!("{:<04X}", l0));
+                    fp.push(format!("{:<04X}", l1));
+                    std::fs::create_dir_all(&fp)?;
+                    fp.push(format!("{:<016X}.dat", l2));
+
+                    let sz_mb = rng.gen_range::<u64, _>(
+                        recipe.file_min..=recipe.file_max);
+
+                    fill_file(&fp, &mut rng, sz_mb, recipe.compressible)?;
+                }
+            }
+
+            /*
+             * Take the "final" snapshot that we will use to create clones.
+             */
+            zfs.snapshot(log, &dataset, "final", false)?;
+        } else {
+            info!(log, #"seed", "seed {} already setup", id);
+        }
+
+        Ok(Seed {
+            dataset,
+        })
+    }
+
+    pub fn dataset(&self) -> &str {
+        &self.dataset
+    }
+
+    /**
+     * The snapshot from which plants are cloned.
+     */
+    pub fn origin(&self) -> (&str, &str) {
+        (self.dataset(), "final")
+    }
+}
@@ -0,0 +1,978 @@
+/*
+ * A simulated ZFS pool, which keeps the dataset hierarchy in memory.  This
+ * allows the orchestration of a stress run to be exercised on a system without
+ * a pool.
+ *
+ * Rather than implementing the backend operations directly, the simulation
+ * interprets the same "zfs" commands that the real backend would run, and
+ * produces the same exit status and error messages.  This means the command
+ * construction and output parsing in "zfs.rs" is exercised as well.
+ */
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output};
+use std::sync::Mutex;
+use super::common::*;
+use super::tools::Tools;
+use super::zfs::*;
+
+struct SimSnapshot {
+    name: String,
+    txg: u64,
+    holds: BTreeSet<String>,
+}
+
+struct SimDataset {
+    txg: u64,
+    /*
+     * If this dataset is a clone, the full name of the origin snapshot.
+     */
+    origin: Option<String>,
+    snapshots: Vec<SimSnapshot>,
+    /*
+     * Permissions delegated with "zfs allow", by user.
+     */
+    allowed: BTreeMap<String, BTreeSet<String>>,
+    /*
+     * User properties set on this dataset, rather than inherited.
+     */
+    props: BTreeMap<String, String>,
+}
+
+struct SimPool {
+    /*
+     * Every operation that creates something advances the transaction group,
+     * which gives us a creation order for datasets and snapshots.
+     */
+    txg: u64,
+    datasets: BTreeMap<String, SimDataset>,
+}
+
+pub struct SimZfs {
+    /*
+     * If a root directory is provided, each dataset is given a real directory
+     * beneath it to use as a mountpoint.  Snapshots and clones do not copy any
+     * data.
+     */
+    root: Option<PathBuf>,
+    pool: Mutex<SimPool>,
+    /*
+     * The programs we interpret, recognised by file name.
+     */
+    zfs: PathBuf,
+    bash: PathBuf,
+}
+
+/*
+ * The result of a simulated command: either the standard output of a
+ * successful command, or an exit status and the standard error of a failed
+ * one.
+ */
+type SimResult = std::result::Result<String, (i32, String)>;
+
+fn fail<T>(msg: String) -> std::result::Result<T, (i32, String)> {
+    Err((1, msg))
+}
+
+fn usage<T>(msg: &str) -> std::result::Result<T, (i32, String)> {
+    Err((2, format!("{}\nusage: ...", msg)))
+}
+
+fn parent_of(dataset: &str) -> Option<&str> {
+    dataset.rfind('/').map(|i| &dataset[..i])
+}
+
+fn is_descendant(dataset: &str, ancestor: &str) -> bool {
+    dataset.len() > ancestor.len()
+        && dataset.starts_with(ancestor)
+        && dataset.as_bytes()[ancestor.len()] == b'/'
+}
+
+fn depth(dataset: &str) -> usize {
+    dataset.matches('/').count()
+}
+
+fn split_snapshot(name: &str) -> Option<(&str, &str)> {
+    let t = name.splitn(2, '@').collect::<Vec<_>>();
+    if t.len() == 2 && !t[0].is_empty() && !t[1].is_empty() {
+        Some((t[0], t[1]))
+    } else {
+        None
+    }
+}
+
+impl SimPool {
+    fn snapshot(&self, fullname: &str) -> Option<&SimSnapshot> {
+        let (ds, snap) = split_snapshot(fullname)?;
+        self.datasets.get(ds)?.snapshots.iter().find(|s| s.name == snap)
+    }
+
+    fn clones_of(&self, fullname: &str) -> Vec<String> {
+        self.datasets.iter()
+            .filter(|(_, ds)| ds.origin.as_deref() == Some(fullname))
+            .map(|(n, _)| n.to_string())
+            .collect()
+    }
+
+    fn insert(&mut self, dataset: &str, origin: Option<String>) -> SimResult {
+        if self.datasets.contains_key(dataset) {
+            return fail(format!("cannot create '{}': dataset already exists",
+                dataset));
+        }
+        match parent_of(dataset) {
+            Some(p) if self.datasets.contains_key(p) => (),
+            _ => return fail(format!("cannot create '{}': parent does not \
+                exist", dataset)),
+        }
+
+        self.txg += 1;
+        self.datasets.insert(dataset.to_string(), SimDataset {
+            txg: self.txg,
+            origin,
+            snapshots: Vec::new(),
+            allowed: BTreeMap::new(),
+            props: BTreeMap::new(),
+        });
+        Ok(String::new())
+    }
+}
+
+impl SimZfs {
+    /**
+     * Create a simulated pool with the given name.
+     */
+    pub fn new(pool: &str, root: Option<PathBuf>) -> Result<SimZfs> {
+        validate_dataset_name(pool)?;
+        if pool.contains('/') {
+            bail!("invalid pool name {}", pool);
+        }
+
+        let mut datasets = BTreeMap::new();
+        datasets.insert(pool.to_string(), SimDataset {
+            txg: 1,
+            origin: None,
+            snapshots: Vec::new(),
+            allowed: BTreeMap::new(),
+            props: BTreeMap::new(),
+        });
+
+        let sim = SimZfs {
+            root,
+            pool: Mutex::new(SimPool {
+                txg: 1,
+                datasets,
+            }),
+            zfs: PathBuf::from("zfs"),
+            bash: PathBuf::from("bash"),
+        };
+        sim.make_mountpoint(pool)?;
+        Ok(sim)
+    }
+
+    /**
+     * Create a simulated pool which matches the state of a real one, as
+     * described by "CliZfs::inventory()".  There are no mountpoints.  The
+     * tags of existing holds are not known, but a held snapshot still cannot
+     * be destroyed.
+     */
+    pub fn import(pool: &str, entries: &[Entry]) -> Result<SimZfs> {
+        let sim = SimZfs::new(pool, None)?;
+
+        {
+            let mut p = sim.pool.lock().unwrap();
+            for e in entries {
+                if let Some((dataset, snap)) = split_snapshot(&e.name) {
+                    p.txg += 1;
+                    let txg = p.txg;
+                    let ds = match p.datasets.get_mut(dataset) {
+                        Some(ds) => ds,
+                        None => bail!("snapshot {} listed before its dataset",
+                            e.name),
+                    };
+                    let mut holds = BTreeSet::new();
+                    if e.held {
+                        holds.insert("(existing)".to_string());
+                    }
+                    ds.snapshots.push(SimSnapshot {
+                        name: snap.to_string(),
+                        txg,
+                        holds,
+                    });
+                    continue;
+                }
+
+                if e.name != pool {
+                    p.insert(&e.name, e.origin.clone())
+                        .map_err(|(_, msg)| anyhow::anyhow!("{}", msg))?;
+                }
+                p.datasets.get_mut(&e.name).unwrap().props
+                    .extend(e.props.clone());
+            }
+        }
+
+        Ok(sim)
+    }
+
+    /**
+     * Create a backend which drives this simulated pool.
+     */
+    pub fn backend(mut self, tools: Tools, guard: Guard) -> CliZfs {
+        self.use_tools(&tools);
+        CliZfs::with_runner(tools, guard, Box::new(self))
+    }
+
+    /**
+     * Interpret the programs we have been configured to run, should they
+     * have names other than "zfs" and "bash".
+     */
+    pub fn use_tools(&mut self, tools: &Tools) {
+        self.zfs = tools.zfs.clone();
+        self.bash = tools.bash.clone();
+    }
+
+    fn is(&self, arg: &str, prog: &Path) -> bool {
+        Path::new(arg).file_name() == prog.file_name()
+    }
+
+    fn mountpoint(&self, dataset: &str) -> PathBuf {
+        if let Some(root) = &self.root {
+            root.join(dataset)
+        } else {
+            PathBuf::from("/").join(dataset)
+        }
+    }
+
+    fn make_mountpoint(&self, dataset: &str) -> std::io::Result<()> {
+        if self.root.is_some() {
+            std::fs::create_dir_all(self.mountpoint(dataset))?;
+        }
+        Ok(())
+    }
+
+    fn remove_mountpoint(&self, dataset: &str) -> std::io::Result<()> {
+        if self.root.is_some() {
+            let mp = self.mountpoint(dataset);
+            if mp.exists() {
+                std::fs::remove_dir_all(&mp)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn zfs(&self, args: &[String]) -> SimResult {
+        if args.is_empty() {
+            return usage("missing command");
+        }
+
+        let mut pool = self.pool.lock().unwrap();
+
+        /*
+         * Set aside any properties given as "-o name=value" when creating a
+         * dataset.
+         */
+        let mut props = BTreeMap::new();
+        let mut rest = Vec::new();
+        let mut i = 1;
+        while i < args.len() {
+            match args[i + 1..].first().and_then(|v| v.split_once('=')) {
+                Some((k, v)) if args[i] == "-o" => {
+                    props.insert(k.to_string(), v.to_string());
+                    i += 2;
+                }
+                _ => {
+                    rest.push(args[i].as_str());
+                    i += 1;
+                }
+            }
+        }
+
+        let opts = rest.iter()
+            .filter(|a| a.starts_with('-'))
+            .cloned()
+            .collect::<Vec<_>>();
+        let operands = rest.iter()
+            .filter(|a| !a.starts_with('-'))
+            .cloned()
+            .collect::<Vec<_>>();
+        let recursive = opts.contains(&"-r");
+
+        match (args[0].as_str(), operands.as_slice()) {
+            ("create", [dataset]) => {
+                pool.insert(dataset, None)?;
+                pool.datasets.get_mut(*dataset).unwrap().props = props;
+                self.make_mountpoint(dataset)
+                    .map_err(|e| (1, e.to_string()))?;
+                Ok(String::new())
+            }
+            ("clone", [origin, target]) => {
+                if pool.snapshot(origin).is_none() {
+                    return fail(format!("cannot open '{}': dataset does not \
+                        exist", origin));
+                }
+                pool.insert(target, Some(origin.to_string()))?;
+                pool.datasets.get_mut(*target).unwrap().props = props;
+                self.make_mountpoint(target)
+                    .map_err(|e| (1, e.to_string()))?;
+                Ok(String::new())
+            }
+            ("snapshot", [fullname]) => {
+                let (dataset, snap) = if let Some(x) = split_snapshot(fullname)
+                {
+                    x
+                } else {
+                    return usage("invalid snapshot name");
+                };
+                if !pool.datasets.contains_key(dataset) {
+                    return fail(format!("cannot open '{}': dataset does not \
+                        exist", dataset));
+                }
+
+                let targets = pool.datasets.keys()
+                    .filter(|n| *n == dataset
+                        || (recursive && is_descendant(n, dataset)))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for n in &targets {
+                    if pool.snapshot(&format!("{}@{}", n, snap)).is_some() {
+                        return fail(format!("cannot create snapshot \
+                            '{}@{}': dataset already exists", n, snap));
+                    }
+                }
+
+                pool.txg += 1;
+                let txg = pool.txg;
+                for n in &targets {
+                    pool.datasets.get_mut(n).unwrap().snapshots
+                        .push(SimSnapshot {
+                            name: snap.to_string(),
+                            txg,
+                            holds: BTreeSet::new(),
+                        });
+                }
+                Ok(String::new())
+            }
+            ("destroy", [name]) => {
+                if let Some((dataset, snap)) = split_snapshot(name) {
+                    self.destroy_snapshot(&mut pool, dataset, snap)
+                } else {
+                    self.destroy(&mut pool, name, recursive)
+                }
+            }
+            ("hold", [tag, fullname]) => {
+                let (dataset, snap) = split_snapshot(fullname)
+                    .ok_or((2, "invalid snapshot name".to_string()))?;
+                let s = pool.datasets.get_mut(dataset)
+                    .and_then(|ds| ds.snapshots.iter_mut()
+                        .find(|s| s.name == snap));
+                match s.map(|s| s.holds.insert(tag.to_string())) {
+                    None => fail(format!("cannot hold snapshot '{}': dataset \
+                        does not exist", fullname)),
+                    Some(false) => fail(format!("cannot hold snapshot '{}': \
+                        tag already exists on this dataset", fullname)),
+                    Some(true) => Ok(String::new()),
+                }
+            }
+            ("release", [tag, fullname]) => {
+                let (dataset, snap) = split_snapshot(fullname)
+                    .ok_or((2, "invalid snapshot name".to_string()))?;
+                let s = pool.datasets.get_mut(dataset)
+                    .and_then(|ds| ds.snapshots.iter_mut()
+                        .find(|s| s.name == snap));
+                match s.map(|s| s.holds.remove(*tag)) {
+                    None => fail(format!("cannot release hold from snapshot \
+                        '{}': dataset does not exist", fullname)),
+                    Some(false) => fail(format!("cannot release hold from \
+                        snapshot '{}': no such tag on this dataset", fullname)),
+                    Some(true) => Ok(String::new()),
+                }
+            }
+            ("set", [assignment, dataset]) => {
+                /*
+                 * We only support setting user properties.
+                 */
+                let (k, v) = match assignment.split_once('=') {
+                    Some((k, v)) if k.contains(':') => (k, v),
+                    _ => return usage(&format!("unsupported property \
+                        assignment: {}", assignment)),
+                };
+                let ds = match pool.datasets.get_mut(*dataset) {
+                    Some(ds) => ds,
+                    None => return fail(format!("cannot open '{}': dataset \
+                        does not exist", dataset)),
+                };
+                ds.props.insert(k.to_string(), v.to_string());
+                Ok(String::new())
+            }
+            ("allow", [user, perms, dataset]) => {
+                /*
+                 * We only support "allow -u USER PERMS DATASET".
+                 */
+                let ds = match pool.datasets.get_mut(*dataset) {
+                    Some(ds) => ds,
+                    None => return fail(format!("cannot open '{}': dataset \
+                        does not exist", dataset)),
+                };
+                ds.allowed.entry(user.to_string()).or_default()
+                    .extend(perms.split(',').map(str::to_string));
+                Ok(String::new())
+            }
+            ("allow", [dataset]) => {
+                let ds = match pool.datasets.get(*dataset) {
+                    Some(ds) => ds,
+                    None => return fail(format!("cannot open '{}': dataset \
+                        does not exist", dataset)),
+                };
+                if ds.allowed.is_empty() {
+                    return Ok(String::new());
+                }
+                let mut out = format!("---- Permissions on {} \
+                    --------------------\nLocal+Descendent permissions:\n",
+                    dataset);
+                for (user, perms) in &ds.allowed {
+                    out += &format!("\tuser {} {}\n", user,
+                        perms.iter().cloned().collect::<Vec<_>>().join(","));
+                }
+                Ok(out)
+            }
+            ("get", _) | ("list", _) if args.len() < 2 => {
+                usage("missing dataset argument")
+            }
+            ("get", _) => {
+                /*
+                 * We only support "get -H -o value [-s local] <prop>
+                 * <dataset>".
+                 */
+                let n = args.len();
+                let local = opts.contains(&"-s");
+                self.get(&pool, &args[n - 2], &args[n - 1], local)
+            }
+            ("list", _) => {
+                self.list(&pool, &args[1..args.len() - 1],
+                    &args[args.len() - 1])
+            }
+            ("send", [_, _]) => {
+                /*
+                 * We only support incremental sends: "send -i old new".
+                 */
+                let old = operands[0];
+                let new = operands[1];
+                for n in &[old, new] {
+                    if pool.snapshot(n).is_none() {
+                        return fail(format!("cannot open '{}': dataset does \
+                            not exist", n));
+                    }
+                }
+                let (dsold, _) = split_snapshot(old).unwrap();
+                let (dsnew, _) = split_snapshot(new).unwrap();
+                if dsold != dsnew
+                    || pool.snapshot(old).unwrap().txg
+                        >= pool.snapshot(new).unwrap().txg
+                {
+                    return fail(format!("incremental source ({}) is not \
+                        earlier than it ({})", old, new));
+                }
+                Ok(String::new())
+            }
+            (cmd, _) => usage(&format!("unsupported command: {}", cmd)),
+        }
+    }
+
+    fn destroy_snapshot(&self, pool: &mut SimPool, dataset: &str, snap: &str)
+        -> SimResult
+    {
+        let fullname = format!("{}@{}", dataset, snap);
+
+        if !pool.datasets.contains_key(dataset) {
+            return fail(format!("cannot open '{}': dataset does not exist",
+                dataset));
+        }
+        let s = if let Some(s) = pool.snapshot(&fullname) {
+            s
+        } else {
+            return fail("could not find any snapshots to destroy; check \
+                snapshot names.".to_string());
+        };
+
+        let clones = pool.clones_of(&fullname);
+        if !clones.is_empty() {
+            return fail(format!("cannot destroy '{}': snapshot has dependent \
+                clones\nuse '-R' to destroy the following datasets:\n{}",
+                fullname, clones.join("\n")));
+        }
+        if !s.holds.is_empty() {
+            return fail(format!("cannot destroy snapshot {}: dataset is busy",
+                fullname));
+        }
+
+        pool.datasets.get_mut(dataset).unwrap().snapshots
+            .retain(|s| s.name != snap);
+        Ok(String::new())
+    }
+
+    fn destroy(&self, pool: &mut SimPool, dataset: &str, recursive: bool)
+        -> SimResult
+    {
+        if !pool.datasets.contains_key(dataset) {
+            return fail(format!("cannot open '{}': dataset does not exist",
+                dataset));
+        }
+        if parent_of(dataset).is_none() {
+            return fail(format!("cannot destroy '{}': operation does not \
+                apply to pools", dataset));
+        }
+
+        /*
+         * Determine the full set of datasets that would be removed, including
+         * snapshots.
+         */
+        let victims = pool.datasets.keys()
+            .filter(|n| *n == dataset || is_descendant(n, dataset))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut snapshots = Vec::new();
+        for n in &victims {
+            for s in &pool.datasets[n].snapshots {
+                snapshots.push((format!("{}@{}", n, s.name), s));
+            }
+        }
+
+        if !recursive && (victims.len() > 1 || !snapshots.is_empty()) {
+            let names = victims.iter()
+                .filter(|n| *n != dataset)
+                .cloned()
+                .chain(snapshots.iter().map(|(n, _)| n.to_string()))
+                .collect::<Vec<_>>();
+            return fail(format!("cannot destroy '{}': filesystem has \
+                children\nuse '-r' to destroy the following datasets:\n{}",
+                dataset, names.join("\n")));
+        }
+
+        /*
+         * Clones of our snapshots that are not themselves being destroyed
+         * prevent the destroy.
+         */
+        let clones = snapshots.iter()
+            .flat_map(|(n, _)| pool.clones_of(n))
+            .filter(|c| !victims.contains(c))
+            .collect::<Vec<_>>();
+        if !clones.is_empty() {
+            return fail(format!("cannot destroy '{}': filesystem has \
+                dependent clones\nuse '-R' to destroy the following \
+                datasets:\n{}", dataset, clones.join("\n")));
+        }
+
+        let held = snapshots.iter().find(|(_, s)| !s.holds.is_empty());
+        if let Some((n, _)) = held {
+            return fail(format!("cannot destroy snapshot {}: dataset is busy",
+                n));
+        }
+
+        for n in &victims {
+            pool.datasets.remove(n);
+        }
+        for n in &victims {
+            self.remove_mountpoint(n).map_err(|e| (1, e.to_string()))?;
+        }
+        Ok(String::new())
+    }
+
+    fn get(&self, pool: &SimPool, prop: &str, name: &str, local: bool)
+        -> SimResult
+    {
+        let (dataset, snap) = if let Some((d, s)) = split_snapshot(name) {
+            (d, Some(s))
+        } else {
+            (name, None)
+        };
+
+        let ds = match pool.datasets.get(dataset) {
+            Some(ds) if snap.is_none() || pool.snapshot(name).is_some() => ds,
+            _ => return fail(format!("cannot open '{}': dataset does not \
+                exist", name)),
+        };
+
+        Ok(match prop {
+            "name" => name.to_string(),
+            "type" if snap.is_some() => "snapshot".to_string(),
+            "type" => "filesystem".to_string(),
+            "mountpoint" if snap.is_some() => "-".to_string(),
+            "mountpoint" => self.mountpoint(dataset)
+                .to_str()
+                .unwrap()
+                .to_string(),
+            "origin" => ds.origin.clone().unwrap_or_else(|| "-".to_string()),
+            "createtxg" => match snap {
+                Some(_) => pool.snapshot(name).unwrap().txg.to_string(),
+                None => ds.txg.to_string(),
+            },
+            "userrefs" => match snap {
+                Some(_) => pool.snapshot(name).unwrap().holds.len().to_string(),
+                None => "-".to_string(),
+            },
+            p if p.contains(':') => {
+                /*
+                 * User properties are inherited from the nearest ancestor on
+                 * which they are set.  If only local values were requested,
+                 * there is no output at all for an inherited or unset one.
+                 */
+                let val = std::iter::successors(Some(dataset), |d| {
+                        parent_of(d)
+                    })
+                    .take(if local { 1 } else { usize::MAX })
+                    .find_map(|d| pool.datasets.get(d)
+                        .and_then(|ds| ds.props.get(p)));
+                match val {
+                    Some(v) => v.to_string(),
+                    None if local => String::new(),
+                    None => "-".to_string(),
+                }
+            }
+            p => return usage(&format!("bad property list: invalid property \
+                '{}'", p)),
+        })
+    }
+
+    fn list(&self, pool: &SimPool, args: &[String], target: &str)
+        -> SimResult
+    {
+        /*
+         * Parse the small subset of "zfs list" options that we use.
+         */
+        let mut types = vec!["filesystem".to_string()];
+        let mut maxdepth = 0;
+        let mut by_creation = false;
+        let mut i = 0;
+        while i < args.len() {
+            let next = args.get(i + 1).map(|s| s.as_str());
+            match (args[i].as_str(), next) {
+                ("-t", Some(t)) => {
+                    types = t.split(',').map(|s| s.to_string()).collect();
+                    i += 1;
+                }
+                ("-d", Some(d)) => {
+                    maxdepth = d.parse().map_err(|_| (2,
+                        "invalid depth".to_string()))?;
+                    i += 1;
+                }
+                ("-s", Some(s)) => {
+                    by_creation = s == "creation";
+                    i += 1;
+                }
+                ("-o", Some("name")) | ("-Ho", Some("name")) => i += 1,
+                ("-o", _) | ("-Ho", _) => {
+                    return usage("only the name property is supported");
+                }
+                ("-r", _) => maxdepth = usize::MAX,
+                ("-H", _) => (),
+                (a, _) if a.starts_with('-') => {
+                    return usage(&format!("invalid option '{}'", a));
+                }
+                _ => (),
+            }
+            i += 1;
+        }
+
+        /*
+         * A snapshot name is listed as-is, regardless of the other options.
+         */
+        if split_snapshot(target).is_some() {
+            if pool.snapshot(target).is_none() {
+                return fail(format!("cannot open '{}': dataset does not \
+                    exist", target));
+            }
+            return Ok(format!("{}\n", target));
+        }
+
+        if !pool.datasets.contains_key(target) {
+            return fail(format!("cannot open '{}': dataset does not exist",
+                target));
+        }
+
+        let want_fs = types.iter().any(|t| t == "filesystem" || t == "all");
+        let want_snap = types.iter().any(|t| t == "snapshot" || t == "all");
+
+        let mut out = Vec::new();
+        for (n, ds) in pool.datasets.iter() {
+            if n != target && !is_descendant(n, target) {
+                continue;
+            }
+            let d = depth(n) - depth(target);
+            if want_fs && d <= maxdepth {
+                out.push((ds.txg, n.to_string()));
+            }
+            if want_snap && d < maxdepth {
+                for s in &ds.snapshots {
+                    out.push((s.txg, format!("{}@{}", n, s.name)));
+                }
+            }
+        }
+        if by_creation {
+            out.sort_by_key(|(txg, _)| *txg);
+        }
+
+        Ok(out.into_iter().map(|(_, n)| n + "\n").collect())
+    }
+}
+
+impl CommandRunner for SimZfs {
+    fn run(&self, cmd: &mut Command) -> std::io::Result<Output> {
+        let argv = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|a| a.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+
+        /*
+         * Skip over any privilege escalation wrapper to find the program we
+         * are actually running.
+         */
+        let prog = argv.iter().position(|a| {
+            self.is(a, &self.zfs) || self.is(a, &self.bash)
+        });
+
+        let res = match prog {
+            Some(i) if self.is(&argv[i], &self.zfs) => {
+                self.zfs(&argv[i + 1..])
+            }
+            Some(i) if argv.get(i + 1).map(|a| a.as_str()) == Some("-c") => {
+                /*
+                 * The only shell pipeline we run is a "zfs send" to
+                 * /dev/null.  Find the zfs invocation within the script.
+                 */
+                let words = argv.get(i + 2)
+                    .map(|s| s.split_whitespace()
+                        .map(|w| w.trim_end_matches(';').to_string())
+                        .collect::<Vec<_>>())
+                    .unwrap_or_default();
+                match words.iter().position(|w| self.is(w, &self.zfs)) {
+                    Some(z) => self.zfs(&words[z + 1..]
+                        .iter()
+                        .take_while(|w| !w.starts_with('>'))
+                        .cloned()
+                        .collect::<Vec<_>>()),
+                    None => usage("unsupported script"),
+                }
+            }
+            _ => usage(&format!("unsupported program: {:?}", argv)),
+        };
+
+        Ok(match res {
+            Ok(stdout) => Output {
+                status: ExitStatus::from_raw(0),
+                stdout: stdout.into_bytes(),
+                stderr: Vec::new(),
+            },
+            Err((code, stderr)) => Output {
+                status: ExitStatus::from_raw(code << 8),
+                stdout: Vec::new(),
+                stderr: (stderr + "\n").into_bytes(),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::testutil;
+
+    fn setup() -> (Logger, CliZfs) {
+        let log = testutil::logger();
+        let zfs = SimZfs::new("tank", None).unwrap()
+            .backend(Tools::detect(), testutil::guard());
+        testutil::populate(&log, &zfs);
+        (log, zfs)
+    }
+
+    #[test]
+    fn create_existing() {
+        let (log, zfs) = setup();
+
+        let e = zfs.create(&log, "tank/seed", false).unwrap_err();
+        assert!(matches!(e, ZfsError::AlreadyExists));
+        zfs.create(&log, "tank/seed", true).unwrap();
+
+        let e = zfs.create(&log, "tank/nothere/child", true).unwrap_err();
+        assert!(matches!(e, ZfsError::NotFound));
+    }
+
+    #[test]
+    fn snapshot_exists() {
+        let (log, zfs) = setup();
+
+        assert!(zfs.snapshot_exists(&log, "tank/seed/0000", "final").unwrap());
+        assert!(!zfs.snapshot_exists(&log, "tank/seed/0000", "other")
+            .unwrap());
+        assert!(!zfs.snapshot_exists(&log, "tank/seed/0001", "final")
+            .unwrap());
+    }
+
+    #[test]
+    fn destroy_missing() {
+        let (log, zfs) = setup();
+
+        zfs.destroy(&log, "tank/plant/0000", true).unwrap();
+        zfs.destroy_snapshot(&log, "tank/plant/0000", "backup-1").unwrap();
+        zfs.destroy_snapshot(&log, "tank/plant", "backup-1").unwrap();
+    }
+
+    #[test]
+    fn failures() {
+        let (log, zfs) = setup();
+
+        /*
+         * Failures the caller expects are not worth reporting:
+         */
+        zfs.destroy(&log, "tank/plant/0000", true).unwrap();
+        zfs.create(&log, "tank/seed", true).unwrap();
+        zfs.snapshot_exists(&log, "tank/seed/0001", "final").unwrap();
+        assert!(zfs.failures().is_empty());
+
+        zfs.create(&log, "tank/seed", false).unwrap_err();
+        zfs.get(&log, "tank/seed/0001", "mountpoint").unwrap_err();
+        let f = zfs.failures();
+        assert_eq!(f.iter().map(|f| f.kind).collect::<Vec<_>>(),
+            vec!["already_exists", "not_found"]);
+        assert!(f[0].command.ends_with(" tank/seed"));
+    }
+
+    #[test]
+    fn destroy_recursive() {
+        let (log, zfs) = setup();
+
+        let e = zfs.destroy(&log, "tank/seed", false).unwrap_err();
+        assert!(matches!(e, ZfsError::Other { code: Some(1), .. }));
+        assert!(e.to_string().contains("filesystem has children"));
+
+        zfs.destroy(&log, "tank/seed", true).unwrap();
+        assert_eq!(zfs.dataset_children(&log, "tank").unwrap(),
+            vec!["tank".to_string(), "tank/plant".to_string()]);
+        assert!(!zfs.snapshot_exists(&log, "tank/seed/0000", "final")
+            .unwrap());
+    }
+
+    #[test]
+    fn dependent_clones() {
+        let (log, zfs) = setup();
+
+        zfs.clone_snapshot(&log, "tank/seed/0000", "final", "tank/plant/0000")
+            .unwrap();
+        assert_eq!(zfs.get(&log, "tank/plant/0000", "origin").unwrap(),
+            "tank/seed/0000@final");
+
+        let e = zfs.destroy_snapshot(&log, "tank/seed/0000", "final")
+            .unwrap_err();
+        assert!(matches!(e, ZfsError::HasDependentClones));
+        let e = zfs.destroy(&log, "tank/seed", true).unwrap_err();
+        assert!(matches!(e, ZfsError::HasDependentClones));
+
+        /*
+         * Once the plants are gone, the seed may be destroyed.
+         */
+        zfs.destroy(&log, "tank/plant", true).unwrap();
+        zfs.destroy_snapshot(&log, "tank/seed/0000", "final").unwrap();
+    }
+
+    #[test]
+    fn holds() {
+        let (log, zfs) = setup();
+
+        zfs.hold(&log, "tank/seed/0000", "final", "keep").unwrap();
+        assert!(zfs.hold(&log, "tank/seed/0000", "final", "keep").is_err());
+
+        let e = zfs.destroy_snapshot(&log, "tank/seed/0000", "final")
+            .unwrap_err();
+        assert!(matches!(e, ZfsError::Busy));
+
+        zfs.release(&log, "tank/seed/0000", "final", "keep").unwrap();
+        assert!(zfs.release(&log, "tank/seed/0000", "final", "keep").is_err());
+        zfs.destroy_snapshot(&log, "tank/seed/0000", "final").unwrap();
+    }
+
+    #[test]
+    fn snapshot_aging() {
+        let (log, zfs) = setup();
+        let ds = "tank/plant";
+
+        /*
+         * Drive the same aging logic as the backup loop, and make sure we
+         * always keep the most recent snapshots in creation order.
+         */
+        let maxsnaps = 3;
+        for i in 0..10 {
+            let snaps = loop {
+                let snaps = zfs.snapshot_list(&log, ds).unwrap();
+                if snaps.len() < maxsnaps {
+                    break snaps;
+                }
+                zfs.destroy_snapshot(&log, ds, &snaps[0]).unwrap();
+            };
+            zfs.snapshot(&log, ds, &format!("backup-{}", i), false).unwrap();
+
+            if snaps.len() >= 2 {
+                let sold = &snaps[snaps.len() - 2];
+                let snew = &snaps[snaps.len() - 1];
+                assert!(zfs.send_to_null(&log, ds, sold, snew).unwrap());
+                assert!(zfs.send_to_null(&log, ds, snew, sold).is_err());
+            }
+        }
+
+        assert_eq!(zfs.snapshot_list(&log, ds).unwrap(),
+            vec!["backup-7", "backup-8", "backup-9"]);
+    }
+
+    #[test]
+    fn allow() {
+        let (log, zfs) = setup();
+
+        assert!(zfs.allowed(&log, "tank").unwrap().is_empty());
+        zfs.allow(&log, "tank", "alice", &["create", "mount"]).unwrap();
+        zfs.allow(&log, "tank", "alice", &["snapshot"]).unwrap();
+
+        let d = zfs.allowed(&log, "tank").unwrap();
+        assert_eq!(d.len(), 1);
+        assert_eq!(d[0].who, "user alice");
+        assert_eq!(d[0].perms.iter().map(|p| p.as_str()).collect::<Vec<_>>(),
+            vec!["create", "mount", "snapshot"]);
+
+        assert!(matches!(zfs.allow(&log, "tank/nope", "alice", &["create"]),
+            Err(ZfsError::NotFound)));
+    }
+
+    #[test]
+    fn user_props() {
+        let (log, zfs) = setup();
+
+        assert_eq!(zfs.get(&log, "tank/seed/0000", SEED_PROP).unwrap(), "-");
+        zfs.set(&log, "tank/seed/0000", SEED_PROP, "42").unwrap();
+        assert_eq!(zfs.get(&log, "tank/seed/0000", SEED_PROP).unwrap(), "42");
+
+        /*
+         * User properties are inherited by children, but not by clones.
+         */
+        zfs.set(&log, "tank/seed", SEED_PROP, "7").unwrap();
+        assert_eq!(zfs.get(&log, "tank/seed/0000", SEED_PROP).unwrap(), "42");
+        zfs.clone_snapshot(&log, "tank/seed/0000", "final", "tank/plant/0000")
+            .unwrap();
+        assert_eq!(zfs.get(&log, "tank/plant/0000", SEED_PROP).unwrap(), "-");
+
+        assert!(zfs.set(&log, "tank/seed", "compression", "on").is_err());
+        assert!(matches!(zfs.set(&log, "tank/nope", SEED_PROP, "1"),
+            Err(ZfsError::NotFound)));
+    }
+
+    #[test]
+    fn destroy_unmarked() {
+        let (log, zfs) = setup();
+
+        assert_eq!(zfs.get(&log, "tank/seed", OWNER_PROP).unwrap(), "test");
+
+        /*
+         * The pool itself was not created by us.
+         */
+        assert!(matches!(zfs.destroy(&log, "tank", true),
+            Err(ZfsError::Unmarked(_))));
+        zfs.destroy(&log, "tank/seed", true).unwrap();
+    }
+}
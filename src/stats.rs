@@ -0,0 +1,182 @@
+/*
+ * Counters for the work done during a run, reported when the run ends.
+ */
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::Serialize;
+use super::common::*;
+use super::integrity::Finding;
+use super::zfs::ZfsError;
+
+/**
+ * The I/O performed by one thread within a plant.
+ */
+#[derive(Default)]
+pub struct ThreadCounts {
+    pub reads: AtomicU64,
+    pub writes: AtomicU64,
+    pub fsyncs: AtomicU64,
+    pub bytes_read: AtomicU64,
+    pub bytes_written: AtomicU64,
+    /*
+     * Files and directories created, renamed, linked or removed.
+     */
+    pub namespace_ops: AtomicU64,
+    pub errors: AtomicU64,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct IoCounts {
+    pub reads: u64,
+    pub writes: u64,
+    pub fsyncs: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub namespace_ops: u64,
+    pub errors: u64,
+}
+
+impl ThreadCounts {
+    pub fn get(&self) -> IoCounts {
+        IoCounts {
+            reads: get(&self.reads),
+            writes: get(&self.writes),
+            fsyncs: get(&self.fsyncs),
+            bytes_read: get(&self.bytes_read),
+            bytes_written: get(&self.bytes_written),
+            namespace_ops: get(&self.namespace_ops),
+            errors: get(&self.errors),
+        }
+    }
+}
+
+impl IoCounts {
+    pub fn add(&mut self, o: &IoCounts) {
+        self.reads += o.reads;
+        self.writes += o.writes;
+        self.fsyncs += o.fsyncs;
+        self.bytes_read += o.bytes_read;
+        self.bytes_written += o.bytes_written;
+        self.namespace_ops += o.namespace_ops;
+        self.errors += o.errors;
+    }
+}
+
+#[derive(Default)]
+pub struct Stats {
+    /*
+     * I/O counts for each thread, by plant dataset and thread number.
+     */
+    threads: Mutex<BTreeMap<(String, u64), Arc<ThreadCounts>>>,
+    pub walk_failures: AtomicU64,
+    pub backup_cycles: AtomicU64,
+    pub snapshots: AtomicU64,
+    pub snapshots_destroyed: AtomicU64,
+    pub sends: AtomicU64,
+    pub verified_blocks: AtomicU64,
+    /*
+     * Failed operations which did not involve damaged data, by kind; see
+     * "error_kind()".
+     */
+    errors: Mutex<BTreeMap<String, u64>>,
+    /*
+     * Blocks found to be damaged or missing.
+     */
+    findings: Mutex<Vec<Finding>>,
+}
+
+pub fn get(v: &AtomicU64) -> u64 {
+    v.load(Ordering::Relaxed)
+}
+
+/**
+ * Classify an error for reporting: ZFS failures by their "ZfsError" kind, and
+ * I/O failures by their "std::io::ErrorKind" (e.g., "not_found").
+ */
+pub fn error_kind(e: &anyhow::Error) -> String {
+    if let Some(ze) = e.downcast_ref::<ZfsError>() {
+        return ze.kind().to_string();
+    }
+    if let Some(ioe) = e.downcast_ref::<std::io::Error>() {
+        let mut out = String::new();
+        for c in format!("{:?}", ioe.kind()).chars() {
+            if c.is_uppercase() && !out.is_empty() {
+                out.push('_');
+            }
+            out.push(c.to_ascii_lowercase());
+        }
+        return out;
+    }
+    "other".to_string()
+}
+
+impl Stats {
+    pub fn add(v: &AtomicU64, n: u64) {
+        v.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /**
+     * Get the counters for a particular I/O thread.
+     */
+    pub fn thread(&self, plant: &str, thread: u64) -> Arc<ThreadCounts> {
+        let mut threads = self.threads.lock().unwrap();
+        Arc::clone(threads.entry((plant.to_string(), thread)).or_default())
+    }
+
+    pub fn io_by_thread(&self) -> Vec<(String, u64, IoCounts)> {
+        self.threads.lock().unwrap().iter()
+            .map(|((plant, thread), c)| (plant.to_string(), *thread, c.get()))
+            .collect()
+    }
+
+    pub fn io_total(&self) -> IoCounts {
+        let mut total = IoCounts::default();
+        for (_, _, c) in self.io_by_thread() {
+            total.add(&c);
+        }
+        total
+    }
+
+    /**
+     * Count a failed operation.
+     */
+    pub fn error(&self, e: &anyhow::Error) {
+        *self.errors.lock().unwrap().entry(error_kind(e)).or_insert(0) += 1;
+    }
+
+    pub fn errors(&self) -> BTreeMap<String, u64> {
+        self.errors.lock().unwrap().clone()
+    }
+
+    pub fn finding(&self, f: &Finding) {
+        self.findings.lock().unwrap().push(f.clone());
+    }
+
+    pub fn findings(&self) -> Vec<Finding> {
+        self.findings.lock().unwrap().clone()
+    }
+
+    pub fn summary(&self, log: &Logger) {
+        let io = self.io_total();
+        info!(log, "summary";
+            "reads" => io.reads,
+            "writes" => io.writes,
+            "fsyncs" => io.fsyncs,
+            "bytes_read" => io.bytes_read,
+            "bytes_written" => io.bytes_written,
+            "namespace_ops" => io.namespace_ops,
+            "walk_failures" => get(&self.walk_failures),
+            "backup_cycles" => get(&self.backup_cycles),
+            "snapshots" => get(&self.snapshots),
+            "snapshots_destroyed" => get(&self.snapshots_destroyed),
+            "sends" => get(&self.sends),
+            "verified_blocks" => get(&self.verified_blocks),
+            "errors" => self.errors().values().sum::<u64>(),
+            "integrity_failures" => self.findings().len());
+        for (kind, n) in self.errors() {
+            info!(log, "errors of kind {}: {}", kind, n);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+/*
+ * Fixtures shared by the unit tests of the backends and models.
+ */
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use super::common::*;
+use super::zfs::{Guard, ZfsBackend};
+
+pub fn logger() -> Logger {
+    Logger::root(slog::Discard, o!())
+}
+
+/**
+ * The guard used by test backends: datasets are marked with a run ID of
+ * "test", and unmarked ones are protected.
+ */
+pub fn guard() -> Guard {
+    Guard {
+        run_id: "test".to_string(),
+        destroy_unmarked: false,
+    }
+}
+
+/**
+ * A scratch directory, named for the test which uses it and for this
+ * process, which is removed when it goes out of scope.
+ */
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(kind: &str, name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("festival-{}-{}-{}",
+            kind, name, std::process::id()));
+        fs::remove_dir_all(&path).ok();
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.path).ok();
+    }
+}
+
+/**
+ * Lay out the datasets of the pool "tank" as a run would: a single seed with
+ * its "final" snapshot, and an empty plant root.
+ */
+pub fn populate(log: &Logger, zfs: &dyn ZfsBackend) {
+    zfs.create(log, "tank/seed", false).unwrap();
+    zfs.create(log, "tank/seed/0000", false).unwrap();
+    zfs.snapshot(log, "tank/seed/0000", "final", false).unwrap();
+    zfs.create(log, "tank/plant", false).unwrap();
+}
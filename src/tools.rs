@@ -0,0 +1,483 @@
+/*
+ * The external programs we run, and how we obtain the privileges they need.
+ * The defaults are detected for the platform we are running on: illumos
+ * systems use pfexec and have the ZFS tools in /sbin, while on Linux (and
+ * elsewhere) we use sudo or doas unless we are already root.
+ */
+
+use std::ffi::CString;
+use std::fmt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use serde::{Serialize, Serializer};
+use super::common::*;
+
+/**
+ * How to run commands that need more privileges than we have.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Privilege {
+    /*
+     * Run commands directly; e.g., because we are already root.
+     */
+    None,
+    /*
+     * Run commands directly, having been delegated the ZFS permissions we
+     * need with "zfs allow".  A dataset created through delegation is owned
+     * by the user who created it, as are clones of it, so we never need to
+     * change the ownership of a mountpoint.
+     */
+    Delegated,
+    Sudo,
+    Doas,
+    Pfexec,
+    /*
+     * Run commands through some other wrapper program, which is passed the
+     * full command line as its arguments.
+     */
+    Custom(PathBuf),
+}
+
+impl std::str::FromStr for Privilege {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Privilege> {
+        Ok(match s {
+            "none" => Privilege::None,
+            "delegated" => Privilege::Delegated,
+            "sudo" => Privilege::Sudo,
+            "doas" => Privilege::Doas,
+            "pfexec" => Privilege::Pfexec,
+            s if s.starts_with('/') => Privilege::Custom(PathBuf::from(s)),
+            s => bail!("invalid privilege strategy {:?} (expected \"none\", \
+                \"delegated\", \"sudo\", \"doas\", \"pfexec\", or the \
+                absolute path of a wrapper program)", s),
+        })
+    }
+}
+
+impl fmt::Display for Privilege {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Privilege::None => write!(f, "none"),
+            Privilege::Delegated => write!(f, "delegated"),
+            Privilege::Sudo => write!(f, "sudo"),
+            Privilege::Doas => write!(f, "doas"),
+            Privilege::Pfexec => write!(f, "pfexec"),
+            Privilege::Custom(p) => write!(f, "{}", p.display()),
+        }
+    }
+}
+
+impl Serialize for Privilege {
+    fn serialize<S: Serializer>(&self, s: S)
+        -> std::result::Result<S::Ok, S::Error>
+    {
+        s.serialize_str(&self.to_string())
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Tools {
+    pub privilege: Privilege,
+    /*
+     * The full path of the wrapper program for the chosen strategy, if any.
+     */
+    #[serde(skip)]
+    wrapper: Option<PathBuf>,
+    pub zfs: PathBuf,
+    pub zpool: PathBuf,
+    pub chown: PathBuf,
+    pub chmod: PathBuf,
+    pub bash: PathBuf,
+    pub btrfs: PathBuf,
+    /*
+     * Who should own the files in seeds and plants.
+     */
+    pub owner: Owner,
+}
+
+/*
+ * Directories in which to look for programs, in addition to those in $PATH.
+ * We clear the environment of the commands we run, so we need to know the
+ * full path of each program.
+ */
+const SEARCH: &[&str] = &[
+    "/usr/sbin",
+    "/sbin",
+    "/usr/bin",
+    "/bin",
+    "/usr/local/sbin",
+    "/usr/local/bin",
+];
+
+/**
+ * Look for a program in the usual places, returning its full path if it can
+ * be found.
+ */
+fn find(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    SEARCH.iter().map(PathBuf::from)
+        .chain(std::env::split_paths(&path))
+        .map(|d| d.join(name))
+        .find(|p| p.is_file())
+}
+
+fn find_or(name: &str, fallback: &str) -> PathBuf {
+    find(name).unwrap_or_else(|| PathBuf::from(fallback))
+}
+
+impl Default for Tools {
+    fn default() -> Tools {
+        Tools::detect()
+    }
+}
+
+impl Tools {
+    /**
+     * Work out sensible defaults for the system we are running on.
+     */
+    pub fn detect() -> Tools {
+        let root = unsafe { libc::geteuid() } == 0;
+
+        let privilege = if root {
+            Privilege::None
+        } else if cfg!(any(target_os = "illumos", target_os = "solaris")) {
+            Privilege::Pfexec
+        } else if find("sudo").is_some() {
+            Privilege::Sudo
+        } else if find("doas").is_some() {
+            Privilege::Doas
+        } else {
+            Privilege::None
+        };
+
+        let mut tools = Tools {
+            privilege: Privilege::None,
+            wrapper: None,
+            zfs: find_or("zfs", "/sbin/zfs"),
+            zpool: find_or("zpool", "/sbin/zpool"),
+            chown: find_or("chown", "/bin/chown"),
+            chmod: find_or("chmod", "/bin/chmod"),
+            bash: find_or("bash", "/bin/bash"),
+            btrfs: find_or("btrfs", "/sbin/btrfs"),
+            owner: Owner::invoking(),
+        };
+        tools.set_privilege(privilege);
+        tools
+    }
+
+    pub fn set_privilege(&mut self, privilege: Privilege) {
+        self.wrapper = match &privilege {
+            Privilege::None | Privilege::Delegated => None,
+            Privilege::Custom(w) => Some(w.clone()),
+            Privilege::Sudo => Some(find_or("sudo", "/usr/bin/sudo")),
+            Privilege::Doas => Some(find_or("doas", "/usr/bin/doas")),
+            Privilege::Pfexec => Some(find_or("pfexec", "/bin/pfexec")),
+        };
+        self.privilege = privilege;
+    }
+
+    /**
+     * Override the path of one of the programs we run, by name.
+     */
+    pub fn set(&mut self, name: &str, path: &Path) -> Result<()> {
+        if !path.is_absolute() {
+            bail!("path for {} must be absolute, not {:?}", name, path);
+        }
+        let p = match name {
+            "zfs" => &mut self.zfs,
+            "zpool" => &mut self.zpool,
+            "chown" => &mut self.chown,
+            "chmod" => &mut self.chmod,
+            "bash" => &mut self.bash,
+            "btrfs" => &mut self.btrfs,
+            n => bail!("unknown tool {:?} (expected zfs, zpool, chown, chmod, \
+                bash or btrfs)", n),
+        };
+        *p = path.to_path_buf();
+        Ok(())
+    }
+
+    /**
+     * Build a command which runs "prog" with privileges.  We classify
+     * failures by looking at the error message, so make sure the commands we
+     * run always emit their messages in the C locale.
+     */
+    pub fn command(&self, prog: &Path) -> Command {
+        let mut cmd = match &self.wrapper {
+            None => Command::new(prog),
+            Some(w) => {
+                let mut cmd = Command::new(w);
+                if let Privilege::Sudo | Privilege::Doas = self.privilege {
+                    /*
+                     * Fail rather than wait for a password that nobody is
+                     * going to type.
+                     */
+                    cmd.arg("-n");
+                }
+                cmd.arg(prog);
+                cmd
+            }
+        };
+        cmd.env_clear();
+        cmd.env("LC_ALL", "C");
+        cmd
+    }
+
+    /**
+     * Make sure the configured owner can write to a mountpoint, changing its
+     * ownership if need be.  With delegated permissions we have no way to do
+     * that, so we leave the mountpoint as it is; whether the owner can write
+     * to it then depends on the system, and on how the pool was prepared.
+     */
+    pub fn chown_to_owner<P: AsRef<Path>>(&self, log: &Logger, p: P)
+        -> Result<()>
+    {
+        let p = p.as_ref();
+        let owner = self.owner;
+
+        if owner.owns(p)? {
+            return Ok(());
+        }
+        if self.privilege == Privilege::Delegated {
+            warn!(log, "{:?} does not belong to uid {} gid {}, and cannot be \
+                changed with delegated permissions; writes to it may fail",
+                p, owner.uid, owner.gid);
+            return Ok(());
+        }
+
+        let run = |cmd: &mut Command, what: &str| {
+            let out = cmd.output()
+                .with_context(|| format!("running {}", what))?;
+            if !out.status.success() {
+                bail!("{} {:?}: {}", what, p, out.info());
+            }
+            Ok(())
+        };
+
+        run(self.command(&self.chown)
+            .arg("-R")
+            .arg(format!("{}:{}", owner.uid, owner.gid))
+            .arg(p), "chown")?;
+        run(self.command(&self.chmod)
+            .arg("-R")
+            .arg("u+rwx")
+            .arg(p), "chmod")?;
+        Ok(())
+    }
+}
+
+/**
+ * The user and group who should own the files in seeds and plants, so that
+ * the I/O threads can write to them.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Default for Owner {
+    fn default() -> Owner {
+        Owner::invoking()
+    }
+}
+
+impl Owner {
+    /**
+     * The user who ran us.  If that was through sudo, we want the files to
+     * belong to the user who invoked sudo, not to root.
+     */
+    pub fn invoking() -> Owner {
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        if uid == 0 {
+            let var = |n: &str| std::env::var(n).ok()
+                .and_then(|v| v.parse::<u32>().ok());
+            if let (Some(uid), Some(gid)) = (var("SUDO_UID"), var("SUDO_GID"))
+            {
+                return Owner { uid, gid };
+            }
+        }
+        Owner { uid, gid }
+    }
+
+    /**
+     * The name of the owning user, if it has one.
+     */
+    pub fn name(&self) -> Option<String> {
+        let pw = unsafe { libc::getpwuid(self.uid) };
+        if pw.is_null() {
+            return None;
+        }
+        let name = unsafe { std::ffi::CStr::from_ptr((*pw).pw_name) };
+        Some(name.to_string_lossy().to_string())
+    }
+
+    /**
+     * The groups to which the owning user belongs, by ID and (where it has
+     * one) by name.
+     */
+    pub fn groups(&self) -> Vec<(u32, Option<String>)> {
+        let mut gids = vec![self.gid];
+
+        if let Some(cs) = self.name().and_then(|n| CString::new(n).ok()) {
+            let mut len: libc::c_int = 32;
+            loop {
+                let mut buf = vec![0 as libc::gid_t; len as usize];
+                let mut n = len;
+                let r = unsafe {
+                    libc::getgrouplist(cs.as_ptr(), self.gid, buf.as_mut_ptr(),
+                        &mut n)
+                };
+                if r >= 0 {
+                    buf.truncate(n as usize);
+                    gids.extend(buf);
+                    break;
+                }
+                /*
+                 * The list did not fit.  Some systems tell us how much room
+                 * is needed, but not all of them do.
+                 */
+                len = n.max(len * 2);
+            }
+        }
+
+        gids.sort_unstable();
+        gids.dedup();
+        gids.into_iter().map(|gid| {
+            let gr = unsafe { libc::getgrgid(gid) };
+            let name = if gr.is_null() {
+                None
+            } else {
+                let name = unsafe { std::ffi::CStr::from_ptr((*gr).gr_name) };
+                Some(name.to_string_lossy().to_string())
+            };
+            (gid, name)
+        }).collect()
+    }
+
+    /**
+     * Check whether a directory already belongs to this owner, with the
+     * permissions the owner needs to write in it.
+     */
+    pub fn owns(&self, p: &Path) -> Result<bool> {
+        let md = std::fs::metadata(p)
+            .with_context(|| format!("checking ownership of {:?}", p))?;
+        Ok(md.uid() == self.uid && md.gid() == self.gid
+            && md.mode() & 0o700 == 0o700)
+    }
+}
+
+/*
+ * Look up a user by name or numeric ID, returning the user ID and primary
+ * group.  This is only used while parsing options, before we start any other
+ * threads, so the static buffer used by getpwnam(3C) is not a problem.
+ */
+fn user(name: &str) -> Result<(u32, u32)> {
+    let pw = if let Ok(uid) = name.parse::<u32>() {
+        let pw = unsafe { libc::getpwuid(uid) };
+        if pw.is_null() {
+            bail!("no user with ID {}; specify a group as well", uid);
+        }
+        pw
+    } else {
+        let cs = CString::new(name)?;
+        let pw = unsafe { libc::getpwnam(cs.as_ptr()) };
+        if pw.is_null() {
+            bail!("unknown user {:?}", name);
+        }
+        pw
+    };
+    Ok(unsafe { ((*pw).pw_uid, (*pw).pw_gid) })
+}
+
+fn group(name: &str) -> Result<u32> {
+    if let Ok(gid) = name.parse::<u32>() {
+        return Ok(gid);
+    }
+    let cs = CString::new(name)?;
+    let gr = unsafe { libc::getgrnam(cs.as_ptr()) };
+    if gr.is_null() {
+        bail!("unknown group {:?}", name);
+    }
+    Ok(unsafe { (*gr).gr_gid })
+}
+
+impl std::str::FromStr for Owner {
+    type Err = anyhow::Error;
+
+    /**
+     * Parse "USER[:GROUP]", where either may be a name or a numeric ID.  If
+     * no group is given, we use the primary group of the user.
+     */
+    fn from_str(s: &str) -> Result<Owner> {
+        let (u, g) = match s.split_once(':') {
+            Some((u, g)) => (u, Some(g)),
+            None => (s, None),
+        };
+        let (uid, gid) = match (u.parse::<u32>(), g) {
+            /*
+             * Numeric IDs need not exist in the password database.
+             */
+            (Ok(uid), Some(g)) => (uid, group(g)?),
+            (_, Some(g)) => (user(u)?.0, group(g)?),
+            (_, None) => user(u)?,
+        };
+        Ok(Owner { uid, gid })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::testutil::{self, TempDir};
+
+    /*
+     * Tools which will fail if they try to change the ownership of anything,
+     * and a directory which belongs to us but not to the configured owner.
+     */
+    fn setup(name: &str, privilege: Privilege) -> (Logger, TempDir, Tools) {
+        let dir = TempDir::new("tools", name);
+
+        let mut tools = Tools::detect();
+        tools.set_privilege(privilege);
+        tools.set("chown", Path::new("/bin/false")).unwrap();
+        tools.set("chmod", Path::new("/bin/false")).unwrap();
+        let md = std::fs::metadata(dir.path()).unwrap();
+        tools.owner = Owner { uid: md.uid() + 1, gid: md.gid() };
+        (testutil::logger(), dir, tools)
+    }
+
+    #[test]
+    fn already_owned() {
+        let (log, dir, mut tools) = setup("owned", Privilege::None);
+
+        let md = std::fs::metadata(dir.path()).unwrap();
+        tools.owner = Owner { uid: md.uid(), gid: md.gid() };
+        assert!(tools.owner.owns(dir.path()).unwrap());
+        tools.chown_to_owner(&log, dir.path()).unwrap();
+    }
+
+    #[test]
+    fn chown() {
+        let (log, dir, tools) = setup("chown", Privilege::None);
+
+        assert!(!tools.owner.owns(dir.path()).unwrap());
+        let e = tools.chown_to_owner(&log, dir.path()).unwrap_err();
+        assert!(e.to_string().starts_with("chown "), "{}", e);
+    }
+
+    #[test]
+    fn delegated() {
+        let (log, dir, tools) = setup("delegated", Privilege::Delegated);
+
+        /*
+         * There is nothing we can do about a mountpoint which belongs to
+         * somebody else, but that is not a reason to give up on the run.
+         */
+        assert!(!tools.owner.owns(dir.path()).unwrap());
+        tools.chown_to_owner(&log, dir.path()).unwrap();
+    }
+}
@@ -0,0 +1,377 @@
+/*
+ * The workloads run by the I/O threads within each plant.  Each plant runs
+ * one workload, chosen by name from the registry below (see "WorkloadMix"):
+ *
+ *      mixed       reads and writes of single blocks at random offsets
+ *                  within the files in the plant
+ *      sequential  runs of reads or writes through the files in the plant,
+ *                  from a random starting block
+ *      metadata    creates, renames, links and unlinks small files in the
+ *                  directories of the plant; see "metadata.rs"
+ *
+ * Every write is made of self-describing blocks (see "integrity.rs") and is
+ * recorded in the golden model for the plant, so that any workload which
+ * writes to the files in a plant has its writes checked in every snapshot.
+ */
+
+use std::collections::VecDeque;
+use std::fs;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use rand::prelude::*;
+use super::common::*;
+use super::config::{Config, WorkloadMix};
+use super::control::Control;
+use super::golden::{FileLog, GoldenModel};
+use super::integrity::{self, BlockState, Finding, BLOCK_SIZE};
+use super::latency::{Histograms, Op};
+use super::metadata::Metadata;
+use super::plant::fatal_finding;
+use super::seed::{fill_file, MEGABYTE};
+use super::stats::{Stats, ThreadCounts};
+
+/**
+ * The activity of the I/O threads within a plant.  Each thread calls "run()"
+ * once, with its own random number generator, and it should return once the
+ * run is stopping.
+ */
+pub trait Workload: Send + Sync {
+    fn run(&self, w: &Worker, rng: &mut dyn RngCore);
+
+    /**
+     * Once every thread has stopped, check the plant against whatever record
+     * the workload keeps of what it should contain.  Damage is reported as a
+     * "Finding".
+     */
+    fn finish(&self, _log: &Logger, _mountpoint: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+/*
+ * The context in which an I/O thread operates.
+ */
+pub struct Worker {
+    pub log: Logger,
+    pub ctl: Arc<Control>,
+    pub counts: Arc<ThreadCounts>,
+    pub lat: Arc<Histograms>,
+    pub cfg: Arc<Config>,
+    pub mountpoint: PathBuf,
+    /*
+     * The writes acknowledged in this plant, against which each backup
+     * snapshot is checked.
+     */
+    pub model: Arc<GoldenModel>,
+}
+
+impl Worker {
+    /**
+     * List all files in the plant at this time, in a random order.  If there
+     * are none, we wait a while before returning, so that a workload does not
+     * spin on the walk.
+     */
+    pub fn files(&self, rng: &mut dyn RngCore) -> Vec<PathBuf> {
+        let Worker { log, ctl, .. } = self;
+
+        let mut files = Vec::new();
+        let walk = walkdir::WalkDir::new(&self.mountpoint).into_iter()
+            /*
+             * If "snapdir" is visible, do not wander into the (read-only)
+             * snapshots.
+             */
+            .filter_entry(|ent| ent.file_name() != ".zfs");
+        for ent in walk {
+            match ent {
+                Ok(ent) => {
+                    if !ent.file_type().is_file() {
+                        continue;
+                    }
+                    files.push(ent.path().to_path_buf());
+                }
+                Err(e) => {
+                    error!(&log, #"workload", "walk failure: {:?}", e);
+                    Stats::add(&ctl.stats.walk_failures, 1);
+                    continue;
+                }
+            }
+        }
+
+        if files.is_empty() {
+            ctl.sleep_until(None, 1_000);
+            return files;
+        }
+
+        /*
+         * The order in which the walk visits files depends on the filesystem,
+         * so sort the list to make the shuffle reproducible.
+         */
+        files.sort();
+
+        /*
+         * Shuffle the deck.
+         */
+        let mut neworder = VecDeque::new();
+        for i in 0..files.len() {
+            neworder.push_back(i);
+        }
+
+        if !neworder.is_empty() {
+            let mut i = neworder.len() - 1;
+            while i >= 1 {
+                let j = rng.gen_range(0..i);
+                neworder.swap(i, j);
+                i -= 1;
+            }
+        }
+
+        neworder.into_iter().map(|i| files[i].clone()).collect()
+    }
+
+    /**
+     * Account for an operation which failed.  If the failure is damaged data,
+     * the run is stopped.
+     */
+    pub fn failed(&self, e: &anyhow::Error) {
+        if let Some(f) = e.downcast_ref::<Finding>() {
+            fatal_finding(&self.log, &self.ctl, f);
+            return;
+        }
+        error!(&self.log, #"workload", "workload error: {:?}", e);
+        self.ctl.stats.error(e);
+        Stats::add(&self.counts.errors, 1);
+    }
+}
+
+/**
+ * Reads and writes within the existing files in a plant, either at random
+ * offsets or in sequential runs.
+ */
+struct FileIo {
+    mix: WorkloadMix,
+    sequential: bool,
+}
+
+impl Workload for FileIo {
+    fn run(&self, w: &Worker, rng: &mut dyn RngCore) {
+        let mut buf = Vec::with_capacity(self.mix.block_size as usize);
+
+        while !w.ctl.stopping() {
+            for p in w.files(rng) {
+                if w.ctl.stopping() {
+                    break;
+                }
+
+                let flog = w.model.file(p.strip_prefix(&w.mountpoint)
+                    .unwrap());
+                if let Err(e) = self.visit(w, &p, &flog, rng, &mut buf) {
+                    w.failed(&e);
+                }
+            }
+        }
+    }
+}
+
+impl FileIo {
+    fn visit(&self, w: &Worker, p: &Path, flog: &FileLog,
+        mut rng: &mut dyn RngCore, buf: &mut Vec<u8>)
+        -> Result<()>
+    {
+        let Worker { log, ctl, counts, lat, cfg, .. } = w;
+        let mix = &self.mix;
+
+        let f = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(false)
+            .open(p)?;
+
+        let sz = f.metadata()?.len();
+
+        if sz < 2048 {
+            /*
+             * Small file found.  Pad it out to meet our expectations.
+             */
+            let sz_mb = rng.gen_range::<u64, _>(
+                cfg.seed.file_min..=cfg.seed.file_max);
+
+            flog.replace(|| fill_file(p, &mut rng, sz_mb,
+                cfg.seed.compressible))?;
+            Stats::add(&counts.bytes_written, sz_mb * MEGABYTE);
+            return Ok(());
+        }
+
+        /*
+         * Operations are aligned to their size, which is a multiple of the
+         * block size, so that every block we write can be checked in its
+         * entirety later.  We never touch the last (possibly partial) chunk
+         * of the file.
+         */
+        let bs = mix.block_size;
+        let count = (bs / BLOCK_SIZE as u64) as usize;
+        let chunks = sz / bs;
+        if chunks < 2 {
+            debug!(log, #"workload", "{:?} is too small for operations of \
+                {} bytes", p, bs);
+            return Ok(());
+        }
+
+        /*
+         * Determine how many operations we will perform on this
+         * file.
+         */
+        let iops = rng.gen_range(1..=mix.max_ops);
+        let fid = integrity::file_id(p);
+
+        buf.clear();
+        buf.resize(bs as usize, 0);
+
+        /*
+         * A sequential run either reads or writes throughout, starting at a
+         * random chunk and wrapping around at the end of the file.
+         */
+        let mut run = if self.sequential {
+            Some((rng.gen_bool(mix.write_ratio), rng.gen_range(0..chunks - 1)))
+        } else {
+            None
+        };
+
+        /*
+         * Writes which have not yet been followed by an fsync, as (offset,
+         * generation) pairs.
+         */
+        let mut pending = Vec::new();
+
+        for _ in 0..iops {
+            if ctl.stopping() {
+                break;
+            }
+
+            let (write, chunk) = match &mut run {
+                Some((write, next)) => {
+                    let chunk = *next;
+                    *next = (*next + 1) % (chunks - 1);
+                    (*write, chunk)
+                }
+                None => {
+                    /*
+                     * Are we looking to read or write?
+                     */
+                    let write = rng.gen_bool(mix.write_ratio);
+                    (write, rng.gen_range(0..chunks - 1))
+                }
+            };
+            let target = chunk * bs;
+
+            if write {
+                let compressible = rng.gen_bool(mix.compressible);
+
+                let gen = lat.time(Op::Write,
+                    || flog.write(&f, buf, fid, target, count, compressible))?;
+                pending.extend((0..count as u64)
+                    .map(|i| (target + i * BLOCK_SIZE as u64, gen)));
+                Stats::add(&counts.writes, 1);
+                Stats::add(&counts.bytes_written, bs);
+
+                if rng.gen_bool(mix.fsync_ratio) {
+                    lat.time(Op::Fsync, || f.sync_all())?;
+                    flog.ack(&pending);
+                    pending.clear();
+                    Stats::add(&counts.fsyncs, 1);
+                }
+
+            } else {
+                let acks = flog.acked(target, bs);
+                lat.time(Op::Read, || f.read_exact_at(buf, target))?;
+                Stats::add(&counts.reads, 1);
+                Stats::add(&counts.bytes_read, bs);
+
+                for (i, block) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+                    let offset = target + (i * BLOCK_SIZE) as u64;
+                    let state = integrity::check_block(block, fid, offset);
+                    if state != BlockState::Corrupt {
+                        /*
+                         * A block that is intact, or carries no header at
+                         * all, must still hold any write acknowledged before
+                         * we read it.
+                         */
+                        if let Some(f) = flog.check(&acks, p, offset, &state) {
+                            return Err(f.into());
+                        }
+                        continue;
+                    }
+
+                    /*
+                     * Another thread may have been writing to this block as
+                     * we read it.  If the block is intact when read again, it
+                     * was not damaged.
+                     */
+                    let finding = Finding::new(p, block, fid, offset);
+                    let mut again = vec![0u8; BLOCK_SIZE];
+                    f.read_exact_at(&mut again, offset)?;
+                    if integrity::check_block(&again, fid, offset)
+                        == BlockState::Corrupt
+                    {
+                        return Err(finding.into());
+                    }
+                    warn!(log, #"workload", "torn read of {:?} at offset {}; \
+                        block was intact when read again", p, offset);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn mixed(mix: &WorkloadMix, _rng: &mut dyn RngCore) -> Arc<dyn Workload> {
+    Arc::new(FileIo { mix: mix.clone(), sequential: false })
+}
+
+fn sequential(mix: &WorkloadMix, _rng: &mut dyn RngCore)
+    -> Arc<dyn Workload>
+{
+    Arc::new(FileIo { mix: mix.clone(), sequential: true })
+}
+
+fn metadata(mix: &WorkloadMix, rng: &mut dyn RngCore) -> Arc<dyn Workload> {
+    Arc::new(Metadata::new(mix, rng))
+}
+
+type Constructor = fn(&WorkloadMix, &mut dyn RngCore) -> Arc<dyn Workload>;
+
+/*
+ * Every workload that can be chosen by name, with a function to create it
+ * from its parameters and any random choices it must make up front.
+ */
+const REGISTRY: &[(&str, Constructor)] = &[
+    ("mixed", mixed),
+    ("sequential", sequential),
+    ("metadata", metadata),
+];
+
+/**
+ * The names of the workloads that can be chosen.
+ */
+pub fn names() -> impl Iterator<Item = &'static str> {
+    REGISTRY.iter().map(|(n, _)| *n)
+}
+
+pub fn exists(name: &str) -> bool {
+    names().any(|n| n == name)
+}
+
+/**
+ * Create the workload named in "mix", with the parameters given there.  Any
+ * random choices made in doing so come from "rng", so that a run can be
+ * replayed.
+ */
+pub fn create(mix: &WorkloadMix, rng: &mut dyn RngCore)
+    -> Result<Arc<dyn Workload>>
+{
+    match REGISTRY.iter().find(|(n, _)| *n == mix.name) {
+        Some((_, f)) => Ok(f(mix, rng)),
+        None => bail!("unknown workload {:?}", mix.name),
+    }
+}
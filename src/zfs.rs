@@ -1,311 +1,912 @@
-use std::process::Command;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::sync::Mutex;
+use serde::Serialize;
 use super::common::*;
+use super::tools::Tools;
+
+/**
+ * The ways in which a ZFS operation can fail, as far as callers are concerned.
+ */
+#[derive(Debug)]
+pub enum ZfsError {
+    NotFound,
+    AlreadyExists,
+    Busy,
+    HasDependentClones,
+    PermissionDenied,
+    OutOfSpace,
+    InvalidName(String),
+    /*
+     * A recursive destroy of a dataset we did not create was refused.
+     */
+    Unmarked(String),
+    Other {
+        code: Option<i32>,
+        stderr: String,
+    },
+}
 
-const ZFS: &str = "/sbin/zfs";
-const ZPOOL: &str = "/sbin/zpool";
-const PFEXEC: &str = "/bin/pfexec";
-const BASH: &str = "/bin/bash";
+pub type ZfsResult<T> = std::result::Result<T, ZfsError>;
+
+impl ZfsError {
+    /**
+     * Determine the class of failure from the output of a failed command.
+     */
+    pub fn from_output(res: &Output) -> ZfsError {
+        let stderr = String::from_utf8_lossy(&res.stderr);
+
+        if stderr.contains("dataset does not exist")
+            || stderr.contains("could not find any snapshots to destroy")
+            || stderr.contains("parent does not exist")
+        {
+            ZfsError::NotFound
+        } else if stderr.contains("dataset already exists") {
+            ZfsError::AlreadyExists
+        } else if stderr.contains("has dependent clones") {
+            ZfsError::HasDependentClones
+        } else if stderr.contains("dataset is busy") {
+            ZfsError::Busy
+        } else if stderr.contains("permission denied") {
+            ZfsError::PermissionDenied
+        } else if stderr.contains("out of space") {
+            ZfsError::OutOfSpace
+        } else {
+            ZfsError::Other {
+                code: res.status.code(),
+                stderr: res.info(),
+            }
+        }
+    }
 
-fn zfs() -> Command {
-    let mut cmd = Command::new(PFEXEC);
-    cmd.env_clear();
-    cmd.arg(ZFS);
-    cmd
+    /**
+     * A short, stable name for this class of failure, suitable for counting
+     * and reporting.
+     */
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ZfsError::NotFound => "not_found",
+            ZfsError::AlreadyExists => "already_exists",
+            ZfsError::Busy => "busy",
+            ZfsError::HasDependentClones => "has_dependent_clones",
+            ZfsError::PermissionDenied => "permission_denied",
+            ZfsError::OutOfSpace => "out_of_space",
+            ZfsError::InvalidName(_) => "invalid_name",
+            ZfsError::Unmarked(_) => "unmarked",
+            ZfsError::Other { .. } => "other",
+        }
+    }
 }
 
-fn zpool() -> Command {
-    let mut cmd = Command::new(PFEXEC);
-    cmd.env_clear();
-    cmd.arg(ZPOOL);
-    cmd
+impl std::fmt::Display for ZfsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ZfsError::NotFound => write!(f, "dataset does not exist"),
+            ZfsError::AlreadyExists => write!(f, "dataset already exists"),
+            ZfsError::Busy => write!(f, "dataset is busy"),
+            ZfsError::HasDependentClones => {
+                write!(f, "dataset has dependent clones")
+            }
+            ZfsError::PermissionDenied => write!(f, "permission denied"),
+            ZfsError::OutOfSpace => write!(f, "out of space"),
+            ZfsError::InvalidName(n) => write!(f, "invalid name {}", n),
+            ZfsError::Unmarked(n) => write!(f, "refusing to destroy {}, \
+                which does not have the {} property and so was not created \
+                by us", n, OWNER_PROP),
+            ZfsError::Other { stderr, .. } => write!(f, "{}", stderr),
+        }
+    }
 }
 
-fn validate_snapshot_name(n: &str) -> Result<()> {
+impl std::error::Error for ZfsError {}
+
+impl From<std::io::Error> for ZfsError {
+    fn from(e: std::io::Error) -> ZfsError {
+        ZfsError::Other {
+            code: None,
+            stderr: format!("exec failure: {}", e),
+        }
+    }
+}
+
+impl From<std::string::FromUtf8Error> for ZfsError {
+    fn from(e: std::string::FromUtf8Error) -> ZfsError {
+        ZfsError::Other {
+            code: None,
+            stderr: format!("invalid output: {}", e),
+        }
+    }
+}
+
+pub(crate) fn validate_snapshot_name(n: &str) -> ZfsResult<()> {
     if n.contains('@') || n.contains('/') {
-        bail!("invalid snapshot name {}", n);
+        return Err(ZfsError::InvalidName(n.to_string()));
     }
     Ok(())
 }
 
-fn validate_dataset_name(n: &str) -> Result<()> {
+pub(crate) fn validate_dataset_name(n: &str) -> ZfsResult<()> {
     if n.contains('@') {
-        bail!("invalid dataset name {}", n);
+        return Err(ZfsError::InvalidName(n.to_string()));
     }
     Ok(())
 }
 
-pub fn zfs_destroy_snapshot(log: &Logger, dataset: &str, snapname: &str)
-    -> Result<()>
-{
-    validate_dataset_name(dataset)?;
-    validate_snapshot_name(snapname)?;
+/**
+ * The set of ZFS operations used to drive a stress run.  The orchestration code
+ * (seeds, plants and the backup loop) is written only in terms of this trait,
+ * so that it can be pointed either at a real pool or at a simulation.
+ */
+pub trait ZfsBackend: Send + Sync {
+    /**
+     * Destroy a snapshot.  It is not an error if the snapshot does not exist.
+     */
+    fn destroy_snapshot(&self, log: &Logger, dataset: &str, snapname: &str)
+        -> ZfsResult<()>;
+
+    /**
+     * Destroy a dataset, and optionally all of its descendants.  It is not an
+     * error if the dataset does not exist.  A recursive destroy is refused
+     * unless we created the dataset; see "Guard".
+     */
+    fn destroy(&self, log: &Logger, dataset: &str, recursive: bool)
+        -> ZfsResult<()>;
+
+    /**
+     * Create a filesystem dataset, marked as ours.  If "exists_ok" is set, it
+     * is not an error for the dataset to exist already.
+     */
+    fn create(&self, log: &Logger, dataset: &str, exists_ok: bool)
+        -> ZfsResult<()>;
+
+    fn snapshot(&self, log: &Logger, dataset: &str, name: &str,
+        recursive: bool)
+        -> ZfsResult<()>;
+
+    fn clone_snapshot(&self, log: &Logger, dataset: &str, snapname: &str,
+        target: &str)
+        -> ZfsResult<()>;
+
+    fn get(&self, log: &Logger, dataset: &str, prop: &str)
+        -> ZfsResult<String>;
+
+    /**
+     * Set a user property (one with a ":" in its name) on a dataset.
+     */
+    fn set(&self, log: &Logger, dataset: &str, prop: &str, value: &str)
+        -> ZfsResult<()>;
+
+    fn snapshot_exists(&self, log: &Logger, dataset: &str, snapname: &str)
+        -> ZfsResult<bool>;
+
+    /**
+     * List the names of the filesystems directly beneath this dataset,
+     * including the dataset itself.
+     */
+    fn dataset_children(&self, log: &Logger, dataset: &str)
+        -> ZfsResult<Vec<String>>;
+
+    /**
+     * List the snapshots of this dataset, oldest first.  Only the part of the
+     * name after the "@" is returned.
+     */
+    fn snapshot_list(&self, log: &Logger, dataset: &str)
+        -> ZfsResult<Vec<String>>;
+
+    /**
+     * Generate an incremental send stream between two snapshots and discard
+     * it.
+     */
+    fn send_to_null(&self, log: &Logger, dataset: &str, snapold: &str,
+        snapnew: &str)
+        -> ZfsResult<bool>;
+
+    /**
+     * Place a user hold with the given tag on a snapshot.
+     */
+    fn hold(&self, log: &Logger, dataset: &str, snapname: &str, tag: &str)
+        -> ZfsResult<()>;
+
+    fn release(&self, log: &Logger, dataset: &str, snapname: &str, tag: &str)
+        -> ZfsResult<()>;
+
+    /**
+     * Delegate permissions on a dataset and its descendants to a user, with
+     * "zfs allow".
+     */
+    fn allow(&self, log: &Logger, dataset: &str, user: &str, perms: &[&str])
+        -> ZfsResult<()>;
+
+    /**
+     * List the permissions which have been delegated on a dataset and apply
+     * to its descendants.
+     */
+    fn allowed(&self, log: &Logger, dataset: &str)
+        -> ZfsResult<Vec<Delegation>>;
+
+    /**
+     * Locate the directory through which the contents of a snapshot can be
+     * read.
+     */
+    fn snapshot_dir(&self, log: &Logger, dataset: &str, snapname: &str)
+        -> ZfsResult<PathBuf>
+    {
+        let mp = self.get(log, dataset, "mountpoint")?;
+        Ok(PathBuf::from(mp).join(".zfs").join("snapshot").join(snapname))
+    }
 
-    let fullname = format!("{}@{}", dataset, snapname);
+    /**
+     * Whether each file in a snapshot is captured at a single instant, so
+     * that the writes acknowledged before the snapshot was taken can be
+     * checked in it.
+     */
+    fn consistent_snapshots(&self) -> bool {
+        true
+    }
 
-    let mut cmd = zfs();
-    cmd.arg("destroy");
-    cmd.arg(fullname);
+    /**
+     * Whether this backend only plans the changes it would make.  If so,
+     * datasets that it "creates" have no mountpoint, so callers must not
+     * touch the files within them.
+     */
+    fn dry_run(&self) -> bool {
+        false
+    }
 
-    info!(log, "exec: {:?}", cmd.get_args());
+    /**
+     * Return the commands which have failed so far, if the backend keeps a
+     * record of them.
+     */
+    fn failures(&self) -> Vec<CommandFailure> {
+        Vec::new()
+    }
+}
 
-    let res = cmd.output()?;
-    if !res.status.success() {
-        if let Ok(s) = String::from_utf8(res.stderr.clone()) {
-            if s.contains("dataset does not exist") {
-                return Ok(());
-            }
+/**
+ * A command which failed, as recorded for the run report.
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct CommandFailure {
+    pub command: String,
+    pub kind: &'static str,
+    pub info: String,
+}
+
+/*
+ * Keep a record of no more than this many failed commands, so that a run in
+ * which some command fails persistently does not grow without bound.
+ */
+const MAX_FAILURES: usize = 1000;
+
+/**
+ * The commands which have failed so far, for the run report.
+ */
+#[derive(Default)]
+pub struct Failures {
+    list: Mutex<Vec<CommandFailure>>,
+}
+
+impl Failures {
+    pub fn record(&self, cmd: &Command, kind: &'static str, info: String) {
+        let mut list = self.list.lock().unwrap();
+        if list.len() < MAX_FAILURES {
+            let command = std::iter::once(cmd.get_program())
+                .chain(cmd.get_args())
+                .map(|a| a.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" ");
+            list.push(CommandFailure {
+                command,
+                kind,
+                info,
+            });
         }
+    }
 
-        error!(log, "{:?} failed: {}", cmd.get_args(), res.info());
-        bail!("{:?} failed: {}", cmd.get_args(), res.info());
+    pub fn list(&self) -> Vec<CommandFailure> {
+        self.list.lock().unwrap().clone()
     }
+}
 
-    Ok(())
+/**
+ * Something which can execute a fully constructed command and collect its
+ * output.  Normally this is the operating system, but the simulated pool in
+ * "sim.rs" also interprets the commands we would run.
+ */
+pub trait CommandRunner: Send + Sync {
+    fn run(&self, cmd: &mut Command) -> std::io::Result<Output>;
+
+    /**
+     * Whether commands which change the pool are only being planned, rather
+     * than performed; see "dryrun.rs".
+     */
+    fn dry_run(&self) -> bool {
+        false
+    }
 }
 
-pub fn zfs_destroy(log: &Logger, dataset: &str, recursive: bool) -> Result<()> {
-    validate_dataset_name(dataset)?;
+pub struct SystemRunner;
 
-    let mut cmd = zfs();
-    cmd.arg("destroy");
-    if recursive {
-        cmd.arg("-r");
+impl CommandRunner for SystemRunner {
+    fn run(&self, cmd: &mut Command) -> std::io::Result<Output> {
+        cmd.output()
     }
-    cmd.arg(dataset);
+}
 
-    info!(log, "exec: {:?}", cmd.get_args());
+/**
+ * Permissions delegated with "zfs allow" to a user, a group, or everyone.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delegation {
+    /*
+     * As "zfs allow" describes it: e.g., "user alice", "group staff" or
+     * "everyone".
+     */
+    pub who: String,
+    pub perms: BTreeSet<String>,
+}
 
-    let res = cmd.output()?;
-    if !res.status.success() {
-        if let Ok(s) = String::from_utf8(res.stderr.clone()) {
-            if s.contains("dataset does not exist") {
-                return Ok(());
-            }
+/**
+ * Parse the output of "zfs allow DATASET", keeping only the permissions that
+ * apply to descendants.  The output looks like:
+ *
+ * ```text
+ * ---- Permissions on tank -------------------------------------------
+ * Local+Descendent permissions:
+ *         user alice create,destroy,mount
+ *         everyone snapshot
+ * ```
+ */
+fn parse_delegations(out: &str) -> Vec<Delegation> {
+    let mut res = Vec::new();
+    let mut descendent = false;
+
+    for l in out.lines() {
+        if !l.starts_with(char::is_whitespace) {
+            descendent = l.starts_with("Local+Descendent permissions")
+                || l.starts_with("Descendent permissions");
+            continue;
+        }
+        if !descendent {
+            continue;
         }
 
-        error!(log, "{:?} failed: {}", cmd.get_args(), res.info());
-        bail!("{:?} failed: {}", cmd.get_args(), res.info());
+        let words = l.split_whitespace().collect::<Vec<_>>();
+        let (who, perms) = match words.as_slice() {
+            ["everyone", perms] => ("everyone".to_string(), perms),
+            [kind, name, perms] => (format!("{} {}", kind, name), perms),
+            _ => continue,
+        };
+        res.push(Delegation {
+            who,
+            perms: perms.split(',').map(str::to_string).collect(),
+        });
     }
 
-    Ok(())
+    res
 }
 
-pub fn zfs_create(log: &Logger, dataset: &str, exists_ok: bool) -> Result<()> {
-    validate_dataset_name(dataset)?;
-
-    let mut cmd = zfs();
-    cmd.arg("create");
-    cmd.arg(dataset);
+/**
+ * A dataset or snapshot, as described by "CliZfs::inventory()".
+ */
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub name: String,
+    /*
+     * For a clone, the full name of the origin snapshot.
+     */
+    pub origin: Option<String>,
+    /*
+     * Whether a snapshot has any user holds.
+     */
+    pub held: bool,
+    /*
+     * The user properties we use (see "OWNER_PROP" and "SEED_PROP") that are
+     * set locally.
+     */
+    pub props: BTreeMap<String, String>,
+}
 
-    info!(log, "exec: {:?}", cmd.get_args());
+/**
+ * Every dataset we create is marked with this user property, so that we can
+ * tell them apart from datasets that we must not destroy.
+ */
+pub const OWNER_PROP: &str = "festival:owner";
+
+/**
+ * The master seed from which the files in a seed dataset were generated.
+ */
+pub const SEED_PROP: &str = "festival:seed";
+
+/**
+ * Protection against destroying datasets that are not ours; e.g., because the
+ * wrong pool was specified.
+ */
+#[derive(Clone, Debug)]
+pub struct Guard {
+    /*
+     * The value of the owner property on the datasets created in this run.
+     */
+    pub run_id: String,
+    /*
+     * Allow the recursive destroy of datasets without the owner property,
+     * such as those created by older versions of this tool.
+     */
+    pub destroy_unmarked: bool,
+}
 
-    let res = cmd.output()?;
-    if !res.status.success() {
-        if exists_ok {
-            if let Ok(s) = String::from_utf8(res.stderr.clone()) {
-                if s.contains("dataset already exists") {
-                    return Ok(());
-                }
-            }
+impl Guard {
+    /**
+     * A guard for a new run, identified by the time and our process ID.
+     */
+    pub fn new(destroy_unmarked: bool) -> Guard {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Guard {
+            run_id: format!("{}-{}", now.as_secs(), std::process::id()),
+            destroy_unmarked,
         }
-
-        error!(log, "{:?} failed: {}", cmd.get_args(), res.info());
-        bail!("{:?} failed: {}", cmd.get_args(), res.info());
     }
+}
 
-    Ok(())
+/**
+ * The real backend, which executes the "zfs" command.
+ */
+pub struct CliZfs {
+    tools: Tools,
+    guard: Guard,
+    runner: Box<dyn CommandRunner>,
+    failures: Failures,
 }
 
-pub fn zfs_snapshot(log: &Logger, dataset: &str, name: &str, recursive: bool)
-    -> Result<()>
-{
-    validate_dataset_name(dataset)?;
-    validate_snapshot_name(name)?;
+impl CliZfs {
+    pub fn new(tools: Tools, guard: Guard) -> CliZfs {
+        CliZfs::with_runner(tools, guard, Box::new(SystemRunner))
+    }
 
-    let fullname = format!("{}@{}", dataset, name);
+    pub fn with_runner(tools: Tools, guard: Guard,
+        runner: Box<dyn CommandRunner>)
+        -> CliZfs
+    {
+        CliZfs {
+            tools,
+            guard,
+            runner,
+            failures: Default::default(),
+        }
+    }
 
-    let mut cmd = zfs();
-    cmd.arg("snapshot");
-    if recursive {
-        cmd.arg("-r");
+    fn zfs(&self) -> Command {
+        self.tools.command(&self.tools.zfs)
     }
-    cmd.arg(fullname);
 
-    info!(log, "exec: {:?}", cmd.get_args());
+    /**
+     * Describe a dataset and everything beneath it, including snapshots, in
+     * the order in which they were created.
+     */
+    pub fn inventory(&self, log: &Logger, dataset: &str)
+        -> ZfsResult<Vec<Entry>>
+    {
+        validate_dataset_name(dataset)?;
+
+        let mut cmd = self.zfs();
+        cmd.arg("get");
+        cmd.arg("-H");
+        cmd.arg("-r");
+        cmd.arg("-s");
+        cmd.arg("local");
+        cmd.arg("-o");
+        cmd.arg("name,property,value");
+        cmd.arg(format!("{},{}", OWNER_PROP, SEED_PROP));
+        cmd.arg(dataset);
+
+        let res = self.exec(log, &mut cmd)?;
+        let mut props = HashMap::<String, BTreeMap<String, String>>::new();
+        for l in String::from_utf8(res.stdout)?.lines() {
+            if let [n, p, v] = l.split('\t').collect::<Vec<_>>().as_slice() {
+                props.entry(n.to_string()).or_default()
+                    .insert(p.to_string(), v.to_string());
+            }
+        }
 
-    let res = cmd.output()?;
-    if !res.status.success() {
-        error!(log, "{:?} failed: {}", cmd.get_args(), res.info());
-        bail!("{:?} failed: {}", cmd.get_args(), res.info());
+        let mut cmd = self.zfs();
+        cmd.arg("list");
+        cmd.arg("-H");
+        cmd.arg("-p");
+        cmd.arg("-r");
+        cmd.arg("-t");
+        cmd.arg("filesystem,snapshot");
+        cmd.arg("-s");
+        cmd.arg("createtxg");
+        cmd.arg("-o");
+        cmd.arg("name,origin,userrefs");
+        cmd.arg(dataset);
+
+        let res = self.exec(log, &mut cmd)?;
+        let mut out = Vec::new();
+        for l in String::from_utf8(res.stdout)?.lines() {
+            let t = l.split('\t').collect::<Vec<_>>();
+            if t.len() != 3 {
+                return Err(ZfsError::Other {
+                    code: None,
+                    stderr: format!("unexpected list output: {:?}", l),
+                });
+            }
+            out.push(Entry {
+                name: t[0].to_string(),
+                origin: Some(t[1]).filter(|o| *o != "-").map(str::to_string),
+                held: t[2].parse::<u64>().map(|n| n > 0).unwrap_or(false),
+                props: props.remove(t[0]).unwrap_or_default(),
+            });
+        }
+        Ok(out)
     }
 
-    Ok(())
+    /**
+     * Run a command, returning its output if it succeeds and the class of
+     * failure if it does not.
+     */
+    fn exec(&self, log: &Logger, cmd: &mut Command) -> ZfsResult<Output> {
+        self.exec_expecting(log, cmd, |_| false)
+    }
+
+    /**
+     * Run a command, as for "exec()", where the caller will handle any
+     * failure for which "expected" returns true.  Only other failures are
+     * logged as errors and recorded for the run report.
+     */
+    fn exec_expecting<F>(&self, log: &Logger, cmd: &mut Command, expected: F)
+        -> ZfsResult<Output>
+        where F: Fn(&ZfsError) -> bool
+    {
+        debug!(log, #"zfs", "exec: {:?}", cmd.get_args());
+
+        let res = self.runner.run(cmd)?;
+        if !res.status.success() {
+            let e = ZfsError::from_output(&res);
+            if expected(&e) {
+                debug!(log, #"zfs", "{:?} failed: {}", cmd.get_args(),
+                    res.info());
+            } else {
+                error!(log, #"zfs", "{:?} failed: {}", cmd.get_args(),
+                    res.info());
+                self.failures.record(cmd, e.kind(), res.info());
+            }
+            return Err(e);
+        }
+
+        Ok(res)
+    }
 }
 
-pub fn zfs_clone(log: &Logger, dataset: &str, snapname: &str, target: &str)
-    -> Result<()>
-{
-    validate_dataset_name(dataset)?;
-    validate_snapshot_name(snapname)?;
-    validate_dataset_name(target)?;
+impl ZfsBackend for CliZfs {
+    fn destroy_snapshot(&self, log: &Logger, dataset: &str,
+        snapname: &str)
+        -> ZfsResult<()>
+    {
+        validate_dataset_name(dataset)?;
+        validate_snapshot_name(snapname)?;
+
+        let fullname = format!("{}@{}", dataset, snapname);
+
+        let mut cmd = self.zfs();
+        cmd.arg("destroy");
+        cmd.arg(fullname);
+
+        match self.exec_expecting(log, &mut cmd,
+            |e| matches!(e, ZfsError::NotFound))
+        {
+            Ok(_) | Err(ZfsError::NotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
 
-    let fullname = format!("{}@{}", dataset, snapname);
+    fn destroy(&self, log: &Logger, dataset: &str, recursive: bool)
+        -> ZfsResult<()>
+    {
+        validate_dataset_name(dataset)?;
+
+        if recursive && !self.guard.destroy_unmarked {
+            /*
+             * Only the property set on the dataset itself counts: anything
+             * created beneath one of our datasets by somebody else would
+             * inherit it.
+             */
+            let mut cmd = self.zfs();
+            cmd.arg("get");
+            cmd.arg("-H");
+            cmd.arg("-o");
+            cmd.arg("value");
+            cmd.arg("-s");
+            cmd.arg("local");
+            cmd.arg(OWNER_PROP);
+            cmd.arg(dataset);
+
+            let marked = match self.exec_expecting(log, &mut cmd,
+                |e| matches!(e, ZfsError::NotFound))
+            {
+                Ok(res) => !String::from_utf8(res.stdout)?.trim().is_empty(),
+                Err(ZfsError::NotFound) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            if !marked {
+                return Err(ZfsError::Unmarked(dataset.to_string()));
+            }
+        }
 
-    let mut cmd = zfs();
-    cmd.arg("clone");
-    cmd.arg(fullname);
-    cmd.arg(target);
+        let mut cmd = self.zfs();
+        cmd.arg("destroy");
+        if recursive {
+            cmd.arg("-r");
+        }
+        cmd.arg(dataset);
 
-    info!(log, "exec: {:?}", cmd.get_args());
+        match self.exec_expecting(log, &mut cmd,
+            |e| matches!(e, ZfsError::NotFound))
+        {
+            Ok(_) | Err(ZfsError::NotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
 
-    let res = cmd.output()?;
-    if !res.status.success() {
-        error!(log, "{:?} failed: {}", cmd.get_args(), res.info());
-        bail!("{:?} failed: {}", cmd.get_args(), res.info());
+    fn create(&self, log: &Logger, dataset: &str, exists_ok: bool)
+        -> ZfsResult<()>
+    {
+        validate_dataset_name(dataset)?;
+
+        let mut cmd = self.zfs();
+        cmd.arg("create");
+        cmd.arg("-o");
+        cmd.arg(format!("{}={}", OWNER_PROP, self.guard.run_id));
+        cmd.arg(dataset);
+
+        match self.exec_expecting(log, &mut cmd,
+            |e| exists_ok && matches!(e, ZfsError::AlreadyExists))
+        {
+            Err(ZfsError::AlreadyExists) if exists_ok => Ok(()),
+            Err(e) => Err(e),
+            Ok(_) => Ok(()),
+        }
     }
 
-    Ok(())
-}
+    fn snapshot(&self, log: &Logger, dataset: &str, name: &str,
+        recursive: bool)
+        -> ZfsResult<()>
+    {
+        validate_dataset_name(dataset)?;
+        validate_snapshot_name(name)?;
 
-pub fn zfs_get(log: &Logger, dataset: &str, prop: &str) -> Result<String> {
-    validate_dataset_name(dataset)?;
+        let fullname = format!("{}@{}", dataset, name);
 
-    let mut cmd = zfs();
-    cmd.arg("get");
-    cmd.arg("-H");
-    cmd.arg("-o");
-    cmd.arg("value");
-    cmd.arg(prop);
-    cmd.arg(dataset);
+        let mut cmd = self.zfs();
+        cmd.arg("snapshot");
+        if recursive {
+            cmd.arg("-r");
+        }
+        cmd.arg(fullname);
 
-    info!(log, "exec: {:?}", cmd.get_args());
+        self.exec(log, &mut cmd)?;
+        Ok(())
+    }
 
-    let res = cmd.output()?;
-    if !res.status.success() {
-        error!(log, "{:?} failed: {}", cmd.get_args(), res.info());
-        bail!("{:?} failed: {}", cmd.get_args(), res.info());
+    fn clone_snapshot(&self, log: &Logger, dataset: &str, snapname: &str,
+        target: &str)
+        -> ZfsResult<()>
+    {
+        validate_dataset_name(dataset)?;
+        validate_snapshot_name(snapname)?;
+        validate_dataset_name(target)?;
+
+        let fullname = format!("{}@{}", dataset, snapname);
+
+        let mut cmd = self.zfs();
+        cmd.arg("clone");
+        cmd.arg("-o");
+        cmd.arg(format!("{}={}", OWNER_PROP, self.guard.run_id));
+        cmd.arg(fullname);
+        cmd.arg(target);
+
+        self.exec(log, &mut cmd)?;
+        Ok(())
     }
 
-    Ok(String::from_utf8(res.stdout)?.trim_end_matches('\n').to_string())
-}
+    fn get(&self, log: &Logger, dataset: &str, prop: &str)
+        -> ZfsResult<String>
+    {
+        validate_dataset_name(dataset)?;
 
-pub fn zfs_snapshot_exists(log: &Logger, dataset: &str, snapname: &str)
-    -> Result<bool>
-{
-    validate_dataset_name(dataset)?;
-    validate_snapshot_name(snapname)?;
+        let mut cmd = self.zfs();
+        cmd.arg("get");
+        cmd.arg("-H");
+        cmd.arg("-o");
+        cmd.arg("value");
+        cmd.arg(prop);
+        cmd.arg(dataset);
 
-    let fullname = format!("{}@{}", dataset, snapname);
+        let res = self.exec(log, &mut cmd)?;
 
-    let mut cmd = zfs();
-    cmd.arg("list");
-    cmd.arg("-Ho");
-    cmd.arg("name");
-    cmd.arg(fullname);
+        Ok(String::from_utf8(res.stdout)?.trim_end_matches('\n').to_string())
+    }
 
-    info!(log, "exec: {:?}", cmd.get_args());
+    fn set(&self, log: &Logger, dataset: &str, prop: &str, value: &str)
+        -> ZfsResult<()>
+    {
+        validate_dataset_name(dataset)?;
 
-    let res = cmd.output()?;
-    if !res.status.success() {
-        if let Ok(s) = String::from_utf8(res.stderr.clone()) {
-            if s.contains("dataset does not exist") {
-                return Ok(false);
-            }
+        let mut cmd = self.zfs();
+        cmd.arg("set");
+        cmd.arg(format!("{}={}", prop, value));
+        cmd.arg(dataset);
+
+        self.exec(log, &mut cmd)?;
+        Ok(())
+    }
+
+    fn snapshot_exists(&self, log: &Logger, dataset: &str, snapname: &str)
+        -> ZfsResult<bool>
+    {
+        validate_dataset_name(dataset)?;
+        validate_snapshot_name(snapname)?;
+
+        let fullname = format!("{}@{}", dataset, snapname);
+
+        let mut cmd = self.zfs();
+        cmd.arg("list");
+        cmd.arg("-Ho");
+        cmd.arg("name");
+        cmd.arg(fullname);
+
+        match self.exec_expecting(log, &mut cmd,
+            |e| matches!(e, ZfsError::NotFound))
+        {
+            Ok(_) => Ok(true),
+            Err(ZfsError::NotFound) => Ok(false),
+            Err(e) => Err(e),
         }
+    }
 
-        error!(log, "{:?} failed: {}", cmd.get_args(), res.info());
-        bail!("{:?} failed: {}", cmd.get_args(), res.info());
+    fn dataset_children(&self, log: &Logger, dataset: &str)
+        -> ZfsResult<Vec<String>>
+    {
+        validate_dataset_name(dataset)?;
+
+        let mut cmd = self.zfs();
+        cmd.arg("list");
+        cmd.arg("-t");
+        cmd.arg("filesystem");
+        cmd.arg("-d");
+        cmd.arg("1");
+        cmd.arg("-Ho");
+        cmd.arg("name");
+        cmd.arg(dataset);
+
+        let res = self.exec(log, &mut cmd)?;
+
+        let s = String::from_utf8(res.stdout)?;
+        Ok(s.lines().map(|s| s.to_string()).collect())
     }
 
-    Ok(true)
-}
+    fn snapshot_list(&self, log: &Logger, dataset: &str)
+        -> ZfsResult<Vec<String>>
+    {
+        validate_dataset_name(dataset)?;
+
+        let mut cmd = self.zfs();
+        cmd.arg("list");
+        cmd.arg("-t");
+        cmd.arg("snapshot");
+        cmd.arg("-d");
+        cmd.arg("1");
+        cmd.arg("-Ho");
+        cmd.arg("name");
+        cmd.arg("-s");
+        cmd.arg("creation");
+        cmd.arg(dataset);
+
+        let res = self.exec(log, &mut cmd)?;
+
+        let s = String::from_utf8(res.stdout)?;
+        Ok(s.lines().map(|s| {
+            let t = s.split('@').collect::<Vec<_>>();
+            assert_eq!(t.len(), 2);
+            t[1].to_string()
+        }).collect())
+    }
 
-pub fn zfs_dataset_children(log: &Logger, dataset: &str)
-    -> Result<Vec<String>>
-{
-    validate_dataset_name(dataset)?;
+    fn send_to_null(&self, log: &Logger, dataset: &str, snapold: &str,
+        snapnew: &str)
+        -> ZfsResult<bool>
+    {
+        validate_dataset_name(dataset)?;
+        validate_snapshot_name(snapold)?;
+        validate_snapshot_name(snapnew)?;
 
-    let mut cmd = zfs();
-    cmd.arg("list");
-    cmd.arg("-t");
-    cmd.arg("filesystem");
-    cmd.arg("-d");
-    cmd.arg("1");
-    cmd.arg("-Ho");
-    cmd.arg("name");
-    cmd.arg(dataset);
+        let fullold = format!("{}@{}", dataset, snapold);
+        let fullnew = format!("{}@{}", dataset, snapnew);
 
-    info!(log, "exec: {:?}", cmd.get_args());
+        let mut script = String::new();
+        script += "set -o errexit; set -o pipefail; ";
+        script += &format!("{} send -i {} {} >/dev/null",
+            self.tools.zfs.display(), fullold, fullnew);
 
-    let res = cmd.output()?;
-    if !res.status.success() {
-        error!(log, "{:?} failed: {}", cmd.get_args(), res.info());
-        bail!("{:?} failed: {}", cmd.get_args(), res.info());
+        let mut cmd = self.tools.command(&self.tools.bash);
+        cmd.arg("-c");
+        cmd.arg(&script);
+
+        self.exec(log, &mut cmd)?;
+        Ok(true)
     }
 
-    let s = String::from_utf8(res.stdout)?;
-    Ok(s.lines().map(|s| s.to_string()).collect())
-}
+    fn hold(&self, log: &Logger, dataset: &str, snapname: &str, tag: &str)
+        -> ZfsResult<()>
+    {
+        validate_dataset_name(dataset)?;
+        validate_snapshot_name(snapname)?;
 
-pub fn zfs_snapshot_list(log: &Logger, dataset: &str) -> Result<Vec<String>> {
-    validate_dataset_name(dataset)?;
-
-    let mut cmd = zfs();
-    cmd.arg("list");
-    cmd.arg("-t");
-    cmd.arg("snapshot");
-    cmd.arg("-d");
-    cmd.arg("1");
-    cmd.arg("-Ho");
-    cmd.arg("name");
-    cmd.arg("-s");
-    cmd.arg("creation");
-    cmd.arg(dataset);
-
-    info!(log, "exec: {:?}", cmd.get_args());
-
-    let res = cmd.output()?;
-    if !res.status.success() {
-        error!(log, "{:?} failed: {}", cmd.get_args(), res.info());
-        bail!("{:?} failed: {}", cmd.get_args(), res.info());
-    }
-
-    let s = String::from_utf8(res.stdout)?;
-    Ok(s.lines().map(|s| {
-        let t = s.split('@').collect::<Vec<_>>();
-        assert_eq!(t.len(), 2);
-        t[1].to_string()
-    }).collect())
-}
+        let fullname = format!("{}@{}", dataset, snapname);
 
-pub fn zfs_send_to_null(log: &Logger, dataset: &str, snapold: &str,
-    snapnew: &str)
-    -> Result<bool>
-{
-    validate_dataset_name(dataset)?;
-    validate_snapshot_name(snapold)?;
-    validate_snapshot_name(snapnew)?;
+        let mut cmd = self.zfs();
+        cmd.arg("hold");
+        cmd.arg(tag);
+        cmd.arg(fullname);
 
-    let fullold = format!("{}@{}", dataset, snapold);
-    let fullnew = format!("{}@{}", dataset, snapnew);
+        self.exec(log, &mut cmd)?;
+        Ok(())
+    }
+
+    fn release(&self, log: &Logger, dataset: &str, snapname: &str, tag: &str)
+        -> ZfsResult<()>
+    {
+        validate_dataset_name(dataset)?;
+        validate_snapshot_name(snapname)?;
+
+        let fullname = format!("{}@{}", dataset, snapname);
+
+        let mut cmd = self.zfs();
+        cmd.arg("release");
+        cmd.arg(tag);
+        cmd.arg(fullname);
+
+        self.exec(log, &mut cmd)?;
+        Ok(())
+    }
 
-    let mut script = String::new();
-    script += "set -o errexit; set -o pipefail; ";
-    script += &format!("{} send -i {} {} >/dev/null", ZFS, fullold, fullnew);
+    fn allow(&self, log: &Logger, dataset: &str, user: &str, perms: &[&str])
+        -> ZfsResult<()>
+    {
+        validate_dataset_name(dataset)?;
 
-    let mut cmd = Command::new(PFEXEC);
-    cmd.env_clear();
-    cmd.arg(BASH);
-    cmd.arg("-c");
-    cmd.arg(&script);
+        let mut cmd = self.zfs();
+        cmd.arg("allow");
+        cmd.arg("-u");
+        cmd.arg(user);
+        cmd.arg(perms.join(","));
+        cmd.arg(dataset);
 
-    info!(log, "exec: {:?}", cmd.get_args());
+        self.exec(log, &mut cmd)?;
+        Ok(())
+    }
+
+    fn allowed(&self, log: &Logger, dataset: &str)
+        -> ZfsResult<Vec<Delegation>>
+    {
+        validate_dataset_name(dataset)?;
+
+        let mut cmd = self.zfs();
+        cmd.arg("allow");
+        cmd.arg(dataset);
 
-    let res = cmd.output()?;
-    if !res.status.success() {
-        error!(log, "{:?} failed: {}", cmd.get_args(), res.info());
-        bail!("{:?} failed: {}", cmd.get_args(), res.info());
+        let out = self.exec(log, &mut cmd)?;
+        Ok(parse_delegations(&String::from_utf8_lossy(&out.stdout)))
     }
 
-    Ok(true)
+    fn dry_run(&self) -> bool {
+        self.runner.dry_run()
+    }
+
+    fn failures(&self) -> Vec<CommandFailure> {
+        self.failures.list()
+    }
 }